@@ -1,7 +1,63 @@
 #[cfg(feature = "node")]
 extern crate napi_build;
 
+use std::env;
+use std::path::PathBuf;
+
+/// The font files embedded by the optional font features, which aren't in the repository.
+const FEATURE_FONTS: &[(&str, &[&str])] = &[
+    (
+        "CJK_FONTS",
+        &[
+            "noto-cjk/NotoSansSC-Regular.otf",
+            "noto-cjk/NotoSansTC-Regular.otf",
+            "noto-cjk/NotoSansJP-Regular.otf",
+            "noto-cjk/NotoSansKR-Regular.otf",
+        ],
+    ),
+    (
+        "DEVANAGARI_FONTS",
+        &[
+            "noto-devanagari/NotoSansDevanagari-Regular.ttf",
+            "noto-devanagari/NotoSansDevanagari-Bold.ttf",
+        ],
+    ),
+];
+
 fn main() {
     #[cfg(feature = "node")]
     napi_build::setup();
+
+    // The fonts are looked up in MDPDF_FONTS_DIR, or in `fonts/` where `make fonts` puts them
+    println!("cargo:rerun-if-env-changed=MDPDF_FONTS_DIR");
+    let fonts_dir = match env::var_os("MDPDF_FONTS_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap()).join("fonts"),
+    };
+    let mut missing = Vec::new();
+    for (feature, files) in FEATURE_FONTS {
+        if env::var_os(format!("CARGO_FEATURE_{feature}")).is_none() {
+            continue;
+        }
+        for file in *files {
+            let path = fonts_dir.join(file);
+            println!("cargo:rerun-if-changed={}", path.display());
+            if !path.is_file() {
+                missing.push(path);
+            }
+        }
+    }
+    if !missing.is_empty() {
+        let missing: Vec<_> = missing
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect();
+        panic!(
+            "the `cjk-fonts` and `devanagari-fonts` features embed fonts that aren't included \
+             with mdpdf, and these are missing:\n  {}\nRun `make fonts` in the repository to \
+             download them, or set MDPDF_FONTS_DIR to a directory with the same layout",
+            missing.join("\n  ")
+        );
+    }
+    println!("cargo:rustc-env=MDPDF_FONTS_DIR={}", fonts_dir.display());
 }
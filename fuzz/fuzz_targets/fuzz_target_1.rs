@@ -8,12 +8,12 @@ fuzz_target!(|data: &[u8]| {
         let config = MdpdfConfig::default();
         
         // First, convert markdown to typst
-        let (typst_code, image_files) = markdown_to_typst(&markdown, &config)
+        let output = markdown_to_typst(&markdown, &config)
             .expect("markdown_to_typst should not fail");
         
         // Then, try to convert typst to PDF
         // Assert that it doesn't return an error
-        let result = typst_to_pdf(&typst_code, &config, image_files);
+        let result = typst_to_pdf(&output.typst_code, &config, output.image_files);
         assert!(result.is_ok(), "typst_to_pdf returned error: {:?}", result.err());
     }
 });
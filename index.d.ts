@@ -1,5 +1,27 @@
 /* auto-generated by NAPI-RS */
 /* eslint-disable */
-export declare function markdownToPdf(markdown: string): Promise<Buffer>;
+/** A diagnostic as seen from JavaScript. */
+export interface ConversionWarning {
+  severity: string;
+  category: string;
+  message: string;
+  line?: number;
+  column?: number;
+}
 
-export declare function markdownToTypstCode(markdown: string): Promise<string>;
+/** Options for the conversion functions in JavaScript. */
+export interface ConversionOptions {
+  /** "default", "github", "academic", "compact", or the path to a theme file */
+  theme?: string;
+}
+
+export declare function markdownToPdf(markdown: string, options?: ConversionOptions | undefined | null): Promise<Buffer>;
+
+export declare function markdownToPdfWithWarnings(markdown: string, options?: ConversionOptions | undefined | null): Promise<PdfWithWarnings>;
+
+export declare function markdownToTypstCode(markdown: string, options?: ConversionOptions | undefined | null): Promise<string>;
+
+export interface PdfWithWarnings {
+  pdf: Buffer;
+  warnings: Array<ConversionWarning>;
+}
@@ -412,4 +412,5 @@ if (!nativeBinding) {
 
 module.exports = nativeBinding;
 module.exports.markdownToPdf = nativeBinding.markdownToPdf;
+module.exports.markdownToPdfWithWarnings = nativeBinding.markdownToPdfWithWarnings;
 module.exports.markdownToTypstCode = nativeBinding.markdownToTypstCode;
@@ -0,0 +1,321 @@
+use pulldown_cmark::{Event, MetadataBlockKind, Options, Parser, Tag, TagEnd};
+use serde::Deserialize;
+
+use super::{DEFAULT_FONT_SIZE, Margins, MdpdfConfig, PageSize, TocConfig, parse_length};
+use crate::diagnostics::{Diagnostic, DiagnosticCategory, LineIndex};
+
+/// Settings that can be given in a YAML block at the start of a document, e.g.
+///
+/// ```yaml
+/// ---
+/// title: Design doc
+/// author: [Ada, Grace]
+/// page_size: a4
+/// ---
+/// ```
+///
+/// Unknown keys are ignored, since front matter is often shared with other tools.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct FrontMatter {
+    title: Option<String>,
+    #[serde(alias = "authors")]
+    author: Option<OneOrMany>,
+    keywords: Option<OneOrMany>,
+    date: Option<String>,
+    lang: Option<String>,
+    page_size: Option<String>,
+    margin: Option<String>,
+    font_size: Option<f64>,
+    #[serde(alias = "font")]
+    font_family: Option<String>,
+    heading_font: Option<String>,
+    mono_font: Option<String>,
+    header: Option<String>,
+    footer: Option<String>,
+    title_block: Option<bool>,
+    toc: Option<Toc>,
+}
+
+/// `toc: true`, or the table of contents settings
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Toc {
+    Enabled(bool),
+    Config(TocConfig),
+}
+
+/// A list that can also be given as a single string
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// Strip the YAML front matter from `markdown` and merge its settings over `config`.
+///
+/// The front matter is replaced with spaces rather than removed, so that byte offsets (and with
+/// them line and column numbers) in the returned Markdown match the original document.
+pub(crate) fn apply_front_matter(
+    markdown: &str,
+    config: &MdpdfConfig,
+) -> (String, MdpdfConfig, Vec<Diagnostic>) {
+    let mut config = config.clone();
+    let mut diagnostics = Vec::new();
+    let Some((yaml, yaml_start, end)) = find_front_matter(markdown) else {
+        return (markdown.to_string(), config, diagnostics);
+    };
+
+    let line_index = LineIndex::new(markdown);
+    let (line, column) = line_index.position(yaml_start);
+    let mut warn = |message: String| {
+        diagnostics
+            .push(Diagnostic::warning(DiagnosticCategory::FrontMatter, message).at(line, column));
+    };
+
+    match serde_yaml::from_str::<Option<FrontMatter>>(&yaml) {
+        Ok(front_matter) => front_matter
+            .unwrap_or_default()
+            .merge_into(&mut config, &mut warn),
+        Err(error) => {
+            let message = format!("Front matter was ignored: {error}");
+            match error.location() {
+                Some(location) => {
+                    let (line, column) = line_index.position(yaml_start + location.index());
+                    diagnostics.push(
+                        Diagnostic::warning(DiagnosticCategory::FrontMatter, message)
+                            .at(line, column),
+                    );
+                }
+                None => warn(message),
+            }
+        }
+    }
+
+    let mut body: String = markdown[..end]
+        .chars()
+        .map(|c| match c {
+            '\n' => "\n".to_string(),
+            _ => " ".repeat(c.len_utf8()),
+        })
+        .collect();
+    body.push_str(&markdown[end..]);
+    (body, config, diagnostics)
+}
+
+/// Find a YAML metadata block at the very start of the document, returning its contents, where
+/// they start, and where the block ends.
+///
+/// `ENABLE_YAML_STYLE_METADATA_BLOCKS` also matches `---` blocks in the middle of a document,
+/// where they're usually a thematic break followed by a setext heading, so it's only enabled
+/// here and the block is only used if it opens the document.
+fn find_front_matter(markdown: &str) -> Option<(String, usize, usize)> {
+    let mut events =
+        Parser::new_ext(markdown, Options::ENABLE_YAML_STYLE_METADATA_BLOCKS).into_offset_iter();
+    let (event, range) = events.next()?;
+    if !matches!(
+        event,
+        Event::Start(Tag::MetadataBlock(MetadataBlockKind::YamlStyle))
+    ) || !markdown[..range.start]
+        .trim_start_matches('\u{feff}')
+        .is_empty()
+    {
+        return None;
+    }
+
+    let mut yaml = String::new();
+    let mut yaml_start = None;
+    for (event, text_range) in events {
+        match event {
+            Event::Text(text) => {
+                yaml_start.get_or_insert(text_range.start);
+                yaml.push_str(&text);
+            }
+            Event::End(TagEnd::MetadataBlock(_)) => break,
+            _ => {}
+        }
+    }
+    Some((yaml, yaml_start.unwrap_or(range.start), range.end))
+}
+
+impl FrontMatter {
+    fn merge_into(self, config: &mut MdpdfConfig, warn: &mut impl FnMut(String)) {
+        if let Some(title) = self.title {
+            config.title = Some(title);
+        }
+        match self.author {
+            Some(OneOrMany::One(author)) => config.authors = vec![author],
+            Some(OneOrMany::Many(authors)) => config.authors = authors,
+            None => {}
+        }
+        match self.keywords {
+            // `keywords: pdf, markdown`
+            Some(OneOrMany::One(keywords)) => {
+                config.keywords = keywords
+                    .split(',')
+                    .map(|keyword| keyword.trim().to_string())
+                    .filter(|keyword| !keyword.is_empty())
+                    .collect();
+            }
+            Some(OneOrMany::Many(keywords)) => config.keywords = keywords,
+            None => {}
+        }
+        if let Some(date) = self.date {
+            config.date = Some(date);
+        }
+        if let Some(lang) = self.lang {
+            if is_valid_lang(&lang) {
+                config.lang = Some(lang);
+            } else {
+                warn(format!(
+                    "Invalid lang '{lang}', expected a language code like 'en' or 'en-US'"
+                ));
+            }
+        }
+        if let Some(page_size) = self.page_size {
+            match PageSize::parse(&page_size) {
+                Ok(page_size) => config.page_size = Some(page_size),
+                Err(error) => warn(error),
+            }
+        }
+        if let Some(margin) = self.margin {
+            // `em` is relative to the document's font size
+            let font_size = self
+                .font_size
+                .filter(|font_size| *font_size > 0.0)
+                .or(config.font_size)
+                .unwrap_or(DEFAULT_FONT_SIZE);
+            match parse_length(&margin, font_size) {
+                Ok(margin) => config.margins = Some(Margins::uniform(margin)),
+                Err(error) => warn(format!("Invalid margin: {error}")),
+            }
+        }
+        if let Some(font_size) = self.font_size {
+            if font_size > 0.0 {
+                config.font_size = Some(font_size);
+            } else {
+                warn(format!(
+                    "Invalid font_size {font_size}, it must be positive"
+                ));
+            }
+        }
+        if let Some(font_family) = self.font_family {
+            config.font_family = Some(font_family);
+        }
+        if let Some(heading_font) = self.heading_font {
+            config.heading_font = Some(heading_font);
+        }
+        if let Some(mono_font) = self.mono_font {
+            config.mono_font = Some(mono_font);
+        }
+        if let Some(header) = self.header {
+            config.header = Some(header);
+        }
+        if let Some(footer) = self.footer {
+            config.footer = Some(footer);
+        }
+        if let Some(title_block) = self.title_block {
+            config.title_block = title_block;
+        }
+        match self.toc {
+            Some(Toc::Enabled(true)) => config.toc = Some(config.toc.take().unwrap_or_default()),
+            Some(Toc::Enabled(false)) => config.toc = None,
+            Some(Toc::Config(mut toc)) => {
+                if toc.depth == Some(0) {
+                    warn("Invalid toc depth 0, it must be at least 1".to_string());
+                    toc.depth = None;
+                }
+                config.toc = Some(toc);
+            }
+            None => {}
+        }
+    }
+}
+
+/// Typst only accepts ISO 639 language codes, optionally followed by an ISO 3166 region.
+fn is_valid_lang(lang: &str) -> bool {
+    let mut parts = lang.split(['-', '_']);
+    let language = parts.next().unwrap_or_default();
+    let region = parts.next();
+    (2..=3).contains(&language.len())
+        && language.chars().all(|c| c.is_ascii_alphabetic())
+        && region.is_none_or(|region| {
+            region.len() == 2 && region.chars().all(|c| c.is_ascii_alphabetic())
+        })
+        && parts.next().is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TocPlacement;
+
+    #[test]
+    fn test_apply_front_matter() {
+        let markdown = "---\ntitle: \"Design dóc\"\nauthor: [Ada, Grace]\nkeywords: pdf, typst\ndate: 2024-05-01\npage_size: a4\nmargin: 20mm\nlang: de-CH\ntoc: true\nsomething_else: 1\n---\n# Héading\n";
+        let (body, config, diagnostics) = apply_front_matter(markdown, &MdpdfConfig::default());
+        assert!(diagnostics.is_empty(), "{diagnostics:?}");
+        assert_eq!(config.title.as_deref(), Some("Design dóc"));
+        assert_eq!(config.authors, vec!["Ada", "Grace"]);
+        assert_eq!(config.keywords, vec!["pdf", "typst"]);
+        assert_eq!(config.date.as_deref(), Some("2024-05-01"));
+        assert!(matches!(config.page_size, Some(PageSize::A4)));
+        assert!((config.margins.unwrap().left - 20.0 / 25.4).abs() < 1e-9);
+        assert_eq!(config.lang.as_deref(), Some("de-CH"));
+        assert!(config.toc.is_some());
+        // Settings that aren't in the front matter are kept
+        assert_eq!(config.font_size, Some(13.0));
+
+        // The front matter is blanked out without moving the rest of the document
+        assert_eq!(body.len(), markdown.len());
+        assert_eq!(body.lines().count(), markdown.lines().count());
+        assert!(body.trim_start().starts_with("# Héading"));
+
+        let markdown = "---\ntoc:\n  depth: 2\n  placement: marker\n---\n";
+        let (_, config, diagnostics) = apply_front_matter(markdown, &MdpdfConfig::default());
+        assert!(diagnostics.is_empty(), "{diagnostics:?}");
+        let toc = config.toc.unwrap();
+        assert_eq!(toc.depth, Some(2));
+        assert_eq!(toc.placement, TocPlacement::Marker);
+
+        let markdown = "---\ntoc:\n  depth: 0\n---\n";
+        let (_, config, diagnostics) = apply_front_matter(markdown, &MdpdfConfig::default());
+        assert_eq!(config.toc.unwrap().depth, None);
+        assert_eq!(
+            diagnostics[0].message,
+            "Invalid toc depth 0, it must be at least 1"
+        );
+    }
+
+    #[test]
+    fn test_front_matter_must_open_the_document() {
+        let markdown = "Intro\n\n---\ntitle: Not front matter\n---\n";
+        let (body, config, diagnostics) = apply_front_matter(markdown, &MdpdfConfig::default());
+        assert_eq!(body, markdown);
+        assert_eq!(config.title, None);
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_front_matter() {
+        let markdown = "---\ntitle: [unclosed\n---\nText\n";
+        let (_, config, diagnostics) = apply_front_matter(markdown, &MdpdfConfig::default());
+        assert_eq!(config.title, None);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].category, DiagnosticCategory::FrontMatter);
+        assert!(
+            diagnostics[0]
+                .message
+                .starts_with("Front matter was ignored")
+        );
+        assert!(diagnostics[0].line.unwrap() >= 2);
+
+        let markdown = "---\npage_size: huge\nlang: English\nfont_size: 11\n---\n";
+        let (_, config, diagnostics) = apply_front_matter(markdown, &MdpdfConfig::default());
+        assert_eq!(config.font_size, Some(11.0));
+        assert_eq!(config.lang, None);
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].line, Some(2));
+    }
+}
@@ -1,15 +1,85 @@
-use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use serde::{Deserialize, Deserializer, Serialize, de};
+use std::fmt;
+use std::path::{Path, PathBuf};
 
+mod front_matter;
+pub mod theme;
+
+pub(crate) use front_matter::apply_front_matter;
+pub use theme::Theme;
+
+/// How documents are converted.
+///
+/// Settings missing when it's deserialized, e.g. from a configuration file (see
+/// [`MdpdfConfig::load`]), keep their defaults.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct MdpdfConfig {
     pub page_size: Option<PageSize>,
+    #[serde(alias = "margin")]
     pub margins: Option<Margins>,
+    /// Font family for body text
     pub font_family: Option<String>,
+    /// Font family for headings. Defaults to `font_family`.
+    pub heading_font: Option<String>,
+    /// Font family for code. Defaults to DejaVu Sans Mono.
+    pub mono_font: Option<String>,
+    /// Font files, or directories to search for font files, in addition to the embedded fonts
+    #[serde(default)]
+    pub font_paths: Vec<String>,
+    /// Also use the fonts installed on the system (needs the `system-fonts` feature)
+    #[serde(default)]
+    pub system_fonts: bool,
     pub font_size: Option<f64>,
     pub header: Option<String>,
     pub footer: Option<String>,
     pub image_handling: Option<ImageHandlingConfig>,
+    pub link_style: Option<LinkStyle>,
+    /// Directory that relative image paths are resolved against. Local images can't be loaded
+    /// if this isn't set.
+    pub base_dir: Option<String>,
+    /// Document title, written to the PDF metadata
+    pub title: Option<String>,
+    /// Document authors, written to the PDF metadata
+    #[serde(default)]
+    pub authors: Vec<String>,
+    /// Document keywords, written to the PDF metadata
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    /// Document date, shown in the title block. Dates like `2024-05-01` are also written to the
+    /// PDF metadata.
+    pub date: Option<String>,
+    /// When the PDF was created, as `YYYY-MM-DD` or `YYYY-MM-DDTHH:MM:SS` (UTC). This is also
+    /// what `datetime.today()` returns. Defaults to the current time; set it for reproducible
+    /// output.
+    pub creation_date: Option<String>,
+    /// Produce byte-identical PDFs for the same input: the PDF identifier is derived from the
+    /// content, and the creation date is `creation_date`, or `SOURCE_DATE_EPOCH` if that's set,
+    /// or left out.
+    #[serde(default)]
+    pub deterministic: bool,
+    /// The PDF standard to conform to. Defaults to PDF 1.7.
+    pub pdf_standard: Option<PdfStandard>,
+    /// Language of the text (e.g. "en" or "de-CH"), used for hyphenation and smart quotes
+    pub lang: Option<String>,
+    /// Show the title, authors and date at the top of the first page
+    #[serde(default)]
+    pub title_block: bool,
+    /// Insert a table of contents
+    pub toc: Option<TocConfig>,
+    /// A built-in theme ("default", "github", "academic" or "compact"), or the path to a
+    /// theme file. See [`Theme`].
+    pub theme: Option<String>,
+    /// Typst code added after the generated template, e.g. `#set par(justify: true)`. Its
+    /// `#import`s of `.typ` files are resolved against `base_dir`.
+    pub preamble: Option<String>,
+    /// Typst code to place the document in, with `{{body}}` where the document goes, e.g.
+    /// `#import "conf.typ": conf` and `#show: conf` followed by `{{body}}`. `#import`s are
+    /// resolved like in the `preamble`.
+    pub template: Option<String>,
+    /// Start each file of a multi-file document on a new page
+    #[serde(default)]
+    pub page_break_between_files: bool,
 }
 
 impl Default for MdpdfConfig {
@@ -18,15 +88,37 @@ impl Default for MdpdfConfig {
             page_size: Some(PageSize::Letter),
             margins: Some(Margins::default()),
             font_family: Some("Libertinus Serif".to_string()),
-            font_size: Some(13.0),
+            heading_font: None,
+            mono_font: None,
+            font_paths: vec![],
+            system_fonts: false,
+            font_size: Some(DEFAULT_FONT_SIZE),
             header: None,
             footer: None,
             image_handling: Some(ImageHandlingConfig::default()),
+            link_style: Some(LinkStyle::default()),
+            base_dir: None,
+            title: None,
+            authors: vec![],
+            keywords: vec![],
+            date: None,
+            creation_date: None,
+            deterministic: false,
+            pdf_standard: None,
+            lang: None,
+            title_block: false,
+            toc: None,
+            theme: None,
+            preamble: None,
+            template: None,
+            page_break_between_files: false,
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// A page size. Deserialized from a name or size like [`PageSize::parse`] accepts, or from the
+/// serialized form.
+#[derive(Debug, Clone, Serialize)]
 pub enum PageSize {
     A4,
     Letter,
@@ -34,7 +126,201 @@ pub enum PageSize {
     Custom { width: f64, height: f64 },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl PageSize {
+    /// Parse a page size name (`letter`, `legal` or `a4`) or a custom size like `8.5inx11in`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "letter" => Ok(PageSize::Letter),
+            "legal" => Ok(PageSize::Legal),
+            "a4" => Ok(PageSize::A4),
+            custom if custom.contains('x') => {
+                // Parse custom page sizes like "8.5inx11in" or "210mmx297mm". The `x` between
+                // the sizes is followed by a number, unlike the one in `px`.
+                let separators: Vec<usize> = custom
+                    .match_indices('x')
+                    .map(|(index, _)| index)
+                    .filter(|index| {
+                        custom[index + 1..].starts_with(|c: char| c.is_ascii_digit() || c == '.')
+                    })
+                    .collect();
+                let [separator] = separators[..] else {
+                    return Err(
+                        "Custom page size must be in format 'WIDTHxHEIGHT' (e.g., '8.5inx11in')"
+                            .to_string(),
+                    );
+                };
+                let width = parse_dimension(&custom[..separator])?;
+                let height = parse_dimension(&custom[separator + 1..])?;
+                if width == 0.0 || height == 0.0 {
+                    return Err(format!("Invalid page size '{value}', it can't be empty"));
+                }
+                Ok(PageSize::Custom { width, height })
+            }
+            _ => Err(format!(
+                "Invalid page size '{value}'. Use 'letter', 'legal', 'a4', or custom format like '8.5inx11in'"
+            )),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PageSize {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Value {
+            Name(String),
+            Custom(CustomPageSize),
+        }
+        #[derive(Deserialize)]
+        enum CustomPageSize {
+            Custom { width: f64, height: f64 },
+        }
+
+        match Value::deserialize(deserializer)? {
+            Value::Name(name) => PageSize::parse(&name).map_err(de::Error::custom),
+            Value::Custom(CustomPageSize::Custom { width, height }) => {
+                Ok(PageSize::Custom { width, height })
+            }
+        }
+    }
+}
+
+/// The font size when none is set, in points
+pub const DEFAULT_FONT_SIZE: f64 = 13.0;
+
+/// Lengths above this many inches (about 5 meters) are rejected as mistakes.
+const MAX_DIMENSION: f64 = 200.0;
+
+/// Parse a length like `1in`, `20mm`, `2.5cm`, `72pt`, `96px` or `2em` into inches. Plain
+/// numbers are inches, and `em` is relative to the default font size.
+pub fn parse_dimension(s: &str) -> Result<f64, String> {
+    parse_length(s, DEFAULT_FONT_SIZE)
+}
+
+/// Like [`parse_dimension`], with `em` relative to `font_size` (in points).
+pub fn parse_length(s: &str, font_size: f64) -> Result<f64, String> {
+    let s = s.trim();
+    // How many inches one of each unit is
+    let units = [
+        ("in", 1.0),
+        ("mm", 1.0 / 25.4),
+        ("cm", 1.0 / 2.54),
+        ("pt", 1.0 / 72.0),
+        // CSS pixels
+        ("px", 1.0 / 96.0),
+        ("em", font_size / 72.0),
+    ];
+    let (number, inches_per_unit) = units
+        .iter()
+        .find_map(|(unit, inches)| Some((s.strip_suffix(unit)?, *inches)))
+        // Assume inches if no unit specified
+        .unwrap_or((s, 1.0));
+    let value = number
+        .trim()
+        .parse::<f64>()
+        .map_err(|_| format!("Invalid dimension: {}", s))?;
+    check_dimension(value * inches_per_unit, s)
+}
+
+/// Reject lengths (in inches) that can't be right, like negative margins.
+fn check_dimension(inches: f64, s: &str) -> Result<f64, String> {
+    if !inches.is_finite() {
+        Err(format!("Invalid dimension: {s}"))
+    } else if inches < 0.0 {
+        Err(format!("Invalid dimension: {s} can't be negative"))
+    } else if inches > MAX_DIMENSION {
+        Err(format!(
+            "Invalid dimension: {s} is too large (at most {MAX_DIMENSION}in)"
+        ))
+    } else {
+        Ok(inches)
+    }
+}
+
+/// A PDF standard that the output conforms to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum PdfStandard {
+    #[default]
+    #[serde(alias = "1.7")]
+    Pdf17,
+    /// PDF/A-2b, for long-term archiving
+    #[serde(alias = "a-2b")]
+    PdfA2b,
+    /// PDF/A-3b, like PDF/A-2b but allows embedding arbitrary files
+    #[serde(alias = "a-3b")]
+    PdfA3b,
+}
+
+impl PdfStandard {
+    /// Parse a standard like `1.7`, `a-2b` or `a-3b`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "1.7" => Ok(PdfStandard::Pdf17),
+            "a-2b" | "pdf/a-2b" => Ok(PdfStandard::PdfA2b),
+            "a-3b" | "pdf/a-3b" => Ok(PdfStandard::PdfA3b),
+            _ => Err(format!(
+                "Invalid PDF standard '{value}'. Use '1.7', 'a-2b', or 'a-3b'"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for PdfStandard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PdfStandard::Pdf17 => write!(f, "PDF 1.7"),
+            PdfStandard::PdfA2b => write!(f, "PDF/A-2b"),
+            PdfStandard::PdfA3b => write!(f, "PDF/A-3b"),
+        }
+    }
+}
+
+/// How links are rendered in the PDF.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum LinkStyle {
+    /// Link text followed by the URL, e.g. "Rust (`https://www.rust-lang.org/`)"
+    #[default]
+    #[serde(alias = "inline-url")]
+    InlineUrl,
+    /// Only the (clickable) link text
+    #[serde(alias = "text-only")]
+    TextOnly,
+    /// Link text, with the URL in a footnote
+    #[serde(alias = "footnote-url")]
+    FootnoteUrl,
+    /// Link text with a number referring to a list of URLs at the end of the document
+    #[serde(alias = "endnotes")]
+    Endnotes,
+}
+
+/// A table of contents, linking to the document's headings.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TocConfig {
+    /// Only list headings up to this level (e.g. `2` for `#` and `##`). All levels by default.
+    pub depth: Option<u8>,
+    /// Title shown above the table of contents. Defaults to "Contents" in the document's
+    /// language; an empty title hides it.
+    pub title: Option<String>,
+    pub placement: TocPlacement,
+}
+
+/// Where the table of contents goes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum TocPlacement {
+    /// Before the document (after the title block, if there is one)
+    #[default]
+    #[serde(alias = "top")]
+    Top,
+    /// In place of a `[[toc]]` paragraph or a `<!-- toc -->` comment. Falls back to the top of
+    /// the document if there's no marker.
+    #[serde(alias = "marker")]
+    Marker,
+}
+
+/// Page margins in inches. Deserialized from numbers (inches) or lengths like `20mm`, either
+/// one for all sides or a table of sides; sides that are left out get the default.
+#[derive(Debug, Clone, Serialize)]
 pub struct Margins {
     pub top: f64,
     pub bottom: f64,
@@ -42,23 +328,81 @@ pub struct Margins {
     pub right: f64,
 }
 
+impl Margins {
+    /// The same margin on all sides, in inches
+    pub fn uniform(size: f64) -> Self {
+        Self {
+            top: size,
+            bottom: size,
+            left: size,
+            right: size,
+        }
+    }
+}
+
 impl Default for Margins {
     fn default() -> Self {
-        Self {
-            top: 1.0,
-            bottom: 1.0,
-            left: 1.0,
-            right: 1.0,
+        Self::uniform(1.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Margins {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Length {
+            Inches(f64),
+            Text(String),
+        }
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Value {
+            Uniform(Length),
+            Sides {
+                top: Option<Length>,
+                bottom: Option<Length>,
+                left: Option<Length>,
+                right: Option<Length>,
+            },
+        }
+
+        let inches = |length: Option<Length>| match length {
+            None => Ok(Margins::default().top),
+            Some(Length::Inches(inches)) => {
+                check_dimension(inches, &format!("{inches}in")).map_err(de::Error::custom)
+            }
+            Some(Length::Text(text)) => parse_dimension(&text).map_err(de::Error::custom),
+        };
+        match Value::deserialize(deserializer)? {
+            Value::Uniform(length) => Ok(Margins::uniform(inches(Some(length))?)),
+            Value::Sides {
+                top,
+                bottom,
+                left,
+                right,
+            } => Ok(Margins {
+                top: inches(top)?,
+                bottom: inches(bottom)?,
+                left: inches(left)?,
+                right: inches(right)?,
+            }),
         }
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct ImageHandlingConfig {
     pub download_timeout: u64, // in milliseconds
     pub cache_directory: Option<String>,
     pub max_image_size: Option<u64>, // in bytes
     pub allowed_domains: Vec<String>,
+    /// Allow local images outside of `base_dir` (e.g. `../../etc/passwd`)
+    #[serde(default)]
+    pub allow_outside_base_dir: bool,
+    /// Never access the network; remote images are only loaded from `cache_directory`
+    #[serde(default)]
+    pub offline: bool,
 }
 
 impl Default for ImageHandlingConfig {
@@ -69,6 +413,8 @@ impl Default for ImageHandlingConfig {
             cache_directory: None,
             max_image_size: Some(10 * 1024 * 1024), // 10MB
             allowed_domains: vec![],
+            allow_outside_base_dir: false,
+            offline: false,
         }
     }
 }
@@ -78,3 +424,135 @@ impl ImageHandlingConfig {
         self.cache_directory.as_ref().map(PathBuf::from)
     }
 }
+
+impl MdpdfConfig {
+    pub fn base_dir_path(&self) -> Option<PathBuf> {
+        self.base_dir.as_ref().map(PathBuf::from)
+    }
+
+    /// Load a configuration file: TOML if it ends in `.toml`, JSON otherwise (e.g.
+    /// `.mdpdfrc.json`). Relative paths in it (`base_dir`, `font_paths`, a theme file and the
+    /// image cache directory) are relative to the file.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let invalid = |error: String| format!("Invalid config file '{}': {error}", path.display());
+        let source = std::fs::read_to_string(path)
+            .map_err(|error| format!("Couldn't read config file '{}': {error}", path.display()))?;
+        let mut config: Self = if path
+            .extension()
+            .is_some_and(|extension| extension == "toml")
+        {
+            toml::from_str(&source).map_err(|error| invalid(error.to_string()))?
+        } else {
+            serde_json::from_str(&source).map_err(|error| invalid(error.to_string()))?
+        };
+
+        let directory = path.parent().unwrap_or(Path::new(""));
+        let resolve = |path: &mut String| {
+            *path = directory.join(&*path).to_string_lossy().to_string();
+        };
+        let paths = config
+            .base_dir
+            .iter_mut()
+            .chain(&mut config.font_paths)
+            .chain(
+                config
+                    .theme
+                    .iter_mut()
+                    .filter(|theme| theme::is_path(theme)),
+            )
+            .chain(
+                config
+                    .image_handling
+                    .iter_mut()
+                    .flat_map(|image_handling| &mut image_handling.cache_directory),
+            );
+        paths.for_each(resolve);
+        if config.toc.as_ref().is_some_and(|toc| toc.depth == Some(0)) {
+            return Err(invalid("toc depth must be at least 1".to_string()));
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dimension() {
+        for (dimension, inches) in [
+            ("1in", 1.0),
+            ("25.4mm", 1.0),
+            ("2.54cm", 1.0),
+            ("72pt", 1.0),
+            ("96px", 1.0),
+            ("0.5", 0.5),
+            (" 36 pt ", 0.5),
+        ] {
+            let parsed = parse_dimension(dimension).unwrap();
+            assert!((parsed - inches).abs() < 1e-9, "{dimension}: {parsed}");
+        }
+        assert!((parse_length("2em", 9.0).unwrap() - 0.25).abs() < 1e-9);
+        for (dimension, error) in [
+            ("1ft", "Invalid dimension: 1ft"),
+            ("-1cm", "can't be negative"),
+            ("1e6mm", "too large"),
+            ("inf", "Invalid dimension: inf"),
+        ] {
+            let message = parse_dimension(dimension).unwrap_err();
+            assert!(message.contains(error), "{message}");
+        }
+
+        assert!(matches!(
+            PageSize::parse("800pxx600px"),
+            Ok(PageSize::Custom { width, height })
+                if (width - 800.0 / 96.0).abs() < 1e-9 && (height - 6.25).abs() < 1e-9
+        ));
+        assert!(PageSize::parse("0inx11in").is_err());
+        assert!(PageSize::parse("8.5inx11inx2in").is_err());
+    }
+
+    #[test]
+    fn test_load_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mdpdf.toml");
+        std::fs::write(
+            &path,
+            "page_size = \"a4\"\nfont_paths = [\"fonts\"]\nlink_style = \"footnote-url\"\n\
+             [margins]\ntop = \"20mm\"\nleft = 0.5\n\
+             [image_handling]\nallowed_domains = [\"example.com\"]\n",
+        )
+        .unwrap();
+        let config = MdpdfConfig::load(&path).unwrap();
+        assert!(matches!(config.page_size, Some(PageSize::A4)));
+        let margins = config.margins.unwrap();
+        assert!((margins.top - 20.0 / 25.4).abs() < 1e-9);
+        assert_eq!((margins.left, margins.right), (0.5, 1.0));
+        assert_eq!(config.link_style, Some(LinkStyle::FootnoteUrl));
+        // Paths are relative to the config file, and settings that aren't set keep defaults
+        assert_eq!(
+            config.font_paths,
+            vec![dir.path().join("fonts").to_string_lossy().to_string()]
+        );
+        assert_eq!(config.font_size, Some(13.0));
+        let image_handling = config.image_handling.unwrap();
+        assert_eq!(image_handling.allowed_domains, vec!["example.com"]);
+        assert_eq!(image_handling.download_timeout, 2000);
+
+        let path = dir.path().join(".mdpdfrc.json");
+        std::fs::write(&path, r#"{"page_size": "8.5inx11in", "margin": "1cm"}"#).unwrap();
+        let config = MdpdfConfig::load(&path).unwrap();
+        assert!(matches!(
+            config.page_size,
+            Some(PageSize::Custom {
+                width: 8.5,
+                height: 11.0
+            })
+        ));
+        assert!((config.margins.unwrap().bottom - 1.0 / 2.54).abs() < 1e-9);
+
+        std::fs::write(&path, r#"{"font_sise": 11}"#).unwrap();
+        let message = MdpdfConfig::load(&path).unwrap_err();
+        assert!(message.contains("unknown field `font_sise`"), "{message}");
+    }
+}
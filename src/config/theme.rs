@@ -0,0 +1,372 @@
+use serde::Deserialize;
+use std::path::Path;
+
+/// The themes built into mdpdf, by name.
+const BUILT_IN_THEMES: [(&str, &str); 4] = [
+    ("default", include_str!("../../themes/default.toml")),
+    ("github", include_str!("../../themes/github.toml")),
+    ("academic", include_str!("../../themes/academic.toml")),
+    ("compact", include_str!("../../themes/compact.toml")),
+];
+
+/// How the document is styled: colors, spacing, and the look of headings, code blocks,
+/// blockquotes and tables.
+///
+/// Themes are TOML files (see `themes/` for the built-in ones). A theme can start from another
+/// one with `extends = "github"`, and only override some of its settings. Anything a theme
+/// doesn't set is left at Typst's default.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Theme {
+    pub text: TextTheme,
+    pub link: LinkTheme,
+    pub heading: HeadingTheme,
+    pub code: CodeTheme,
+    pub blockquote: BlockquoteTheme,
+    pub table: TableTheme,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TextTheme {
+    pub color: Option<Color>,
+    /// Space between lines of a paragraph
+    pub leading: Option<Length>,
+    /// Space between paragraphs
+    pub paragraph_spacing: Option<Length>,
+    pub justify: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LinkTheme {
+    pub color: Option<Color>,
+    pub underline: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeadingTheme {
+    pub color: Option<Color>,
+    pub weight: Option<Weight>,
+    /// Text size by level, starting at `#`. Levels that aren't listed keep Typst's size.
+    pub sizes: Vec<Length>,
+    /// Space above headings, by level
+    pub above: Vec<Length>,
+    /// Space below headings, by level
+    pub below: Vec<Length>,
+    /// Draw a line under headings up to this level, e.g. `2` for `#` and `##`
+    pub rule_levels: u8,
+    pub rule: Option<Stroke>,
+    /// Number headings with a Typst numbering pattern like "1.1"
+    pub numbering: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CodeTheme {
+    /// Background of code blocks
+    pub fill: Option<Color>,
+    /// Border of code blocks
+    pub stroke: Option<Stroke>,
+    pub inset: Option<Length>,
+    pub radius: Option<Length>,
+    /// Background of inline code
+    pub inline_fill: Option<Color>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlockquoteTheme {
+    /// The line to the left of blockquotes
+    pub border: Option<Stroke>,
+    pub fill: Option<Color>,
+    pub color: Option<Color>,
+    pub inset: Option<Length>,
+    pub above: Option<Length>,
+    pub below: Option<Length>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TableTheme {
+    /// Lines between cells
+    pub stroke: Option<Stroke>,
+    /// Background of the first row
+    pub header_fill: Option<Color>,
+    pub inset: Option<Length>,
+}
+
+/// A line, e.g. `{ width = "1pt", color = "gray" }`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Stroke {
+    pub width: Length,
+    pub color: Option<Color>,
+}
+
+/// A color, as a hex code like `#d0d7de` or one of Typst's color names like `gray`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Color(String);
+
+/// A length with a unit: `pt`, `mm`, `cm`, `in` or `em`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Length(String);
+
+/// A font weight like `bold`, or a number from 100 to 900.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Weight(String);
+
+impl Theme {
+    /// Load a built-in theme by name, or a theme file by path.
+    pub fn load(theme: &str) -> Result<Self, String> {
+        let value = Self::load_value(theme, None, 0)?;
+        value
+            .try_into()
+            .map_err(|error| format!("Invalid theme '{theme}': {error}"))
+    }
+
+    /// Load a theme's settings, merged over those of the themes it extends. Theme files that
+    /// another theme extends are found relative to its directory, `dir`.
+    fn load_value(theme: &str, dir: Option<&Path>, depth: usize) -> Result<toml::Table, String> {
+        // Themes can't extend each other forever
+        if depth > 8 {
+            return Err(format!("Theme '{theme}' extends too many themes"));
+        }
+        let mut path = None;
+        let source = match BUILT_IN_THEMES.iter().find(|(name, _)| *name == theme) {
+            Some((_, source)) => source.to_string(),
+            None if is_path(theme) => {
+                let file = path.insert(dir.map_or_else(|| theme.into(), |dir| dir.join(theme)));
+                std::fs::read_to_string(file)
+                    .map_err(|error| format!("Couldn't read theme '{theme}': {error}"))?
+            }
+            None => {
+                let names: Vec<&str> = BUILT_IN_THEMES.iter().map(|(name, _)| *name).collect();
+                return Err(format!(
+                    "Unknown theme '{theme}'. Use {} or the path to a .toml file",
+                    names.join(", ")
+                ));
+            }
+        };
+        let mut table: toml::Table = source
+            .parse()
+            .map_err(|error| format!("Invalid theme '{theme}': {error}"))?;
+
+        match table.remove("extends") {
+            Some(toml::Value::String(base)) => {
+                let dir = path.as_deref().and_then(Path::parent);
+                let mut base = Self::load_value(&base, dir, depth + 1)?;
+                merge(&mut base, table);
+                Ok(base)
+            }
+            Some(_) => Err(format!(
+                "Invalid theme '{theme}': `extends` must be a theme name or path"
+            )),
+            None => Ok(table),
+        }
+    }
+}
+
+/// Merge `overrides` into `base`, replacing values but merging tables.
+fn merge(base: &mut toml::Table, overrides: toml::Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(value)) => merge(base, value),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+pub(crate) fn is_path(theme: &str) -> bool {
+    let path = Path::new(theme);
+    path.extension().is_some() || path.components().count() > 1
+}
+
+/// Typst's predefined colors
+const COLOR_NAMES: [&str; 18] = [
+    "black", "gray", "silver", "white", "navy", "blue", "aqua", "teal", "eastern", "purple",
+    "fuchsia", "maroon", "red", "orange", "yellow", "olive", "green", "lime",
+];
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, String> {
+        let hex = value.strip_prefix('#').is_some_and(|hex| {
+            matches!(hex.len(), 3 | 4 | 6 | 8) && hex.chars().all(|c| c.is_ascii_hexdigit())
+        });
+        if hex || COLOR_NAMES.contains(&value.as_str()) {
+            Ok(Self(value))
+        } else {
+            Err(format!(
+                "invalid color '{value}', expected a hex code like '#d0d7de' or a color name like 'gray'"
+            ))
+        }
+    }
+}
+
+impl TryFrom<String> for Length {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, String> {
+        let valid = ["pt", "mm", "cm", "in", "em"].iter().any(|unit| {
+            // Only plain numbers like `0.8`, so the length is valid Typst too
+            value.strip_suffix(unit).is_some_and(|number| {
+                number.chars().all(|c| c.is_ascii_digit() || c == '.')
+                    && !number.ends_with('.')
+                    && number.parse::<f64>().is_ok()
+            })
+        });
+        if valid {
+            Ok(Self(value))
+        } else {
+            Err(format!(
+                "invalid length '{value}', expected a number with a unit like '1pt' or '0.8em'"
+            ))
+        }
+    }
+}
+
+/// Typst's named font weights
+const WEIGHT_NAMES: [&str; 9] = [
+    "thin",
+    "extralight",
+    "light",
+    "regular",
+    "medium",
+    "semibold",
+    "bold",
+    "extrabold",
+    "black",
+];
+
+impl TryFrom<String> for Weight {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, String> {
+        let number = value
+            .parse::<u16>()
+            .is_ok_and(|weight| (100..=900).contains(&weight));
+        if number || WEIGHT_NAMES.contains(&value.as_str()) {
+            Ok(Self(value))
+        } else {
+            Err(format!(
+                "invalid font weight '{value}', expected e.g. 'bold' or a number from 100 to 900"
+            ))
+        }
+    }
+}
+
+impl Color {
+    /// The color as Typst code
+    pub fn to_typst(&self) -> String {
+        if self.0.starts_with('#') {
+            format!("rgb(\"{}\")", self.0)
+        } else {
+            self.0.clone()
+        }
+    }
+}
+
+impl Length {
+    /// The length as Typst code
+    pub fn to_typst(&self) -> String {
+        self.0.clone()
+    }
+}
+
+impl Weight {
+    /// The weight as Typst code
+    pub fn to_typst(&self) -> String {
+        if self.0.parse::<u16>().is_ok() {
+            self.0.clone()
+        } else {
+            format!("\"{}\"", self.0)
+        }
+    }
+}
+
+impl Stroke {
+    /// The stroke as Typst code, e.g. `1pt + gray`
+    pub fn to_typst(&self) -> String {
+        match &self.color {
+            Some(color) => format!("{} + {}", self.width.to_typst(), color.to_typst()),
+            None => self.width.to_typst(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_built_in_themes() {
+        for (name, _) in BUILT_IN_THEMES {
+            Theme::load(name).unwrap_or_else(|error| panic!("{name}: {error}"));
+        }
+        let github = Theme::load("github").unwrap();
+        assert_eq!(github.link.color, Some(Color("#0969da".to_string())));
+        assert!(
+            Theme::load("nope")
+                .unwrap_err()
+                .starts_with("Unknown theme 'nope'")
+        );
+    }
+
+    #[test]
+    fn test_custom_theme() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("theme.toml");
+        std::fs::write(
+            &path,
+            "extends = \"github\"\n[link]\ncolor = \"purple\"\n[table]\ninset = \"2pt\"\n",
+        )
+        .unwrap();
+        let theme = Theme::load(&path.to_string_lossy()).unwrap();
+        let github = Theme::load("github").unwrap();
+        // Settings in the theme file override the theme it extends
+        assert_eq!(theme.link.color, Some(Color("purple".to_string())));
+        assert_eq!(theme.table.inset, Some(Length("2pt".to_string())));
+        assert_eq!(theme.table.stroke, github.table.stroke);
+        assert_eq!(theme.heading, github.heading);
+
+        // Theme files are extended relative to the extending theme, not the working directory
+        let base_dir = dir.path().join("themes");
+        std::fs::create_dir(&base_dir).unwrap();
+        std::fs::write(base_dir.join("base.toml"), "[link]\ncolor = \"teal\"\n").unwrap();
+        std::fs::write(
+            base_dir.join("theme.toml"),
+            "extends = \"base.toml\"\n[table]\ninset = \"3pt\"\n",
+        )
+        .unwrap();
+        assert_ne!(std::env::current_dir().unwrap(), base_dir);
+        let theme = Theme::load(&base_dir.join("theme.toml").to_string_lossy()).unwrap();
+        assert_eq!(theme.link.color, Some(Color("teal".to_string())));
+        assert_eq!(theme.table.inset, Some(Length("3pt".to_string())));
+
+        for (source, error) in [
+            ("[link]\ncolor = \"#12345\"\n", "invalid color '#12345'"),
+            ("[code]\ninset = \"1px\"\n", "invalid length '1px'"),
+            (
+                "[heading]\nweight = \"heavy\"\n",
+                "invalid font weight 'heavy'",
+            ),
+            ("[heading]\nsize = [\"2em\"]\n", "unknown field `size`"),
+            (
+                "extends = \"missing.toml\"\n",
+                "Couldn't read theme 'missing.toml'",
+            ),
+        ] {
+            std::fs::write(&path, source).unwrap();
+            let message = Theme::load(&path.to_string_lossy()).unwrap_err();
+            assert!(message.contains(error), "{message}");
+        }
+    }
+}
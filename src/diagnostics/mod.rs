@@ -0,0 +1,224 @@
+use std::borrow::Cow;
+use std::fmt;
+
+mod source_map;
+
+pub use source_map::{SourceLocation, SourceMap};
+
+/// How serious a diagnostic is. Warnings mean part of the document was rendered with a fallback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// The part of the conversion a diagnostic came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagnosticCategory {
+    /// An image couldn't be loaded or converted
+    Image,
+    /// An HTML tag isn't supported and was dropped or reduced to its text
+    Html,
+    /// Lists or blockquotes are nested deeper than we render
+    Nesting,
+    /// A link couldn't be resolved
+    Link,
+    /// TeX math couldn't be translated to Typst math
+    Math,
+    /// Reported by the Typst compiler
+    Typst,
+    /// The YAML front matter is invalid
+    FrontMatter,
+    /// A font couldn't be loaded or found
+    Font,
+}
+
+impl fmt::Display for DiagnosticCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiagnosticCategory::Image => write!(f, "image"),
+            DiagnosticCategory::Html => write!(f, "html"),
+            DiagnosticCategory::Nesting => write!(f, "nesting"),
+            DiagnosticCategory::Link => write!(f, "link"),
+            DiagnosticCategory::Math => write!(f, "math"),
+            DiagnosticCategory::Typst => write!(f, "typst"),
+            DiagnosticCategory::FrontMatter => write!(f, "front-matter"),
+            DiagnosticCategory::Font => write!(f, "font"),
+        }
+    }
+}
+
+/// Something that went wrong (but didn't stop the conversion) while converting a document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub category: DiagnosticCategory,
+    pub message: String,
+    /// 1-based line in the Markdown source, if known
+    pub line: Option<usize>,
+    /// 1-based column (in characters) in the Markdown source, if known
+    pub column: Option<usize>,
+    /// The Markdown source line, if known
+    pub snippet: Option<String>,
+    /// The Markdown file, when several files are converted into one document
+    pub file: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn warning(category: DiagnosticCategory, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            category,
+            message: message.into(),
+            line: None,
+            column: None,
+            snippet: None,
+            file: None,
+        }
+    }
+
+    pub fn error(category: DiagnosticCategory, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            ..Self::warning(category, message)
+        }
+    }
+
+    /// Set the source position, unless the diagnostic already has one.
+    pub fn at(mut self, line: usize, column: usize) -> Self {
+        if self.line.is_none() {
+            self.line = Some(line);
+            self.column = Some(column);
+        }
+        self
+    }
+
+    /// Set the source position and the source line it points into.
+    pub fn with_location(mut self, location: SourceLocation) -> Self {
+        self.line = Some(location.line);
+        self.column = Some(location.column);
+        self.snippet = Some(location.snippet);
+        if location.file.is_some() {
+            self.file = location.file;
+        }
+        self
+    }
+
+    /// Set the file the diagnostic is in, unless it already has one.
+    pub fn in_file(mut self, file: &str) -> Self {
+        self.file.get_or_insert_with(|| file.to_string());
+        self
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => write!(f, "{line}:{column}: ")?,
+            (Some(line), None) => write!(f, "{line}: ")?,
+            _ => {}
+        }
+        write!(f, "{}[{}]: {}", self.severity, self.category, self.message)?;
+        if let (Some(line), Some(snippet)) = (self.line, &self.snippet) {
+            // Show the line with a marker under the column, like rustc
+            let gutter = " ".repeat(line.to_string().len());
+            write!(f, "\n{gutter} |\n{line} | {snippet}")?;
+            if let Some(column) = self.column {
+                write!(f, "\n{gutter} | {}^", " ".repeat(column - 1))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Maps byte offsets in a source string to 1-based line and column numbers.
+#[derive(Debug, Clone)]
+pub(crate) struct LineIndex<'a> {
+    source: Cow<'a, str>,
+    line_starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    pub(crate) fn new(source: impl Into<Cow<'a, str>>) -> Self {
+        let source = source.into();
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(pos, _)| pos + 1))
+            .collect();
+        Self {
+            source,
+            line_starts,
+        }
+    }
+
+    pub(crate) fn position(&self, offset: usize) -> (usize, usize) {
+        let offset = offset.min(self.source.len());
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let line_start = self.line_starts[line];
+        let column = self
+            .source
+            .get(line_start..offset)
+            .map_or(0, |prefix| prefix.chars().count());
+        (line + 1, column + 1)
+    }
+
+    /// The text of a 1-based line, without its line ending.
+    pub(crate) fn line(&self, line: usize) -> &str {
+        let Some(&start) = self.line_starts.get(line.wrapping_sub(1)) else {
+            return "";
+        };
+        let end = self
+            .line_starts
+            .get(line)
+            .map_or(self.source.len(), |&next| next - 1);
+        self.source[start..end].trim_end_matches('\r')
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_index() {
+        let index = LineIndex::new("one\ntwö\n\nfour");
+        assert_eq!(index.position(0), (1, 1));
+        assert_eq!(index.position(2), (1, 3));
+        assert_eq!(index.position(4), (2, 1));
+        // `ö` is two bytes but one column
+        assert_eq!(index.position(8), (2, 4));
+        assert_eq!(index.position(9), (3, 1));
+        assert_eq!(index.position(10), (4, 1));
+        assert_eq!(index.position(100), (4, 5));
+        assert_eq!(index.line(2), "twö");
+        assert_eq!(index.line(3), "");
+        assert_eq!(index.line(4), "four");
+        assert_eq!(index.line(5), "");
+    }
+
+    #[test]
+    fn test_display() {
+        let diagnostic = Diagnostic::warning(DiagnosticCategory::Image, "failed").at(3, 7);
+        assert_eq!(diagnostic.to_string(), "3:7: warning[image]: failed");
+        let diagnostic = Diagnostic::error(DiagnosticCategory::Typst, "oops");
+        assert_eq!(diagnostic.to_string(), "error[typst]: oops");
+        let diagnostic = diagnostic.with_location(SourceLocation {
+            line: 12,
+            column: 3,
+            snippet: "a *b".to_string(),
+            file: None,
+        });
+        assert_eq!(
+            diagnostic.to_string(),
+            "12:3: error[typst]: oops\n   |\n12 | a *b\n   |   ^"
+        );
+    }
+}
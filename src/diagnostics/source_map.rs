@@ -0,0 +1,134 @@
+use super::LineIndex;
+
+/// Maps byte offsets in generated Typst code back to the Markdown it was generated from.
+///
+/// The map is built from checkpoints recorded while converting: each one says that the Typst
+/// code from some offset on was generated for the Markdown starting at some offset. Text that
+/// is buffered before being written (headings, links, table cells, footnotes, ...) maps to the
+/// start of the enclosing element.
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    // The Markdown sources, with their file names when several files were combined
+    sources: Vec<(Option<String>, LineIndex<'static>)>,
+    // (Typst offset, source index, Markdown offset), sorted by Typst offset
+    checkpoints: Vec<(usize, usize, usize)>,
+}
+
+/// A position in the Markdown source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+    /// 1-based line
+    pub line: usize,
+    /// 1-based column, in characters
+    pub column: usize,
+    /// The text of the line
+    pub snippet: String,
+    /// The Markdown file, when several files were combined
+    pub file: Option<String>,
+}
+
+impl SourceMap {
+    pub fn new(markdown: &str) -> Self {
+        Self {
+            sources: vec![(None, LineIndex::new(markdown.to_string()))],
+            checkpoints: Vec::new(),
+        }
+    }
+
+    /// Record that the Typst code from `typst_offset` on comes from the Markdown at
+    /// `markdown_offset`.
+    pub(crate) fn record(&mut self, typst_offset: usize, markdown_offset: usize) {
+        let source = self.sources.len().saturating_sub(1);
+        match self.checkpoints.last_mut() {
+            // Nothing was generated since the last checkpoint, so the next output belongs here
+            Some(last) if last.0 == typst_offset => *last = (typst_offset, source, markdown_offset),
+            Some(last) if (last.1, last.2) == (source, markdown_offset) => {}
+            _ => self
+                .checkpoints
+                .push((typst_offset, source, markdown_offset)),
+        }
+    }
+
+    /// Add the map of another file's Typst code, which starts at `typst_offset`.
+    pub(crate) fn append(&mut self, file: &str, other: SourceMap, typst_offset: usize) {
+        let first_source = self.sources.len();
+        self.sources.extend(
+            other
+                .sources
+                .into_iter()
+                .map(|(_, line_index)| (Some(file.to_string()), line_index)),
+        );
+        self.checkpoints.extend(other.checkpoints.into_iter().map(
+            |(offset, source, markdown_offset)| {
+                (
+                    offset + typst_offset,
+                    first_source + source,
+                    markdown_offset,
+                )
+            },
+        ));
+    }
+
+    /// Find the Markdown that generated the Typst code at `typst_offset`.
+    pub fn locate(&self, typst_offset: usize) -> Option<SourceLocation> {
+        let index = self
+            .checkpoints
+            .partition_point(|&(offset, _, _)| offset <= typst_offset)
+            .checked_sub(1)?;
+        let (_, source, markdown_offset) = self.checkpoints[index];
+        let (file, line_index) = self.sources.get(source)?;
+        let (line, column) = line_index.position(markdown_offset);
+        let snippet = line_index.line(line).to_string();
+        Some(SourceLocation {
+            line,
+            column,
+            snippet,
+            file: file.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locate() {
+        let mut source_map = SourceMap::new("# Title\n\nSome *text*\n");
+        source_map.record(0, 0);
+        // An event that generated nothing is replaced by the next one
+        source_map.record(10, 9);
+        source_map.record(10, 14);
+        source_map.record(20, 20);
+
+        assert_eq!(
+            source_map.locate(3),
+            Some(SourceLocation {
+                line: 1,
+                column: 1,
+                snippet: "# Title".to_string(),
+                file: None,
+            })
+        );
+        let location = source_map.locate(12).unwrap();
+        assert_eq!((location.line, location.column), (3, 6));
+        assert_eq!(location.snippet, "Some *text*");
+        assert_eq!(source_map.locate(100).unwrap().column, 12);
+
+        assert_eq!(SourceMap::default().locate(0), None);
+    }
+
+    #[test]
+    fn test_append() {
+        let mut source_map = SourceMap::new("");
+        let mut other = SourceMap::new("Intro\n\nMore\n");
+        other.record(0, 0);
+        other.record(5, 7);
+        source_map.append("intro.md", other, 100);
+
+        assert_eq!(source_map.locate(50), None);
+        let location = source_map.locate(106).unwrap();
+        assert_eq!((location.line, location.column), (3, 1));
+        assert_eq!(location.file.as_deref(), Some("intro.md"));
+    }
+}
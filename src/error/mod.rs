@@ -0,0 +1,179 @@
+use std::fmt;
+
+use crate::diagnostics::Diagnostic;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Errors returned by the library API.
+#[derive(Debug)]
+pub enum Error {
+    /// The input document is invalid
+    Input(String),
+    /// An image couldn't be loaded or decoded
+    Image(ImageError),
+    /// An HTML fragment couldn't be converted
+    Html(String),
+    /// The generated Typst code didn't compile. Each diagnostic points at the Markdown that the
+    /// failing code was generated from, when that's known.
+    Compile(Vec<Diagnostic>),
+    /// The compiled document couldn't be exported as a PDF
+    PdfExport(Vec<Diagnostic>),
+    /// Reading or writing a file (e.g. the image cache) failed
+    Io(std::io::Error),
+}
+
+impl Error {
+    /// A stable identifier for the kind of error, e.g. for error codes in the Node binding.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Input(_) => "MDPDF_INPUT",
+            Error::Image(_) => "MDPDF_IMAGE",
+            Error::Html(_) => "MDPDF_HTML",
+            Error::Compile(_) => "MDPDF_COMPILE",
+            Error::PdfExport(_) => "MDPDF_PDF_EXPORT",
+            Error::Io(_) => "MDPDF_IO",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Input(message) => write!(f, "Invalid input: {message}"),
+            Error::Image(error) => write!(f, "{error}"),
+            Error::Html(message) => write!(f, "HTML conversion failed: {message}"),
+            Error::Compile(diagnostics) => {
+                write!(f, "Typst compilation failed:")?;
+                for diagnostic in diagnostics {
+                    write!(f, "\n{diagnostic}")?;
+                }
+                Ok(())
+            }
+            Error::PdfExport(diagnostics) => {
+                write!(f, "PDF generation failed:")?;
+                for diagnostic in diagnostics {
+                    write!(f, "\n{diagnostic}")?;
+                }
+                Ok(())
+            }
+            Error::Io(error) => write!(f, "I/O error: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Image(error) => Some(error),
+            Error::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<ImageError> for Error {
+    fn from(error: ImageError) -> Self {
+        Error::Image(error)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+/// Why an image couldn't be loaded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImageError {
+    /// The image URL isn't a data URL, an HTTP(S) URL, or a local path we can resolve
+    UnsupportedUrl { url: String },
+    /// The data URL is malformed or not base64 encoded
+    InvalidDataUrl { reason: String },
+    /// A local image couldn't be read
+    Read { path: String, reason: String },
+    /// A local image is outside of the base directory
+    OutsideBaseDir { path: String, base_dir: String },
+    /// The image's host isn't in `allowed_domains`
+    DomainNotAllowed { url: String, host: String },
+    /// The download failed (e.g. a connection error or an error status)
+    Download { url: String, reason: String },
+    /// The download didn't finish within `download_timeout`
+    Timeout { url: String, timeout_ms: u64 },
+    /// The image is larger than `max_image_size`
+    TooLarge { url: String, max_size: u64 },
+    /// The image isn't cached and network access is disabled
+    Offline { url: String },
+    /// The image data isn't in a format we can decode
+    Decode { reason: String },
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::UnsupportedUrl { url } => write!(f, "Unsupported image URL format: {url}"),
+            ImageError::InvalidDataUrl { reason } => write!(f, "Invalid data URL: {reason}"),
+            ImageError::Read { path, reason } => {
+                write!(f, "Failed to read image '{path}': {reason}")
+            }
+            ImageError::OutsideBaseDir { path, base_dir } => {
+                write!(
+                    f,
+                    "Image path '{path}' is outside of the base directory '{base_dir}'"
+                )
+            }
+            ImageError::DomainNotAllowed { url, host } => {
+                write!(
+                    f,
+                    "Image host '{host}' is not in the allowed domains: {url}"
+                )
+            }
+            ImageError::Download { url, reason } => {
+                write!(f, "Failed to download image: {reason}: {url}")
+            }
+            ImageError::Timeout { url, timeout_ms } => {
+                write!(f, "Image download timed out after {timeout_ms}ms: {url}")
+            }
+            ImageError::TooLarge { url, max_size } => {
+                write!(f, "Image is larger than the {max_size} byte limit: {url}")
+            }
+            ImageError::Offline { url } => {
+                write!(
+                    f,
+                    "Image is not cached and network access is disabled: {url}"
+                )
+            }
+            ImageError::Decode { reason } => write!(f, "Failed to decode image: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostics::DiagnosticCategory;
+
+    #[test]
+    fn test_error_display_and_source() {
+        let error = Error::from(ImageError::Offline {
+            url: "https://example.com/a.png".to_string(),
+        });
+        assert_eq!(error.code(), "MDPDF_IMAGE");
+        assert_eq!(
+            error.to_string(),
+            "Image is not cached and network access is disabled: https://example.com/a.png"
+        );
+        assert!(std::error::Error::source(&error).is_some());
+
+        let error = Error::Compile(vec![
+            Diagnostic::error(DiagnosticCategory::Typst, "unclosed delimiter").at(3, 1),
+        ]);
+        assert_eq!(error.code(), "MDPDF_COMPILE");
+        assert_eq!(
+            error.to_string(),
+            "Typst compilation failed:\n3:1: error[typst]: unclosed delimiter"
+        );
+    }
+}
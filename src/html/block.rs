@@ -1,8 +1,11 @@
 use html_parser::{Dom, Element, Node};
 use std::collections::HashMap;
 
+use crate::diagnostics::{Diagnostic, DiagnosticCategory};
+use crate::error::{Error, Result};
 use crate::escape_text;
 use crate::utils::images::ImageProcessor;
+use crate::utils::links::{FileLinks, LinkRenderer};
 pub struct HtmlToTypstConverter {
     // Track nested structures
     in_code_block: bool,
@@ -16,6 +19,12 @@ pub struct HtmlToTypstConverter {
     current_row: Vec<String>,
     in_table_cell: bool,
     current_cell_content: String,
+    // Shared with the Markdown conversion while converting a block (see `convert_html_to_typst`)
+    links: LinkRenderer,
+    // The Markdown file the HTML is in, for links to other files and relative image paths
+    file: FileLinks,
+    // Problems found since the last `take_diagnostics`, without source positions
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl HtmlToTypstConverter {
@@ -32,9 +41,33 @@ impl HtmlToTypstConverter {
             current_row: Vec::new(),
             in_table_cell: false,
             current_cell_content: String::new(),
+            links: LinkRenderer::default(),
+            file: FileLinks::default(),
+            diagnostics: Vec::new(),
         }
     }
 
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.diagnostics)
+    }
+
+    /// A link to `href` around `text`. Links to the other files of a multi-file document point
+    /// at their labels.
+    fn render_link(&mut self, href: &str, text: &str) -> String {
+        let Some((label, diagnostic)) = self.file.resolve(href) else {
+            return self.links.render(href, text);
+        };
+        self.diagnostics.extend(diagnostic);
+        format!("#link(label(\"{label}\"))[{text}]")
+    }
+
+    fn report_unsupported(&mut self, tag_name: &str, outcome: &str) {
+        self.diagnostics.push(Diagnostic::warning(
+            DiagnosticCategory::Html,
+            format!("unsupported HTML tag `<{tag_name}>` {outcome}"),
+        ));
+    }
+
     // test only
     #[allow(dead_code)]
     async fn convert_html_to_typst_no_images(&mut self, html: &str) -> String {
@@ -50,13 +83,20 @@ impl HtmlToTypstConverter {
         html: &str,
         image_processor: &ImageProcessor,
         image_files: &mut HashMap<String, Vec<u8>>,
-    ) -> String {
+        links: &mut LinkRenderer,
+        file: &FileLinks,
+    ) -> Result<String> {
         // Parse the HTML
-        let dom = Dom::parse(html).unwrap_or_else(|_| Dom::parse("").unwrap());
+        let dom = Dom::parse(html).map_err(|e| Error::Html(e.to_string()))?;
 
         // Convert to Typst with image processing
-        self.process_nodes(&dom.children, Some(image_processor), Some(image_files))
-            .await
+        self.links = std::mem::take(links);
+        self.file = file.clone();
+        let result = self
+            .process_nodes(&dom.children, Some(image_processor), Some(image_files))
+            .await;
+        *links = std::mem::take(&mut self.links);
+        Ok(result)
     }
 
     async fn process_nodes(
@@ -68,14 +108,12 @@ impl HtmlToTypstConverter {
         let mut result = String::new();
 
         for node in nodes.iter() {
-            let node_result = if image_processor.is_some() && image_files.is_some() {
-                // We know both are Some, so we can safely unwrap
-                let processor = image_processor.unwrap();
-                let files = image_files.as_mut().unwrap();
-                Box::pin(self.process_node(node, Some(processor), Some(files))).await
-            } else {
-                self.process_node_sync(node)
-            };
+            let node_result =
+                if let (Some(processor), Some(files)) = (image_processor, image_files.as_mut()) {
+                    Box::pin(self.process_node(node, Some(processor), Some(files))).await
+                } else {
+                    self.process_node_sync(node)
+                };
 
             result.push_str(&node_result);
         }
@@ -197,14 +235,9 @@ impl HtmlToTypstConverter {
 
             // Links
             "a" => {
-                if let Some(href) = element.attributes.get("href") {
-                    if let Some(href_str) = href {
-                        result.push_str(&format!("#link(\"{href_str}\")["));
-                        result.push_str(&self.process_children(element));
-                        result.push(']');
-                    } else {
-                        result.push_str(&self.process_children(element));
-                    }
+                if let Some(Some(href_str)) = element.attributes.get("href") {
+                    let text = self.process_children(element);
+                    result.push_str(&self.render_link(href_str, &text));
                 } else {
                     result.push_str(&self.process_children(element));
                 }
@@ -222,7 +255,7 @@ impl HtmlToTypstConverter {
                         .map_or("", |v| v);
                     result.push_str(&format!(
                         "#image(\"{}\", alt: \"{}\")",
-                        src_str,
+                        self.file.image_path(src_str),
                         escape_text(alt)
                     ));
                 }
@@ -344,7 +377,8 @@ impl HtmlToTypstConverter {
             }
 
             // Generic containers (div, span, etc.)
-            "div" | "span" | "section" | "article" | "header" | "footer" | "nav" | "aside" => {
+            "div" | "span" | "section" | "article" | "header" | "footer" | "nav" | "aside"
+            | "thead" | "tbody" | "tfoot" => {
                 if element.name == "div" {
                     // div should add newlines like paragraphs
                     if !self.in_code_block && !self.in_pre_block {
@@ -363,11 +397,13 @@ impl HtmlToTypstConverter {
             // Strip these elements completely
             "script" | "style" | "noscript" | "iframe" | "object" | "embed" | "video" | "audio"
             | "canvas" => {
-                // Do nothing - strip these elements completely
+                // Strip these elements completely
+                self.report_unsupported(&tag_name, "was removed");
             }
 
             // Convert to plain text for other unsupported elements
             _ => {
+                self.report_unsupported(&tag_name, "was replaced with its text");
                 result.push_str(&self.process_children(element));
             }
         }
@@ -500,22 +536,11 @@ impl HtmlToTypstConverter {
 
             // Links
             "a" => {
-                if let Some(href) = element.attributes.get("href") {
-                    if let Some(href_str) = href {
-                        result.push_str(&format!("#link(\"{href_str}\")["));
-                        result.push_str(
-                            &self
-                                .process_children_async(element, image_processor, image_files)
-                                .await,
-                        );
-                        result.push(']');
-                    } else {
-                        result.push_str(
-                            &self
-                                .process_children_async(element, image_processor, image_files)
-                                .await,
-                        );
-                    }
+                if let Some(Some(href_str)) = element.attributes.get("href") {
+                    let text = self
+                        .process_children_async(element, image_processor, image_files)
+                        .await;
+                    result.push_str(&self.render_link(href_str, &text));
                 } else {
                     result.push_str(
                         &self
@@ -535,38 +560,14 @@ impl HtmlToTypstConverter {
                         .get("alt")
                         .and_then(|alt_opt| alt_opt.as_ref())
                         .map_or("", |v| v);
+                    let src_str = &self.file.image_path(src_str);
 
                     if let (Some(processor), Some(files)) = (image_processor, image_files) {
-                        // Process the image using the ImageProcessor
-                        match processor.process_image_url(src_str).await {
-                            Ok(image_data) => {
-                                // Convert to Typst format and add to image files
-                                match processor.convert_to_typst_format(&image_data, alt).await {
-                                    Ok((typst_image_code, converted_png_data)) => {
-                                        // Extract filename from the generated code
-                                        if let Some(filename) = typst_image_code.split('"').nth(1) {
-                                            let _png_data_len = converted_png_data.len();
-                                            files.insert(filename.to_string(), converted_png_data);
-                                            result.push_str(&typst_image_code);
-                                        } else {
-                                            // Fallback if parsing fails
-                                            result.push_str(&format!(
-                                                "#emph[Image: {}]",
-                                                escape_text(alt)
-                                            ));
-                                        }
-                                    }
-                                    Err(_e) => {
-                                        // Fallback on conversion error
-                                        result.push_str(&format!(
-                                            "#emph[Image: {}]",
-                                            escape_text(alt)
-                                        ));
-                                    }
-                                }
-                            }
-                            Err(_e) => {
-                                // Fallback on processing error
+                        match processor.embed_image(src_str, alt, files).await {
+                            Ok(typst_image_code) => result.push_str(&typst_image_code),
+                            Err(diagnostic) => {
+                                // Fall back to the alt text
+                                self.diagnostics.push(diagnostic);
                                 result.push_str(&format!("#emph[Image: {}]", escape_text(alt)));
                             }
                         }
@@ -634,12 +635,8 @@ impl HtmlToTypstConverter {
 
             // Blockquotes
             "blockquote" => {
-                let attribution = if let Some(cite) = element.attributes.get("cite") {
-                    if let Some(cite_str) = cite {
-                        format!("(attribution: \"{cite_str}\")")
-                    } else {
-                        String::new()
-                    }
+                let attribution = if let Some(Some(cite_str)) = element.attributes.get("cite") {
+                    format!("(attribution: \"{cite_str}\")")
                 } else {
                     String::new()
                 };
@@ -762,7 +759,8 @@ impl HtmlToTypstConverter {
             }
 
             // Container elements
-            "div" | "span" | "section" | "article" | "header" | "footer" | "nav" | "aside" => {
+            "div" | "span" | "section" | "article" | "header" | "footer" | "nav" | "aside"
+            | "thead" | "tbody" | "tfoot" => {
                 if element.name == "div" {
                     // div should add newlines like paragraphs
                     if !self.in_code_block && !self.in_pre_block {
@@ -789,11 +787,13 @@ impl HtmlToTypstConverter {
             // Strip these elements completely
             "script" | "style" | "noscript" | "iframe" | "object" | "embed" | "video" | "audio"
             | "canvas" => {
-                // Do nothing - strip these elements completely
+                // Strip these elements completely
+                self.report_unsupported(&tag_name, "was removed");
             }
 
             // Convert to plain text for other unsupported elements
             _ => {
+                self.report_unsupported(&tag_name, "was replaced with its text");
                 result.push_str(
                     &self
                         .process_children_async(element, image_processor, image_files)
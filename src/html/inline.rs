@@ -1,8 +1,10 @@
 use html_escape::decode_html_entities;
 use std::collections::HashMap;
 
+use crate::diagnostics::{Diagnostic, DiagnosticCategory};
 use crate::escape_text;
 use crate::utils::images::ImageProcessor;
+use crate::utils::links::{FileLinks, LinkRenderer};
 
 // HTML tag tracking structures
 #[derive(Debug, Clone)]
@@ -13,15 +15,33 @@ pub struct OpenTag {
 
 pub struct HtmlTagTracker {
     open_tags: Vec<OpenTag>,
+    // Problems found since the last `take_diagnostics`, without source positions
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl HtmlTagTracker {
     pub fn new() -> Self {
         Self {
             open_tags: Vec::new(),
+            diagnostics: Vec::new(),
         }
     }
 
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.diagnostics)
+    }
+
+    fn unsupported_tag(&mut self, tag_name: &str) -> String {
+        // Comments and doctypes aren't tags
+        if !tag_name.starts_with(['!', '?']) {
+            self.diagnostics.push(Diagnostic::warning(
+                DiagnosticCategory::Html,
+                format!("unsupported HTML tag `<{tag_name}>` was ignored"),
+            ));
+        }
+        String::new()
+    }
+
     pub fn open_tag(&mut self, tag_name: String, typst_close: String) {
         self.open_tags.push(OpenTag {
             tag_name,
@@ -80,6 +100,8 @@ impl HtmlTagTracker {
         html: &str,
         image_processor: &ImageProcessor,
         image_files: &mut HashMap<String, Vec<u8>>,
+        links: &mut LinkRenderer,
+        file: &FileLinks,
     ) -> String {
         let html = html.trim();
 
@@ -149,9 +171,9 @@ impl HtmlTagTracker {
                     let value_part = &attr_part[equal_pos + 1..];
 
                     // Handle quoted and unquoted values
-                    let value = if value_part.starts_with('"') && value_part.ends_with('"') {
-                        &value_part[1..value_part.len() - 1]
-                    } else if value_part.starts_with('\'') && value_part.ends_with('\'') {
+                    let value = if (value_part.starts_with('"') && value_part.ends_with('"'))
+                        || (value_part.starts_with('\'') && value_part.ends_with('\''))
+                    {
                         &value_part[1..value_part.len() - 1]
                     } else {
                         value_part
@@ -202,8 +224,14 @@ impl HtmlTagTracker {
                 "code" => "`".to_string(),
                 "a" => {
                     if let Some(href) = attributes.get("href") {
-                        let typst_open = format!("#link(\"{href}\")[");
-                        let typst_close = "]".to_string();
+                        // Links to the other files of a multi-file document point at their labels
+                        let (typst_open, typst_close) = match file.resolve(href) {
+                            Some((label, diagnostic)) => {
+                                self.diagnostics.extend(diagnostic);
+                                (format!("#link(label(\"{label}\"))["), "]".to_string())
+                            }
+                            None => (links.open(href), links.close(href)),
+                        };
                         self.open_tag(tag_name, typst_close);
                         typst_open
                     } else {
@@ -214,35 +242,13 @@ impl HtmlTagTracker {
                     if let Some(src) = attributes.get("src") {
                         let empty_alt = String::new();
                         let alt = attributes.get("alt").unwrap_or(&empty_alt);
+                        let src = &file.image_path(src);
 
-                        // Process the image using the ImageProcessor
-                        match image_processor.process_image_url(src).await {
-                            Ok(image_data) => {
-                                // Convert to Typst format and add to image files
-                                match image_processor
-                                    .convert_to_typst_format(&image_data, alt)
-                                    .await
-                                {
-                                    Ok((typst_image_code, converted_png_data)) => {
-                                        // Extract filename from the generated code
-                                        if let Some(filename) = typst_image_code.split('"').nth(1) {
-                                            let _png_data_len = converted_png_data.len();
-                                            image_files
-                                                .insert(filename.to_string(), converted_png_data);
-                                            typst_image_code
-                                        } else {
-                                            // Fallback if parsing fails
-                                            format!("#emph[Image: {}]", escape_text(alt))
-                                        }
-                                    }
-                                    Err(_e) => {
-                                        // Fallback on conversion error
-                                        format!("#emph[Image: {}]", escape_text(alt))
-                                    }
-                                }
-                            }
-                            Err(_e) => {
-                                // Fallback on processing error
+                        match image_processor.embed_image(src, alt, image_files).await {
+                            Ok(typst_image_code) => typst_image_code,
+                            Err(diagnostic) => {
+                                // Fall back to the alt text
+                                self.diagnostics.push(diagnostic);
                                 format!("#emph[Image: {}]", escape_text(alt))
                             }
                         }
@@ -281,8 +287,11 @@ impl HtmlTagTracker {
                     self.open_tag(tag_name, typst_close);
                     typst_open
                 }
-                "video" | "audio" | "canvas" | "script" | "style" => String::new(), // Strip these tags
-                _ => String::new(),
+                "video" | "audio" | "canvas" | "script" | "style" => {
+                    // Strip these tags
+                    self.unsupported_tag(&tag_name)
+                }
+                _ => self.unsupported_tag(&tag_name),
             }
         }
         // Handle closing tags
@@ -374,9 +383,9 @@ impl HtmlTagTracker {
                     let value_part = &attr_part[equal_pos + 1..];
 
                     // Handle quoted and unquoted values
-                    let value = if value_part.starts_with('"') && value_part.ends_with('"') {
-                        &value_part[1..value_part.len() - 1]
-                    } else if value_part.starts_with('\'') && value_part.ends_with('\'') {
+                    let value = if (value_part.starts_with('"') && value_part.ends_with('"'))
+                        || (value_part.starts_with('\'') && value_part.ends_with('\''))
+                    {
                         &value_part[1..value_part.len() - 1]
                     } else {
                         value_part
@@ -395,35 +404,13 @@ impl HtmlTagTracker {
                     if let Some(src) = attributes.get("src") {
                         let empty_alt = String::new();
                         let alt = attributes.get("alt").unwrap_or(&empty_alt);
+                        let src = &file.image_path(src);
 
-                        // Process the image using the ImageProcessor
-                        match image_processor.process_image_url(src).await {
-                            Ok(image_data) => {
-                                // Convert to Typst format and add to image files
-                                match image_processor
-                                    .convert_to_typst_format(&image_data, alt)
-                                    .await
-                                {
-                                    Ok((typst_image_code, converted_png_data)) => {
-                                        // Extract filename from the generated code
-                                        if let Some(filename) = typst_image_code.split('"').nth(1) {
-                                            let _png_data_len = converted_png_data.len();
-                                            image_files
-                                                .insert(filename.to_string(), converted_png_data);
-                                            typst_image_code
-                                        } else {
-                                            // Fallback if parsing fails
-                                            format!("#emph[Image: {}]", escape_text(alt))
-                                        }
-                                    }
-                                    Err(_e) => {
-                                        // Fallback on conversion error
-                                        format!("#emph[Image: {}]", escape_text(alt))
-                                    }
-                                }
-                            }
-                            Err(_e) => {
-                                // Fallback on processing error
+                        match image_processor.embed_image(src, alt, image_files).await {
+                            Ok(typst_image_code) => typst_image_code,
+                            Err(diagnostic) => {
+                                // Fall back to the alt text
+                                self.diagnostics.push(diagnostic);
                                 format!("#emph[Image: {}]", escape_text(alt))
                             }
                         }
@@ -431,7 +418,7 @@ impl HtmlTagTracker {
                         String::new()
                     }
                 }
-                _ => String::new(),
+                _ => self.unsupported_tag(&tag_name),
             }
         } else {
             String::new()
@@ -9,64 +9,332 @@
 //
 // This should be refactored to first do a Markdown -> HTML conversion with pulldown_cmark, then do HTML -> Typst conversion in the html_parser loop (also it would maybe make sense to move to html5ever instead).
 
-use anyhow::Result;
 use html_escape::decode_html_entities;
 
-#[cfg(all(not(feature = "fuzz"), feature = "node"))]
-use napi::Error as NapiError;
 #[cfg(all(not(feature = "fuzz"), feature = "node"))]
 use napi_derive::napi;
 use pulldown_cmark::{
     Alignment, BlockQuoteKind, CodeBlockKind, Event, HeadingLevel, Options, Parser, Tag, TagEnd,
 };
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
 use tokio::runtime::Runtime;
 
 pub mod config;
+pub mod diagnostics;
+pub mod error;
 mod html;
+mod math;
 mod typst;
 mod utils;
 
-use crate::config::MdpdfConfig;
+use crate::config::{MdpdfConfig, TocPlacement, apply_front_matter};
+use crate::diagnostics::{Diagnostic, DiagnosticCategory, LineIndex, SourceMap};
+pub use crate::error::{Error, ImageError, Result};
 use crate::html::{block::HtmlToTypstConverter, inline::HtmlTagTracker};
-use crate::typst::TypstCompiler;
+use crate::math::tex::tex_to_typst;
+pub use crate::typst::LoadedFonts;
+use crate::typst::{MdpdfWorld, TypstCompiler};
 use crate::utils::images::ImageProcessor;
+use crate::utils::links::{FileLinks, LinkRenderer, LinkTarget, missing_heading, normalize_path};
 
 const MAX_LIST_NESTING_LEVEL: usize = 15;
 const MAX_BLOCKQUOTE_NESTING_LEVEL: usize = 8;
 
+/// Errors thrown to JavaScript, with `code` set to [`Error::code`] (e.g. `MDPDF_COMPILE`).
+#[cfg(all(not(feature = "fuzz"), feature = "node"))]
+type NapiError = napi::Error<&'static str>;
+
+#[cfg(all(not(feature = "fuzz"), feature = "node"))]
+fn to_napi_error(error: Error) -> NapiError {
+    napi::Error::new(error.code(), error.to_string())
+}
+
+/// A diagnostic as seen from JavaScript.
+#[cfg(all(not(feature = "fuzz"), feature = "node"))]
+#[napi(object)]
+pub struct ConversionWarning {
+    pub severity: String,
+    pub category: String,
+    pub message: String,
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+}
+
+#[cfg(all(not(feature = "fuzz"), feature = "node"))]
+impl From<Diagnostic> for ConversionWarning {
+    fn from(diagnostic: Diagnostic) -> Self {
+        Self {
+            severity: diagnostic.severity.to_string(),
+            category: diagnostic.category.to_string(),
+            message: diagnostic.message,
+            line: diagnostic.line.map(|line| line as u32),
+            column: diagnostic.column.map(|column| column as u32),
+        }
+    }
+}
+
+#[cfg(all(not(feature = "fuzz"), feature = "node"))]
+#[napi(object)]
+pub struct PdfWithWarnings {
+    pub pdf: napi::bindgen_prelude::Buffer,
+    pub warnings: Vec<ConversionWarning>,
+}
+
+/// Options for the conversion functions in JavaScript.
+#[cfg(all(not(feature = "fuzz"), feature = "node"))]
+#[napi(object)]
+#[derive(Default)]
+pub struct ConversionOptions {
+    /// "default", "github", "academic", "compact", or the path to a theme file
+    pub theme: Option<String>,
+}
+
+#[cfg(all(not(feature = "fuzz"), feature = "node"))]
+impl From<ConversionOptions> for MdpdfConfig {
+    fn from(options: ConversionOptions) -> Self {
+        MdpdfConfig {
+            theme: options.theme,
+            ..MdpdfConfig::default()
+        }
+    }
+}
+
+#[cfg(all(not(feature = "fuzz"), feature = "node"))]
+#[napi]
+pub async fn markdown_to_pdf(
+    markdown: String,
+    options: Option<ConversionOptions>,
+) -> Result<napi::bindgen_prelude::Buffer, NapiError> {
+    let result = markdown_to_pdf_with_warnings(markdown, options).await?;
+    Ok(result.pdf)
+}
+
 #[cfg(all(not(feature = "fuzz"), feature = "node"))]
 #[napi]
-pub async fn markdown_to_pdf(markdown: String) -> Result<napi::bindgen_prelude::Buffer, NapiError> {
-    let config = MdpdfConfig::default();
-    let (typst_code, image_files) = markdown_to_typst_async(&markdown, &config)
+pub async fn markdown_to_pdf_with_warnings(
+    markdown: String,
+    options: Option<ConversionOptions>,
+) -> Result<PdfWithWarnings, NapiError> {
+    let config = MdpdfConfig::from(options.unwrap_or_default());
+    let output = convert_document(&markdown, &config)
         .await
-        .map_err(|e| NapiError::from_reason(e))?;
-    let pdf_bytes =
-        typst_to_pdf(&typst_code, &config, image_files).map_err(|e| NapiError::from_reason(e))?;
-    Ok(napi::bindgen_prelude::Buffer::from(pdf_bytes))
+        .map_err(to_napi_error)?;
+    let pdf = typst_to_pdf_with_source_map(
+        &output.typst_code,
+        &output.config,
+        output.image_files,
+        &output.source_map,
+    )
+    .map_err(to_napi_error)?;
+    let warnings = output
+        .diagnostics
+        .into_iter()
+        .chain(pdf.diagnostics)
+        .map(ConversionWarning::from)
+        .collect();
+    Ok(PdfWithWarnings {
+        pdf: napi::bindgen_prelude::Buffer::from(pdf.pdf_bytes),
+        warnings,
+    })
 }
 
 #[cfg(all(not(feature = "fuzz"), feature = "node"))]
 #[napi]
-pub async fn markdown_to_typst_code(markdown: String) -> Result<String, NapiError> {
-    let config = MdpdfConfig::default();
+pub async fn markdown_to_typst_code(
+    markdown: String,
+    options: Option<ConversionOptions>,
+) -> Result<String, NapiError> {
+    let config = MdpdfConfig::from(options.unwrap_or_default());
     // TODO: disable image URL rewriting
-    let (typst_code, _image_files) = markdown_to_typst_async(&markdown, &config)
+    let output = convert_document(&markdown, &config)
         .await
-        .map_err(|e| NapiError::from_reason(e))?;
-    let template = TypstCompiler::create_document_template(&config);
-    let full_document = format!("\n{template}\n\n{typst_code}\n");
+        .map_err(to_napi_error)?;
+    let (full_document, _) = TypstCompiler::create_document(&output.config, &output.typst_code)
+        .map_err(to_napi_error)?;
     Ok(full_document)
 }
 
-async fn markdown_to_typst_async(
+/// Typst code generated from a Markdown document.
+#[derive(Debug)]
+pub struct TypstOutput {
+    pub typst_code: String,
+    /// Images referenced by `typst_code`, by file name
+    pub image_files: HashMap<String, Vec<u8>>,
+    /// Problems found in the Markdown, in document order
+    pub diagnostics: Vec<Diagnostic>,
+    /// Where each part of `typst_code` came from in the Markdown
+    pub source_map: SourceMap,
+    /// The configuration with the document's front matter merged in. Pass this to
+    /// [`typst_to_pdf`] so that settings like the title and page size take effect.
+    pub config: MdpdfConfig,
+    /// Local image files the document uses (including missing ones), e.g. to watch them for
+    /// changes
+    pub local_files: Vec<PathBuf>,
+}
+
+/// A compiled PDF.
+#[derive(Debug)]
+pub struct PdfOutput {
+    pub pdf_bytes: Vec<u8>,
+    /// Warnings from the Typst compiler
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Settings that win over the front matter, e.g. from command-line flags.
+pub type ConfigOverrides<'a> = &'a dyn Fn(&mut MdpdfConfig);
+
+async fn convert_document(markdown: &str, config: &MdpdfConfig) -> Result<TypstOutput> {
+    convert_document_with(markdown, config, &|_| {}).await
+}
+
+async fn convert_document_with(
+    markdown: &str,
+    config: &MdpdfConfig,
+    overrides: ConfigOverrides<'_>,
+) -> Result<TypstOutput> {
+    let (markdown, mut config, mut diagnostics) = apply_front_matter(markdown, config);
+    overrides(&mut config);
+    let mut links = LinkRenderer::new(config.link_style.unwrap_or_default());
+    let mut source_map = SourceMap::new(&markdown);
+    let image_processor = ImageProcessor::from_config(&config);
+    let (mut typst_code, image_files) = convert_markdown(
+        &markdown,
+        &config,
+        &image_processor,
+        &mut links,
+        &mut diagnostics,
+        &mut source_map,
+        &FileLinks::default(),
+    )
+    .await?;
+    typst_code.push_str(&links.finish());
+    Ok(TypstOutput {
+        typst_code,
+        image_files,
+        diagnostics,
+        source_map,
+        config,
+        local_files: image_processor.local_files(),
+    })
+}
+
+async fn convert_files(
+    files: &[MarkdownFile],
+    config: &MdpdfConfig,
+    overrides: ConfigOverrides<'_>,
+) -> Result<TypstOutput> {
+    let mut diagnostics = Vec::new();
+    let mut documents = Vec::new();
+    for file in files {
+        let (markdown, mut file_config, file_diagnostics) =
+            apply_front_matter(&file.markdown, config);
+        overrides(&mut file_config);
+        diagnostics.extend(
+            file_diagnostics
+                .into_iter()
+                .map(|diagnostic| diagnostic.in_file(&file.path)),
+        );
+        documents.push((file, markdown, file_config));
+    }
+    // The first file's front matter (usually the title page) sets up the document
+    let config = documents
+        .first()
+        .map_or_else(|| config.clone(), |(_, _, config)| config.clone());
+
+    // Links can point at headings in any of the files, so index them all first
+    let mut targets = HashMap::new();
+    let mut has_toc_marker = Vec::new();
+    for (index, (file, markdown, _)) in documents.iter().enumerate() {
+        let document_index = DocumentIndex::new(markdown);
+        has_toc_marker.push(document_index.has_toc_marker);
+        targets.insert(
+            normalize_path(&file.path),
+            LinkTarget::new(index, document_index.used_slugs),
+        );
+    }
+    let targets = Arc::new(targets);
+
+    let mut typst_code = String::new();
+    let mut image_files = HashMap::new();
+    let mut local_files = Vec::new();
+    let mut source_map = SourceMap::default();
+    let mut links = LinkRenderer::new(config.link_style.unwrap_or_default());
+    // Like in a single file, the table of contents goes at the first marker or at the top
+    let toc_at_marker = config
+        .toc
+        .as_ref()
+        .filter(|toc| toc.placement == TocPlacement::Marker);
+    let first_marker = has_toc_marker.iter().position(|&has_marker| has_marker);
+    if let Some(toc) = toc_at_marker
+        && first_marker.is_none()
+    {
+        typst_code.push_str(&TypstCompiler::outline(toc));
+    }
+
+    for (index, (file, markdown, file_config)) in documents.iter().enumerate() {
+        if index > 0 && config.page_break_between_files {
+            typst_code.push_str("\n#pagebreak(weak: true)\n");
+        }
+        let file_links = FileLinks::new(&file.path, targets.clone());
+        if let Some(label) = file_links.file_label() {
+            // Something for links to the whole file to point at
+            typst_code.push_str(&format!("\n#metadata(none) <{label}>\n"));
+        }
+        let file_config = MdpdfConfig {
+            toc: toc_at_marker
+                .filter(|_| first_marker == Some(index))
+                .cloned(),
+            ..file_config.clone()
+        };
+        let mut file_diagnostics = Vec::new();
+        let mut file_source_map = SourceMap::new(markdown);
+        let image_processor = ImageProcessor::from_config(&file_config);
+        let (file_code, file_images) = convert_markdown(
+            markdown,
+            &file_config,
+            &image_processor,
+            &mut links,
+            &mut file_diagnostics,
+            &mut file_source_map,
+            &file_links,
+        )
+        .await?;
+        source_map.append(&file.path, file_source_map, typst_code.len());
+        typst_code.push_str(&file_code);
+        image_files.extend(file_images);
+        local_files.extend(image_processor.local_files());
+        diagnostics.extend(
+            file_diagnostics
+                .into_iter()
+                .map(|diagnostic| diagnostic.in_file(&file.path)),
+        );
+    }
+    typst_code.push_str(&links.finish());
+    Ok(TypstOutput {
+        typst_code,
+        image_files,
+        diagnostics,
+        source_map,
+        config,
+        local_files,
+    })
+}
+
+async fn convert_markdown(
     markdown: &str,
     config: &MdpdfConfig,
-) -> Result<(String, HashMap<String, Vec<u8>>), String> {
+    image_processor: &ImageProcessor,
+    links: &mut LinkRenderer,
+    diagnostics: &mut Vec<Diagnostic>,
+    source_map: &mut SourceMap,
+    file: &FileLinks,
+) -> Result<(String, HashMap<String, Vec<u8>>)> {
     let mut typst_code = String::new();
 
     let mut in_code_block = false;
+    let mut in_math_block = false;
     let mut current_code_block = String::new();
 
     let mut in_link = false;
@@ -77,6 +345,8 @@ async fn markdown_to_typst_async(
     let mut current_heading_text = String::new();
 
     let mut in_image = false;
+    // Images in the alt text of an image
+    let mut nested_images: usize = 0;
     let mut current_image_alt = String::new();
     let mut current_image_src = String::new();
 
@@ -103,31 +373,67 @@ async fn markdown_to_typst_async(
 
     let mut in_list_item = false;
 
-    let image_processor = ImageProcessor::new(
-        config
-            .image_handling
-            .as_ref()
-            .and_then(|c| c.cache_directory_path()),
-    );
-
     let mut image_files = HashMap::new();
 
     // Initialize HTML tag tracker for inline HTML processing
     let mut html_tag_tracker = HtmlTagTracker::new();
 
-    let parser = Parser::new_ext(
-        markdown,
-        Options::ENABLE_TABLES
-            | Options::ENABLE_STRIKETHROUGH
-            | Options::ENABLE_DEFINITION_LIST
-            | Options::ENABLE_MATH
-            | Options::ENABLE_SUPERSCRIPT // TODO not working - use <sup></sup> and <sub></sub> instead
-            | Options::ENABLE_GFM
-            | Options::ENABLE_TASKLISTS,
-        // | Options::ENABLE_FOOTNOTES
-    );
+    let DocumentIndex {
+        footnote_sources,
+        heading_slugs,
+        used_slugs,
+        has_toc_marker,
+    } = DocumentIndex::new(markdown);
+    let line_index = LineIndex::new(markdown);
+    let mut in_footnote_definition = false;
+    let mut heading_index = 0;
+    // Footnotes that have already been emitted, with the number used in their label
+    let mut footnote_numbers: HashMap<String, usize> = HashMap::new();
+
+    // The table of contents goes in place of `[[toc]]`/`<!-- toc -->` markers if configured, or
+    // at the top if there aren't any
+    let toc_at_marker = config
+        .toc
+        .as_ref()
+        .filter(|toc| toc.placement == TocPlacement::Marker);
+    if let Some(toc) = toc_at_marker
+        && !has_toc_marker
+    {
+        typst_code.push_str(&TypstCompiler::outline(toc));
+    }
+    let mut in_toc_marker = false;
+
+    let parser = Parser::new_ext(markdown, parser_options());
+
+    for (event, range) in parser.into_offset_iter() {
+        if in_footnote_definition {
+            if let Event::End(TagEnd::FootnoteDefinition) = event {
+                in_footnote_definition = false;
+            }
+            continue;
+        }
+
+        if in_toc_marker {
+            if let Event::End(TagEnd::Paragraph | TagEnd::HtmlBlock) = event {
+                in_toc_marker = false;
+            }
+            continue;
+        }
+        if let Event::Start(Tag::Paragraph | Tag::HtmlBlock) = event
+            && is_toc_marker(&markdown[range.start..range.end])
+        {
+            // Markers are dropped if the table of contents goes elsewhere
+            in_toc_marker = true;
+            if let Some(toc) = toc_at_marker {
+                source_map.record(typst_code.len(), range.start);
+                typst_code.push_str(&format!("\n{}", TypstCompiler::outline(toc)));
+            }
+            continue;
+        }
+
+        let position = line_index.position(range.start);
+        source_map.record(typst_code.len(), range.start);
 
-    for event in parser {
         // println!("event: {:?}", event);
         // Get the current output buffer based on context
         let current_output: &mut String = if in_code_block {
@@ -144,6 +450,30 @@ async fn markdown_to_typst_async(
             &mut typst_code
         };
 
+        // Alt text is plain text, so only the text inside images is kept
+        if in_image {
+            match &event {
+                Event::End(TagEnd::Image) if nested_images == 0 => {}
+                Event::End(TagEnd::Image) => {
+                    nested_images -= 1;
+                    continue;
+                }
+                Event::Start(Tag::Image { .. }) => {
+                    nested_images += 1;
+                    continue;
+                }
+                Event::Text(text) | Event::Code(text) => {
+                    current_image_alt.push_str(text);
+                    continue;
+                }
+                Event::SoftBreak | Event::HardBreak => {
+                    current_image_alt.push(' ');
+                    continue;
+                }
+                _ => continue,
+            }
+        }
+
         match event {
             Event::Start(Tag::Heading { level, .. }) => {
                 in_heading = true;
@@ -164,11 +494,16 @@ async fn markdown_to_typst_async(
                 if !closed_tags.is_empty() {
                     current_output.push_str(&closed_tags);
                 }
-                let kebab_case = to_kebab_case(&current_heading_text);
-                if kebab_case.is_empty() {
+                let slug = heading_slugs
+                    .get(heading_index)
+                    .cloned()
+                    .unwrap_or_default();
+                heading_index += 1;
+                if slug.is_empty() {
                     typst_code.push_str(&format!("{current_heading_text}\n"));
                 } else {
-                    typst_code.push_str(&format!("{current_heading_text} <{kebab_case}>\n"));
+                    let label = file.label(&slug);
+                    typst_code.push_str(&format!("{current_heading_text} <{label}>\n"));
                 }
                 current_heading_text.clear();
             }
@@ -221,8 +556,11 @@ async fn markdown_to_typst_async(
                     CodeBlockKind::Fenced(lang) => lang.to_string(),
                     CodeBlockKind::Indented => String::new(),
                 };
-                // Start code block with syntax highlighting if language is specified
-                if !language.is_empty() {
+                // Start code block with syntax highlighting if language is specified.
+                // ```math blocks are translated at the end of the block instead.
+                if language == "math" {
+                    in_math_block = true;
+                } else if !language.is_empty() {
                     typst_code.push_str(&format!("```{language}\n"));
                 } else {
                     typst_code.push_str("```\n");
@@ -235,6 +573,15 @@ async fn markdown_to_typst_async(
                 if !closed_tags.is_empty() {
                     typst_code.push_str(&closed_tags);
                 }
+                if in_math_block {
+                    in_math_block = false;
+                    if let Some(typst_math) = tex_to_typst(&current_code_block) {
+                        typst_code.push_str(&format!("\n$ {typst_math} $\n"));
+                        continue;
+                    }
+                    diagnostics.push(untranslated_math().at(position.0, position.1));
+                    typst_code.push_str("```math\n");
+                }
                 // Add the collected code content
                 typst_code.push_str(&current_code_block.replace("```", "\\`\\`\\`"));
                 if current_code_block.ends_with("`") {
@@ -249,15 +596,27 @@ async fn markdown_to_typst_async(
             }
             Event::End(TagEnd::Link) => {
                 in_link = false;
-                // TODO: do first pass where we inject some kind of tracking string, then come back later and replace those with a `label()` if the corresponding header exists. other replace with original #url
-                // if current_link_url.starts_with("#") {
-                //     typst_code.push_str(&format!(
-                //         "#link(label(\"{}\"))[{}]", current_link_url.replace("#", ""), current_link_text
-                //     ))
-                // } else {
-                let result = &format!(
-                    "#link(\"{current_link_url}\")[{current_link_text}] (`{current_link_url}`)"
-                );
+                let result = &if let Some(fragment) = current_link_url.strip_prefix('#') {
+                    // Links within the document point at the label of the matching heading
+                    if used_slugs.contains(fragment) {
+                        let label = file.label(fragment);
+                        format!("#link(label(\"{label}\"))[{current_link_text}]")
+                    } else if used_slugs.contains(&fragment.to_lowercase()) {
+                        let label = file.label(&fragment.to_lowercase());
+                        format!("#link(label(\"{label}\"))[{current_link_text}]")
+                    } else {
+                        diagnostics
+                            .push(missing_heading(&current_link_url).at(position.0, position.1));
+                        current_link_text.clone()
+                    }
+                } else if let Some((label, diagnostic)) = file.resolve(&current_link_url) {
+                    // Links to the other files of a multi-file document
+                    diagnostics
+                        .extend(diagnostic.map(|diagnostic| diagnostic.at(position.0, position.1)));
+                    format!("#link(label(\"{label}\"))[{current_link_text}]")
+                } else {
+                    links.render(&current_link_url, &current_link_text)
+                };
                 // Get the current output buffer based on context
                 let current_output: &mut String = if in_code_block {
                     &mut current_code_block
@@ -275,7 +634,7 @@ async fn markdown_to_typst_async(
             Event::Start(Tag::Image { dest_url, .. }) => {
                 in_image = true;
                 current_image_alt.clear();
-                current_image_src = dest_url.to_string();
+                current_image_src = file.image_path(&dest_url);
             }
             Event::End(TagEnd::Image) => {
                 in_image = false;
@@ -284,55 +643,31 @@ async fn markdown_to_typst_async(
                 let image_alt = current_image_alt.clone();
                 let image_src = current_image_src.clone();
 
-                // Process the image
-                let result: Result<String, String> =
-                    match image_processor.process_image_url(&image_src).await {
-                        Ok(image_data) => {
-                            // Convert to Typst format and add to image files
-                            match image_processor
-                                .convert_to_typst_format(&image_data, &image_alt)
-                                .await
-                            {
-                                Ok((typst_image_code, converted_png_data)) => {
-                                    // Extract filename from the generated code
-                                    if let Some(filename) = typst_image_code.split('"').nth(1) {
-                                        let _png_data_len = converted_png_data.len();
-                                        image_files
-                                            .insert(filename.to_string(), converted_png_data);
-                                        Ok(typst_image_code)
-                                    } else {
-                                        // Fallback if parsing fails
-                                        Ok(format!("#emph[Image: {}]", escape_text(&image_alt)))
-                                    }
-                                }
-                                Err(_e) => {
-                                    // Fallback on conversion error
-                                    Ok(format!("#emph[Image: {}]", escape_text(&image_alt)))
-                                }
-                            }
-                        }
-                        Err(_e) => {
-                            // Fallback on processing error
-                            Ok(format!("#emph[Image: {}]", escape_text(&image_alt)))
-                        }
-                    };
+                let output = match image_processor
+                    .embed_image(&image_src, &image_alt, &mut image_files)
+                    .await
+                {
+                    Ok(typst_image_code) => typst_image_code,
+                    Err(diagnostic) => {
+                        // Fall back to the alt text
+                        diagnostics.push(diagnostic.at(position.0, position.1));
+                        format!("#emph[Image: {}]", escape_text(&image_alt))
+                    }
+                };
 
-                // Now add the result to the output using current_output
-                if let Ok(output) = result {
-                    // Get the current output buffer based on context (but not in_image anymore)
-                    let current_output: &mut String = if in_code_block {
-                        &mut current_code_block
-                    } else if in_link {
-                        &mut current_link_text
-                    } else if in_table_cell {
-                        &mut current_cell_content
-                    } else if in_heading {
-                        &mut current_heading_text
-                    } else {
-                        &mut typst_code
-                    };
-                    current_output.push_str(&output);
-                }
+                // Get the current output buffer based on context (but not in_image anymore)
+                let current_output: &mut String = if in_code_block {
+                    &mut current_code_block
+                } else if in_link {
+                    &mut current_link_text
+                } else if in_table_cell {
+                    &mut current_cell_content
+                } else if in_heading {
+                    &mut current_heading_text
+                } else {
+                    &mut typst_code
+                };
+                current_output.push_str(&output);
             }
             Event::Start(Tag::Item) => {
                 in_list_item = true;
@@ -383,6 +718,17 @@ async fn markdown_to_typst_async(
             }
             Event::Start(Tag::BlockQuote(kind)) => {
                 blockquote_nesting_level += 1;
+                if blockquote_nesting_level == MAX_BLOCKQUOTE_NESTING_LEVEL + 1 {
+                    diagnostics.push(
+                        Diagnostic::warning(
+                            DiagnosticCategory::Nesting,
+                            format!(
+                                "blockquotes nested deeper than {MAX_BLOCKQUOTE_NESTING_LEVEL} levels are flattened"
+                            ),
+                        )
+                        .at(position.0, position.1),
+                    );
+                }
 
                 if blockquote_nesting_level <= MAX_BLOCKQUOTE_NESTING_LEVEL {
                     // Only create #quote[] blocks for the first N levels
@@ -447,6 +793,9 @@ async fn markdown_to_typst_async(
                 // TODO: cursor added this check but i don't think we could ever be in a list inside a code block
                 if !in_code_block {
                     list_types.push(true);
+                    if list_types.len() == MAX_LIST_NESTING_LEVEL + 1 {
+                        diagnostics.push(list_nesting_warning().at(position.0, position.1));
+                    }
                     if list_nesting_level == 0 {
                         typst_code.push('\n');
                     }
@@ -463,6 +812,9 @@ async fn markdown_to_typst_async(
                 // TODO: cursor added this check but i don't think we could ever be in a list inside a code block
                 if !in_code_block {
                     list_types.push(false);
+                    if list_types.len() == MAX_LIST_NESTING_LEVEL + 1 {
+                        diagnostics.push(list_nesting_warning().at(position.0, position.1));
+                    }
                     if list_nesting_level == 0 {
                         typst_code.push('\n');
                     }
@@ -502,8 +854,17 @@ async fn markdown_to_typst_async(
                     // Check if this looks like a complete HTML block
                     if trimmed.starts_with('<') && trimmed.ends_with('>') {
                         // This might be a complete HTML block, try to process it
-                        let result =
-                            convert_html_block(&html_str, &image_processor, &mut image_files).await;
+                        // If the HTML can't be parsed on its own, it's accumulated below
+                        let (result, html_diagnostics) = convert_html_block(
+                            &html_str,
+                            image_processor,
+                            &mut image_files,
+                            links,
+                            file,
+                        )
+                        .await
+                        .unwrap_or_default();
+                        diagnostics.extend(located(html_diagnostics, position));
                         if !result.trim().is_empty() {
                             typst_code.push_str(&result);
                         } else {
@@ -522,12 +883,18 @@ async fn markdown_to_typst_async(
                 // Handle inline HTML elements using the tag tracker
                 current_output.push_str(
                     &html_tag_tracker
-                        .process_html_tag(&html, &image_processor, &mut image_files)
+                        .process_html_tag(&html, image_processor, &mut image_files, links, file)
                         .await,
                 );
+                diagnostics.extend(located(html_tag_tracker.take_diagnostics(), position));
             }
             Event::InlineMath(math) => {
-                // TODO
+                if let Some(typst_math) = tex_to_typst(&math) {
+                    current_output.push_str(&format!("${typst_math}$"));
+                    continue;
+                }
+                diagnostics.push(untranslated_math().at(position.0, position.1));
+                // Fall back to showing the TeX source if we can't translate it
                 let filtered_math = filter_problematic_unicode(&math);
                 let padding = if filtered_math.ends_with("`") {
                     " "
@@ -537,7 +904,12 @@ async fn markdown_to_typst_async(
                 current_output.push_str(&format!(" ``` {filtered_math}{padding}```"));
             }
             Event::DisplayMath(math) => {
-                // TODO
+                if let Some(typst_math) = tex_to_typst(&math) {
+                    current_output.push_str(&format!("\n$ {typst_math} $\n"));
+                    continue;
+                }
+                diagnostics.push(untranslated_math().at(position.0, position.1));
+                // Fall back to showing the TeX source if we can't translate it
                 current_output.push_str(&format!(
                     "```math\n{}```\n",
                     filter_problematic_unicode(&math)
@@ -574,10 +946,17 @@ async fn markdown_to_typst_async(
                 // End of HTML block - process accumulated fragments
                 if accumulating_html && !html_fragments.is_empty() {
                     let combined_html = html_fragments.join("");
-                    current_output.push_str(
-                        &convert_html_block(&combined_html, &image_processor, &mut image_files)
-                            .await,
-                    );
+                    let (result, html_diagnostics) = convert_html_block(
+                        &combined_html,
+                        image_processor,
+                        &mut image_files,
+                        links,
+                        file,
+                    )
+                    .await
+                    .unwrap_or_else(unconverted_html);
+                    current_output.push_str(&result);
+                    diagnostics.extend(located(html_diagnostics, position));
                     html_fragments.clear();
                     accumulating_html = false;
                 }
@@ -597,15 +976,49 @@ async fn markdown_to_typst_async(
                 current_output.push('\n');
             }
             Event::FootnoteReference(fnref) => {
-                current_output.push_str(&format!("^{fnref}"));
-            }
-            Event::Start(Tag::FootnoteDefinition(fndef)) => {
-                current_output.push_str(&format!("^{fndef}"));
+                let key = fnref.to_lowercase();
+                if let Some(number) = footnote_numbers.get(&key) {
+                    // Repeated references point at the footnote we already emitted
+                    let label = file.label(&format!("footnote:{number}"));
+                    current_output.push_str(&format!("#footnote(<{label}>)"));
+                } else if let Some((source, definition_line)) = footnote_sources.get(&key) {
+                    let mut body_diagnostics = Vec::new();
+                    // Footnotes don't get a table of contents of their own
+                    let body_config = MdpdfConfig {
+                        toc: None,
+                        ..config.clone()
+                    };
+                    // The whole footnote maps to its reference, so the body's map isn't needed
+                    let (body, body_images) = Box::pin(convert_markdown(
+                        source,
+                        &body_config,
+                        image_processor,
+                        links,
+                        &mut body_diagnostics,
+                        &mut SourceMap::default(),
+                        file,
+                    ))
+                    .await?;
+                    image_files.extend(body_images);
+                    // Columns in the body don't match the source once the indent is stripped
+                    diagnostics.extend(body_diagnostics.into_iter().map(|diagnostic| Diagnostic {
+                        line: diagnostic.line.map(|line| line + definition_line - 1),
+                        column: None,
+                        ..diagnostic
+                    }));
+                    let number = footnote_numbers.len() + 1;
+                    footnote_numbers.insert(key, number);
+                    let label = file.label(&format!("footnote:{number}"));
+                    current_output.push_str(&format!("#footnote[{}]<{label}>", body.trim()));
+                } else {
+                    current_output.push_str(&format!("^{}", escape_text(&fnref)));
+                }
             }
-            Event::End(TagEnd::FootnoteDefinition) => {
-                // Note: fndef is not available in End event, so we just close the footnote
-                current_output.push('\n');
+            Event::Start(Tag::FootnoteDefinition(_)) => {
+                // Definitions were already converted in the first pass
+                in_footnote_definition = true;
             }
+            Event::End(TagEnd::FootnoteDefinition) => {}
             Event::Start(Tag::Superscript) => {
                 current_output.push_str("\n#super[\n");
             }
@@ -645,9 +1058,7 @@ async fn markdown_to_typst_async(
                             Alignment::Right => "right",
                             Alignment::None => "left", // Default to left for None
                         };
-                        if i == 0 {
-                            alignment_conditions.push(format!("if x == {i} {{ {align_str} }}"));
-                        } else if i == 1 {
+                        if i <= 1 {
                             alignment_conditions.push(format!("if x == {i} {{ {align_str} }}"));
                         } else {
                             alignment_conditions.push(format!("{{ {align_str} }}"));
@@ -729,9 +1140,17 @@ async fn markdown_to_typst_async(
     // Process any remaining accumulated HTML fragments (for cases where HtmlBlock events aren't used)
     if accumulating_html && !html_fragments.is_empty() {
         let combined_html = html_fragments.join("");
-        typst_code.push_str(
-            &convert_html_block(&combined_html, &image_processor, &mut image_files).await,
-        );
+        let (result, html_diagnostics) = convert_html_block(
+            &combined_html,
+            image_processor,
+            &mut image_files,
+            links,
+            file,
+        )
+        .await
+        .unwrap_or_else(unconverted_html);
+        typst_code.push_str(&result);
+        diagnostics.extend(html_diagnostics);
     }
 
     // Close any remaining open HTML tags
@@ -743,6 +1162,169 @@ async fn markdown_to_typst_async(
     Ok((typst_code, image_files))
 }
 
+/// Attach a source position to diagnostics that were reported without one.
+fn located(
+    diagnostics: Vec<Diagnostic>,
+    (line, column): (usize, usize),
+) -> impl Iterator<Item = Diagnostic> {
+    diagnostics
+        .into_iter()
+        .map(move |diagnostic| diagnostic.at(line, column))
+}
+
+/// Drop HTML that couldn't be converted, reporting why.
+fn unconverted_html(error: Error) -> (String, Vec<Diagnostic>) {
+    let diagnostic = Diagnostic::warning(
+        DiagnosticCategory::Html,
+        format!("{error}, the HTML was left out"),
+    );
+    (String::new(), vec![diagnostic])
+}
+
+fn untranslated_math() -> Diagnostic {
+    Diagnostic::warning(
+        DiagnosticCategory::Math,
+        "couldn't translate TeX math to Typst, showing the TeX source instead",
+    )
+}
+
+fn list_nesting_warning() -> Diagnostic {
+    Diagnostic::warning(
+        DiagnosticCategory::Nesting,
+        format!("lists nested deeper than {MAX_LIST_NESTING_LEVEL} levels are flattened"),
+    )
+}
+
+/// Whether a block is a `[[toc]]` or `<!-- toc -->` table of contents marker.
+fn is_toc_marker(block: &str) -> bool {
+    let block = block.trim();
+    block.eq_ignore_ascii_case("[[toc]]")
+        || block
+            .strip_prefix("<!--")
+            .and_then(|comment| comment.strip_suffix("-->"))
+            .is_some_and(|comment| comment.trim().eq_ignore_ascii_case("toc"))
+}
+
+/// Strip the `[^label]:` marker and continuation indent from a footnote definition's source.
+fn footnote_definition_body(definition: &str) -> String {
+    let definition = match definition.find("]:") {
+        Some(pos) => &definition[pos + 2..],
+        None => definition,
+    };
+    definition
+        .lines()
+        .map(|line| {
+            let indent = line.len() - line.trim_start_matches(' ').len();
+            &line[indent.min(4)..]
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn parser_options() -> Options {
+    Options::ENABLE_TABLES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_DEFINITION_LIST
+        | Options::ENABLE_MATH
+        | Options::ENABLE_SUPERSCRIPT // TODO not working - use <sup></sup> and <sub></sub> instead
+        | Options::ENABLE_GFM
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_FOOTNOTES
+}
+
+/// What has to be known about a document before converting it.
+struct DocumentIndex {
+    /// Footnote definitions can appear anywhere, so they're collected up front. Each one is
+    /// converted at its first reference and dropped from the body.
+    /// Label -> (definition body, line the definition starts on)
+    footnote_sources: HashMap<String, (String, usize)>,
+    /// Heading slugs in document order, so links to `#anchors` can be resolved to labels
+    heading_slugs: Vec<String>,
+    used_slugs: HashSet<String>,
+    /// Whether there's a `[[toc]]`/`<!-- toc -->` marker
+    has_toc_marker: bool,
+}
+
+impl DocumentIndex {
+    fn new(markdown: &str) -> Self {
+        let line_index = LineIndex::new(markdown);
+        let mut index = DocumentIndex {
+            footnote_sources: HashMap::new(),
+            heading_slugs: Vec::new(),
+            used_slugs: HashSet::new(),
+            has_toc_marker: false,
+        };
+        let mut plain_heading_text: Option<String> = None;
+        let mut in_footnote_definition = false;
+        for (event, range) in Parser::new_ext(markdown, parser_options()).into_offset_iter() {
+            match event {
+                Event::Start(Tag::FootnoteDefinition(label)) => {
+                    in_footnote_definition = true;
+                    index
+                        .footnote_sources
+                        .entry(label.to_lowercase())
+                        .or_insert_with(|| {
+                            let (line, _) = line_index.position(range.start);
+                            (footnote_definition_body(&markdown[range]), line)
+                        });
+                }
+                Event::End(TagEnd::FootnoteDefinition) => in_footnote_definition = false,
+                _ if in_footnote_definition => {}
+                Event::Start(Tag::Paragraph | Tag::HtmlBlock)
+                    if is_toc_marker(&markdown[range.start..range.end]) =>
+                {
+                    index.has_toc_marker = true;
+                }
+                Event::Start(Tag::Heading { .. }) => plain_heading_text = Some(String::new()),
+                Event::Text(text) | Event::Code(text) => {
+                    if let Some(heading_text) = plain_heading_text.as_mut() {
+                        heading_text.push_str(&text);
+                    }
+                }
+                Event::End(TagEnd::Heading { .. }) => {
+                    let heading_text = plain_heading_text.take().unwrap_or_default();
+                    index
+                        .heading_slugs
+                        .push(unique_heading_slug(&heading_text, &mut index.used_slugs));
+                }
+                _ => {}
+            }
+        }
+        index
+    }
+}
+
+/// Slug a heading the way GitHub does, appending `-1`, `-2`, ... to repeated slugs.
+///
+/// Returns an empty string (no label) for headings with RTL text.
+fn unique_heading_slug(heading_text: &str, used_slugs: &mut HashSet<String>) -> String {
+    if heading_text.chars().any(is_rtl_character) {
+        return String::new();
+    }
+    let base: String = heading_text
+        .trim()
+        .to_lowercase()
+        .chars()
+        .filter_map(|c| match c {
+            ' ' => Some('-'),
+            c if c.is_alphanumeric() || c == '_' || c == '-' => Some(c),
+            _ => None,
+        })
+        .collect();
+    if base.is_empty() {
+        return base;
+    }
+
+    let mut slug = base.clone();
+    let mut suffix = 0;
+    while used_slugs.contains(&slug) {
+        suffix += 1;
+        slug = format!("{base}-{suffix}");
+    }
+    used_slugs.insert(slug.clone());
+    slug
+}
+
 pub fn to_kebab_case(text: &str) -> String {
     // Check if any characters are RTL
     if text.chars().any(is_rtl_character) {
@@ -785,7 +1367,6 @@ pub fn escape_text_without_filtering(text: &str) -> String {
         .replace("+", "\\+")
         .replace("!", "\\!")
         .replace("\"", "\\\"")
-        .replace("'", "\'")
         .replace("<", "\\<")
         .replace(">", "\\>")
         .replace("~", "\\~")
@@ -937,15 +1518,18 @@ async fn convert_html_block(
     html: &str,
     image_processor: &ImageProcessor,
     image_files: &mut HashMap<String, Vec<u8>>,
-) -> String {
+    links: &mut LinkRenderer,
+    file: &FileLinks,
+) -> Result<(String, Vec<Diagnostic>)> {
     // For block HTML, we can use the full HTML parser
     let mut converter = HtmlToTypstConverter::new();
     let result = converter
-        .convert_html_to_typst(html, image_processor, image_files)
-        .await;
+        .convert_html_to_typst(html, image_processor, image_files, links, file)
+        .await?;
+    let diagnostics = converter.take_diagnostics();
 
     // Check if this is a code block (contains ```)
-    if result.trim().starts_with("```") && result.trim().ends_with("```") {
+    let result = if result.trim().starts_with("```") && result.trim().ends_with("```") {
         // For code blocks, don't add extra newlines
         result
     } else if !result.is_empty() {
@@ -953,37 +1537,176 @@ async fn convert_html_block(
         format!("\n\n{}\n\n", result.trim())
     } else {
         String::new()
-    }
+    };
+    Ok((result, diagnostics))
 }
 
-pub fn markdown_to_typst(
-    markdown: &str,
-    config: &MdpdfConfig,
-) -> Result<(String, HashMap<String, Vec<u8>>), String> {
+pub fn markdown_to_typst(markdown: &str, config: &MdpdfConfig) -> Result<TypstOutput> {
     // Create a runtime for async operations
-    let rt = Runtime::new().map_err(|e| format!("Failed to create runtime: {e}"))?;
+    let rt = Runtime::new()?;
 
     // Run the async function
-    rt.block_on(markdown_to_typst_async(markdown, config))
+    rt.block_on(convert_document(markdown, config))
+}
+
+/// Async version of [`markdown_to_typst`].
+pub async fn markdown_to_typst_async(markdown: &str, config: &MdpdfConfig) -> Result<TypstOutput> {
+    convert_document(markdown, config).await
+}
+
+/// Like [`markdown_to_typst`], but `overrides` changes the configuration after the front matter
+/// is merged in, before the document is converted.
+pub fn markdown_to_typst_with_overrides(
+    markdown: &str,
+    config: &MdpdfConfig,
+    overrides: ConfigOverrides<'_>,
+) -> Result<TypstOutput> {
+    let rt = Runtime::new()?;
+    rt.block_on(convert_document_with(markdown, config, overrides))
+}
+
+/// A Markdown file that's part of a document made of several files.
+#[derive(Debug, Clone)]
+pub struct MarkdownFile {
+    /// Where the file is, relative to `base_dir`. Links from the other files (e.g.
+    /// `setup.md#install`) and the file's relative image paths are resolved with it.
+    pub path: String,
+    pub markdown: String,
+}
+
+/// Like [`markdown_to_typst`], but for a document made of several Markdown files, in order.
+///
+/// The first file's front matter applies to the whole document. Links between the files, like
+/// `setup.md#install`, point at the heading in the other file, and diagnostics have their
+/// `file` set.
+pub fn markdown_files_to_typst(
+    files: &[MarkdownFile],
+    config: &MdpdfConfig,
+) -> Result<TypstOutput> {
+    let rt = Runtime::new()?;
+    rt.block_on(convert_files(files, config, &|_| {}))
+}
+
+/// Async version of [`markdown_files_to_typst`].
+pub async fn markdown_files_to_typst_async(
+    files: &[MarkdownFile],
+    config: &MdpdfConfig,
+) -> Result<TypstOutput> {
+    convert_files(files, config, &|_| {}).await
+}
+
+/// Like [`markdown_files_to_typst`], but `overrides` changes the configuration after each
+/// file's front matter is merged in, before the files are converted.
+pub fn markdown_files_to_typst_with_overrides(
+    files: &[MarkdownFile],
+    config: &MdpdfConfig,
+    overrides: ConfigOverrides<'_>,
+) -> Result<TypstOutput> {
+    let rt = Runtime::new()?;
+    rt.block_on(convert_files(files, config, overrides))
 }
 
 pub fn typst_to_pdf(
     typst_code: &str,
     config: &MdpdfConfig,
     image_files: HashMap<String, Vec<u8>>,
-) -> Result<Vec<u8>, String> {
-    // Create the full Typst document with template
-    let template = TypstCompiler::create_document_template(config);
-    let full_document = format!("\n{template}\n\n{typst_code}\n"); // force newlines
-
-    // Compile to PDF using our Typst compiler
-    TypstCompiler::compile_to_pdf(full_document, config.clone(), image_files)
-        .map_err(|e| e.to_string())
+) -> Result<PdfOutput> {
+    compile_typst(typst_code, config, image_files, None)
+}
+
+/// Like [`typst_to_pdf`], but reports Typst errors and warnings at the Markdown that the
+/// offending Typst code was generated from.
+pub fn typst_to_pdf_with_source_map(
+    typst_code: &str,
+    config: &MdpdfConfig,
+    image_files: HashMap<String, Vec<u8>>,
+    source_map: &SourceMap,
+) -> Result<PdfOutput> {
+    compile_typst(typst_code, config, image_files, Some(source_map))
+}
+
+fn compile_typst(
+    typst_code: &str,
+    config: &MdpdfConfig,
+    image_files: HashMap<String, Vec<u8>>,
+    source_map: Option<&SourceMap>,
+) -> Result<PdfOutput> {
+    let (pdf_bytes, diagnostics) =
+        TypstCompiler::compile_to_pdf(typst_code, config.clone(), image_files, source_map)?;
+    Ok(PdfOutput {
+        pdf_bytes,
+        diagnostics,
+    })
+}
+
+/// Compiles new versions of a document to PDF, e.g. when watching it for changes. Unlike
+/// [`typst_to_pdf_with_source_map`], this keeps the parsed Typst code between compilations,
+/// so Typst can reuse its work for the parts of a large document that didn't change.
+///
+/// Typst's caches are shared by the whole process and grow with every version compiled, so
+/// programs that compile many versions should clear out old entries with `comemo::evict`.
+#[derive(Default)]
+pub struct PdfCompiler {
+    world: Option<MdpdfWorld>,
+    fonts: Option<LoadedFonts>,
+}
+
+impl PdfCompiler {
+    /// A compiler that loads the fonts again for every version, so changes to the font files
+    /// are picked up.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A compiler that uses fonts loaded before, e.g. to share them between the documents of
+    /// a batch. Documents with different `font_paths` or `system_fonts` load their own.
+    pub fn with_fonts(fonts: LoadedFonts) -> Self {
+        Self {
+            world: None,
+            fonts: Some(fonts),
+        }
+    }
+
+    /// Compile the latest version of the document, from [`TypstOutput`].
+    pub fn compile(
+        &mut self,
+        typst_code: &str,
+        config: &MdpdfConfig,
+        image_files: HashMap<String, Vec<u8>>,
+        source_map: &SourceMap,
+    ) -> Result<PdfOutput> {
+        let fonts = match &self.fonts {
+            Some(fonts) => fonts.clone(),
+            None => LoadedFonts::load(config),
+        };
+        let world = match &mut self.world {
+            Some(world) => {
+                world.update(config.clone(), image_files, fonts);
+                world
+            }
+            None => self.world.insert(MdpdfWorld::with_fonts(
+                config.clone(),
+                String::new(),
+                image_files,
+                fonts,
+            )),
+        };
+        // The template is generated with the world's fonts, so they're only loaded once
+        let (full_document, body_start) = world.document(typst_code)?;
+        world.set_main(&full_document);
+        let locate = |offset: usize| source_map.locate(offset.checked_sub(body_start)?);
+        let (pdf_bytes, diagnostics) = world.compile_to_pdf(&locate)?;
+        Ok(PdfOutput {
+            pdf_bytes,
+            diagnostics,
+        })
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::LinkStyle;
     use tokio::runtime::Runtime;
 
     fn run_async_test<F, T>(future: F) -> T
@@ -994,6 +1717,14 @@ mod tests {
         rt.block_on(future)
     }
 
+    async fn markdown_to_typst_async(
+        markdown: &str,
+        config: &MdpdfConfig,
+    ) -> Result<(String, HashMap<String, Vec<u8>>)> {
+        let output = convert_document(markdown, config).await?;
+        Ok((output.typst_code, output.image_files))
+    }
+
     #[test]
     fn test_heading_conversion() {
         let markdown = "# Heading 1\n## Heading 2\n### Heading 3";
@@ -1076,7 +1807,7 @@ fn main() {
         assert!(result.is_ok());
 
         let (typst_code, _) = result.unwrap();
-        assert!(typst_code.contains(&markdown));
+        assert!(typst_code.contains(markdown));
     }
 
     #[test]
@@ -1148,7 +1879,7 @@ print(f"User: {user}")
         assert!(result.is_ok());
 
         let (typst_code, _) = result.unwrap();
-        assert!(typst_code.contains(&markdown));
+        assert!(typst_code.contains(markdown));
     }
 
     #[test]
@@ -1281,7 +2012,7 @@ print(f"User: {user}")
         let (typst_code, _) = result.unwrap();
         assert_eq!(
             typst_code,
-            "\n\nthis is a\n\nparagraph\nwith#strong[bold] and #emph[italic] and #link(\"https://www.google.com\")[link]\n and #strong[strong] and #emph[em] and \n- an unordered list\n and an \n+ ordered list\n\n"
+            "\n\nthis is a\n\nparagraph\nwith#strong[bold] and #emph[italic] and #link(\"https://www.google.com\")[link] (`https://www.google.com`)\n and #strong[strong] and #emph[em] and \n- an unordered list\n and an \n+ ordered list\n\n"
         );
     }
 
@@ -2237,7 +2968,7 @@ This is a new paragraph with <u>underlined text."#;
             typst_code,
             r#"
 
-This paragraph has #strong[bold], #emph[italic], `code`, and #link("https://example.com")[links] all mixed together.
+This paragraph has #strong[bold], #emph[italic], `code`, and #link("https://example.com")[links] (`https://example.com`) all mixed together.
 
 "#
         );
@@ -2384,4 +3115,1021 @@ xyz 456
 "#
         );
     }
+
+    #[test]
+    fn test_inline_and_display_math() {
+        let markdown = r#"Euler: $e^{i\pi} + 1 = 0$
+
+$$
+\frac{-b \pm \sqrt{b^2 - 4ac}}{2a}
+$$
+"#;
+        let config = MdpdfConfig::default();
+        let result = run_async_test(markdown_to_typst_async(markdown, &config));
+        assert!(result.is_ok());
+        let (typst_code, _) = result.unwrap();
+        assert!(typst_code.contains("Euler: $e^(i pi) + 1 = 0$"));
+        assert!(typst_code.contains("$ frac(- b plus.minus sqrt(b^2 - 4 a c), 2 a) $"));
+    }
+
+    #[test]
+    fn test_math_code_block() {
+        let markdown = "```math\n\\begin{pmatrix} 1 & 2 \\\\ 3 & 4 \\end{pmatrix}\n```\n";
+        let config = MdpdfConfig::default();
+        let result = run_async_test(markdown_to_typst_async(markdown, &config));
+        assert!(result.is_ok());
+        let (typst_code, _) = result.unwrap();
+        assert!(typst_code.contains("$ mat(delim: \"(\", 1, 2; 3, 4) $"));
+        assert!(!typst_code.contains("```"));
+    }
+
+    #[test]
+    fn test_untranslatable_math_fallback() {
+        let markdown = "Inline $\\foo{x}$\n\n```math\n\\foo{x}\n```\n";
+        let config = MdpdfConfig::default();
+        let result = run_async_test(markdown_to_typst_async(markdown, &config));
+        assert!(result.is_ok());
+        let (typst_code, _) = result.unwrap();
+        assert!(typst_code.contains(" ``` \\foo{x}```"));
+        assert!(typst_code.contains("```math\n\\foo{x}\n```"));
+    }
+
+    #[test]
+    fn test_translated_math_compiles() {
+        let markdown = r#"
+$a_{ij}^2$, $\mathbb{R}^n$, $\hat{x}$, $\vec{v} \cdot \vec{w}$, $\text{if } x \neq 0$,
+$f'(x)$, ${}^{14}C$, $\left\langle x, y \right\rangle$, $\operatorname{argmax}_x f(x)$
+
+$$
+\begin{align}
+\int_0^\infty e^{-x^2} dx &= \frac{\sqrt{\pi}}{2} \\
+\sum_{k=1}^{n} k &= \binom{n+1}{2}
+\end{align}
+$$
+
+$$
+|x| = \begin{cases} x & x \geq 0 \\ -x & \text{otherwise} \end{cases}
+\quad \begin{bmatrix} \alpha & \beta \\ \gamma & \delta \end{bmatrix}
+$$
+"#;
+        let config = MdpdfConfig::default();
+        let (typst_code, image_files) =
+            run_async_test(markdown_to_typst_async(markdown, &config)).unwrap();
+        assert!(!typst_code.contains("```"));
+        let result = typst_to_pdf(&typst_code, &config, image_files);
+        assert!(result.is_ok(), "{result:?}");
+    }
+
+    #[test]
+    fn test_footnotes() {
+        let markdown = r#"First[^1], second[^note] and first again[^1].
+
+[^1]: The first footnote.
+[^note]: A footnote with *emphasis*
+    spanning two lines.
+
+Last paragraph.
+"#;
+        let config = MdpdfConfig::default();
+        let result = run_async_test(markdown_to_typst_async(markdown, &config));
+        assert!(result.is_ok());
+        let (typst_code, _) = result.unwrap();
+        assert_eq!(
+            typst_code,
+            r#"
+First#footnote[The first footnote.]<footnote:1>, second#footnote[A footnote with #emph[emphasis]
+spanning two lines.]<footnote:2> and first again#footnote(<footnote:1>).
+
+Last paragraph.
+"#
+        );
+    }
+
+    #[test]
+    fn test_footnote_label_next_to_heading() {
+        // The heading's slug is `footnote-1`, which must not clash with the footnote's label
+        let markdown = "## Footnote 1\n\nText[^1] and again[^1].\n\n[^1]: The note.\n";
+        let config = MdpdfConfig::default();
+        let (typst_code, image_files) =
+            run_async_test(markdown_to_typst_async(markdown, &config)).unwrap();
+        assert!(typst_code.contains("<footnote-1>"));
+        assert!(typst_code.contains("<footnote:1>"));
+        let result = typst_to_pdf(&typst_code, &config, image_files);
+        assert!(result.is_ok(), "{result:?}");
+    }
+
+    #[test]
+    fn test_undefined_footnote_reference() {
+        let markdown = "Missing[^missing] footnote.";
+        let config = MdpdfConfig::default();
+        let result = run_async_test(markdown_to_typst_async(markdown, &config));
+        assert!(result.is_ok());
+        let (typst_code, _) = result.unwrap();
+        assert!(!typst_code.contains("#footnote"));
+    }
+
+    #[test]
+    fn test_anchor_links() {
+        let markdown = r#"# Setup
+
+## Setup
+
+## Step 1: Install `mdpdf`!
+
+See [setup](#setup), [the second setup](#setup-1), [install](#step-1-install-mdpdf) and [nothing](#missing).
+"#;
+        let config = MdpdfConfig::default();
+        let result = run_async_test(markdown_to_typst_async(markdown, &config));
+        assert!(result.is_ok());
+        let (typst_code, _) = result.unwrap();
+        assert!(typst_code.contains("= Setup <setup>\n"));
+        assert!(typst_code.contains("== Setup <setup-1>\n"));
+        assert!(typst_code.contains("<step-1-install-mdpdf>\n"));
+        assert!(typst_code.contains(r#"See #link(label("setup"))[setup], #link(label("setup-1"))[the second setup], #link(label("step-1-install-mdpdf"))[install] and nothing."#));
+
+        let pdf = typst_to_pdf(&typst_code, &config, HashMap::new());
+        assert!(pdf.is_ok(), "{pdf:?}");
+    }
+
+    #[test]
+    fn test_link_styles() {
+        let markdown = r#"[Markdown](https://a.example), <a href="https://b.example">inline</a>[^1]
+
+<div><a href="https://a.example">block</a></div>
+
+[^1]: A footnote [link](https://c.example).
+"#;
+        let mut config = MdpdfConfig {
+            link_style: Some(LinkStyle::TextOnly),
+            ..MdpdfConfig::default()
+        };
+        let (typst_code, _) = run_async_test(markdown_to_typst_async(markdown, &config)).unwrap();
+        assert!(typst_code.contains(
+            r#"#link("https://a.example")[Markdown], #link("https://b.example")[inline]"#
+        ));
+        assert!(typst_code.contains(r#"#link("https://a.example")[block]"#));
+        assert!(!typst_code.contains("`https://"));
+
+        config.link_style = Some(LinkStyle::FootnoteUrl);
+        let (typst_code, _) = run_async_test(markdown_to_typst_async(markdown, &config)).unwrap();
+        assert!(typst_code.contains(
+            r#"#link("https://a.example")[Markdown]#footnote(link("https://a.example"))"#
+        ));
+        assert!(
+            typst_code.contains(
+                r#"#link("https://b.example")[inline]#footnote(link("https://b.example"))"#
+            )
+        );
+
+        config.link_style = Some(LinkStyle::Endnotes);
+        let (typst_code, image_files) =
+            run_async_test(markdown_to_typst_async(markdown, &config)).unwrap();
+        assert!(typst_code.contains(r#"#link("https://a.example")[Markdown]#super[1], #link("https://b.example")[inline]#super[2]"#));
+        assert!(typst_code.contains(r#"#link("https://c.example")[link]#super[3]"#));
+        assert!(typst_code.contains(r#"#link("https://a.example")[block]#super[1]"#));
+        assert!(typst_code.ends_with(
+            "\n#heading(level: 2, outlined: false, numbering: none)[Links]\n\n+ #link(\"https://a.example\")\n+ #link(\"https://b.example\")\n+ #link(\"https://c.example\")\n"
+        ));
+        let pdf = typst_to_pdf(&typst_code, &config, image_files);
+        assert!(pdf.is_ok(), "{pdf:?}");
+    }
+
+    #[test]
+    fn test_offline_image_placeholder() {
+        let markdown = "![Architecture diagram](https://example.com/arch.png)";
+        let config = MdpdfConfig {
+            image_handling: Some(crate::config::ImageHandlingConfig {
+                offline: true,
+                ..Default::default()
+            }),
+            ..MdpdfConfig::default()
+        };
+        let (typst_code, image_files) =
+            run_async_test(markdown_to_typst_async(markdown, &config)).unwrap();
+        assert!(typst_code.contains("#emph[Image: Architecture diagram]"));
+        assert!(image_files.is_empty());
+    }
+
+    #[test]
+    fn test_conversion_diagnostics() {
+        let markdown = "# Title\n\nSee [below](#missing).\n\n  ![logo](missing.png)\n\n<marquee>hi</marquee>\n\n$\\unknowncommand{x}$\n";
+        let config = MdpdfConfig {
+            base_dir: Some(".".to_string()),
+            ..MdpdfConfig::default()
+        };
+        let output = run_async_test(convert_document(markdown, &config)).unwrap();
+        let summary: Vec<(DiagnosticCategory, Option<usize>, Option<usize>)> = output
+            .diagnostics
+            .iter()
+            .map(|d| (d.category, d.line, d.column))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (DiagnosticCategory::Link, Some(3), Some(5)),
+                (DiagnosticCategory::Image, Some(5), Some(3)),
+                (DiagnosticCategory::Html, Some(7), Some(1)),
+                (DiagnosticCategory::Math, Some(9), Some(1)),
+            ]
+        );
+        assert!(
+            output
+                .diagnostics
+                .iter()
+                .all(|d| d.severity == diagnostics::Severity::Warning)
+        );
+        assert!(output.diagnostics[1].message.contains("`missing.png`"));
+        assert!(output.diagnostics[2].message.contains("<marquee>"));
+    }
+
+    #[test]
+    fn test_nesting_diagnostics() {
+        let quotes = ">".repeat(MAX_BLOCKQUOTE_NESTING_LEVEL + 3);
+        let output = run_async_test(convert_document(
+            &format!("{quotes} deep"),
+            &MdpdfConfig::default(),
+        ))
+        .unwrap();
+        assert_eq!(output.diagnostics.len(), 1);
+        assert_eq!(output.diagnostics[0].category, DiagnosticCategory::Nesting);
+
+        let list: String = (0..MAX_LIST_NESTING_LEVEL + 3)
+            .map(|level| format!("{}- item\n", "  ".repeat(level)))
+            .collect();
+        let output = run_async_test(convert_document(&list, &MdpdfConfig::default())).unwrap();
+        assert_eq!(output.diagnostics.len(), 1);
+        assert_eq!(output.diagnostics[0].category, DiagnosticCategory::Nesting);
+        assert_eq!(output.diagnostics[0].line, Some(MAX_LIST_NESTING_LEVEL + 1));
+    }
+
+    #[test]
+    fn test_footnote_diagnostic_lines() {
+        let markdown = "Text[^1].\n\n[^1]: First line\n    <blink>x</blink>\n";
+        let output = run_async_test(convert_document(markdown, &MdpdfConfig::default())).unwrap();
+        assert_eq!(output.diagnostics.len(), 1);
+        assert_eq!(output.diagnostics[0].category, DiagnosticCategory::Html);
+        assert_eq!(output.diagnostics[0].line, Some(4));
+        assert_eq!(output.diagnostics[0].column, None);
+    }
+
+    #[test]
+    fn test_typst_warnings() {
+        let config = MdpdfConfig::default();
+        let output = typst_to_pdf(
+            "#text(font: \"No Such Font\")[Hello]",
+            &config,
+            HashMap::new(),
+        )
+        .unwrap();
+        assert!(!output.pdf_bytes.is_empty());
+        assert!(
+            output
+                .diagnostics
+                .iter()
+                .any(|d| d.category == DiagnosticCategory::Typst && d.message.contains("font"))
+        );
+    }
+
+    #[test]
+    fn test_compile_errors_point_at_markdown() {
+        use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+
+        // Images are checked for SVG before conversion and passed to Typst as-is, so a broken
+        // SVG only fails when Typst decodes it
+        let broken_svg = BASE64.encode("<svg xmlns='http://www.w3.org/2000/svg'><g></svg>");
+        let markdown = format!(
+            "# Title\n\nSome text.\n\n  Look: ![bad](data:image/svg+xml;base64,{broken_svg})\n"
+        );
+        let config = MdpdfConfig::default();
+        let output = run_async_test(convert_document(&markdown, &config)).unwrap();
+
+        let error = typst_to_pdf_with_source_map(
+            &output.typst_code,
+            &config,
+            output.image_files.clone(),
+            &output.source_map,
+        )
+        .unwrap_err();
+        assert_eq!(error.code(), "MDPDF_COMPILE");
+        let Error::Compile(errors) = error else {
+            panic!("expected a compile error, got {error:?}");
+        };
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].severity, diagnostics::Severity::Error);
+        assert_eq!(errors[0].category, DiagnosticCategory::Typst);
+        assert_eq!((errors[0].line, errors[0].column), (Some(5), Some(9)));
+        assert!(
+            errors[0]
+                .snippet
+                .as_ref()
+                .unwrap()
+                .starts_with("  Look: ![bad]")
+        );
+        assert!(errors[0].message.contains("SVG"));
+
+        // Without a source map, errors point at the generated code
+        let error = typst_to_pdf(&output.typst_code, &config, output.image_files).unwrap_err();
+        assert!(error.to_string().contains("in generated Typst code, line"));
+    }
+
+    #[test]
+    fn test_pdf_compiler() {
+        use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = MdpdfConfig {
+            base_dir: Some(dir.path().to_string_lossy().to_string()),
+            ..MdpdfConfig::default()
+        };
+        let mut compiler = PdfCompiler::new();
+        let mut compile = |markdown: &str| {
+            let output = run_async_test(convert_document(markdown, &config)).unwrap();
+            let pdf = compiler.compile(
+                &output.typst_code,
+                &output.config,
+                output.image_files,
+                &output.source_map,
+            );
+            (pdf, output.local_files)
+        };
+
+        // Local images are listed even if they're missing, so they can be watched
+        let (first, local_files) = compile("# Title\n\nSome text.\n\n![logo](logo.png)\n");
+        let first = first.unwrap();
+        let dir = std::fs::canonicalize(dir.path()).unwrap();
+        assert_eq!(local_files, vec![dir.join("logo.png")]);
+
+        // Errors in a later version still point at the Markdown
+        let broken_svg = BASE64.encode("<svg xmlns='http://www.w3.org/2000/svg'><g></svg>");
+        let (error, _) = compile(&format!(
+            "# Title\n\nSome text.\n\n  Look: ![bad](data:image/svg+xml;base64,{broken_svg})\n"
+        ));
+        let Err(Error::Compile(errors)) = error else {
+            panic!("expected a compile error, got {error:?}");
+        };
+        assert_eq!((errors[0].line, errors[0].column), (Some(5), Some(9)));
+
+        let (second, _) = compile("# Title\n\nSome other text.\n");
+        assert_ne!(first.pdf_bytes, second.unwrap().pdf_bytes);
+    }
+
+    #[test]
+    fn test_front_matter() {
+        let markdown = "---\ntitle: \"Quarterly \\\"Report\\\"\"\nauthor: [Ada, Grace]\ndate: 2024-05-01\nlang: de-CH\ntitle_block: true\ntoc: true\npage_size: nope\n---\n# Intro\n\nText\n";
+        let output = run_async_test(convert_document(markdown, &MdpdfConfig::default())).unwrap();
+        assert!(!output.typst_code.contains("title:"));
+        assert_eq!(output.config.title.as_deref(), Some("Quarterly \"Report\""));
+        // The invalid page size is reported at its line and otherwise ignored
+        assert_eq!(output.diagnostics.len(), 1);
+        assert_eq!(
+            output.diagnostics[0].category,
+            DiagnosticCategory::FrontMatter
+        );
+        assert_eq!(output.diagnostics[0].line, Some(2));
+
+        let template = TypstCompiler::create_document_template(&output.config).unwrap();
+        assert!(template.contains(
+            "#set document(title: \"Quarterly \\\"Report\\\"\", author: (\"Ada\", \"Grace\",), date: datetime(year: 2024, month: 5, day: 1))"
+        ));
+        assert!(template.contains("#set text(lang: \"de\", region: \"CH\")"));
+        assert!(template.contains("#outline()"));
+
+        let pdf = typst_to_pdf(&output.typst_code, &output.config, output.image_files).unwrap();
+        assert!(pdf.diagnostics.is_empty(), "{:?}", pdf.diagnostics);
+        let pdf_text = String::from_utf8_lossy(&pdf.pdf_bytes);
+        assert!(pdf_text.contains("Quarterly"));
+    }
+
+    #[test]
+    fn test_table_of_contents() {
+        use crate::config::TocConfig;
+
+        let markdown = "# Intro\n\n[[toc]]\n\n## Details\n\n<!-- TOC -->\n\nText\n";
+        let convert = |toc: Option<TocConfig>| {
+            let config = MdpdfConfig {
+                toc,
+                ..MdpdfConfig::default()
+            };
+            let output = run_async_test(convert_document(markdown, &config)).unwrap();
+            let template = TypstCompiler::create_document_template(&output.config).unwrap();
+            (template, output)
+        };
+
+        // At the top by default, with the markers dropped
+        let (template, output) = convert(Some(TocConfig::default()));
+        assert!(template.ends_with("#outline()\n"));
+        assert!(!output.typst_code.contains("toc"));
+        assert!(!output.typst_code.contains("#outline"));
+
+        // In place of each marker
+        let (template, output) = convert(Some(TocConfig {
+            depth: Some(2),
+            title: Some("Inhalt".to_string()),
+            placement: TocPlacement::Marker,
+        }));
+        assert!(!template.contains("#outline"));
+        assert_eq!(
+            output
+                .typst_code
+                .matches("#outline(title: \"Inhalt\", depth: 2)")
+                .count(),
+            2
+        );
+        assert!(output.typst_code.find("<intro>") < output.typst_code.find("#outline"));
+        let pdf = typst_to_pdf(&output.typst_code, &output.config, output.image_files).unwrap();
+        assert!(pdf.diagnostics.is_empty(), "{:?}", pdf.diagnostics);
+
+        // At the top if there's no marker
+        let config = MdpdfConfig {
+            toc: Some(TocConfig {
+                title: Some(String::new()),
+                placement: TocPlacement::Marker,
+                ..TocConfig::default()
+            }),
+            ..MdpdfConfig::default()
+        };
+        let output = run_async_test(convert_document("# Intro\n", &config)).unwrap();
+        assert!(output.typst_code.starts_with("#outline(title: none)\n"));
+
+        // Markers are dropped without a table of contents
+        let (_, output) = convert(None);
+        assert!(!output.typst_code.contains("toc"));
+        assert!(!output.typst_code.contains("#outline"));
+    }
+
+    #[test]
+    fn test_pdf_metadata() {
+        let config = MdpdfConfig {
+            title: Some("Metadata Test".to_string()),
+            authors: vec!["Ada".to_string()],
+            keywords: vec!["pdf".to_string(), "typst".to_string()],
+            creation_date: Some("2024-05-01T12:30:00".to_string()),
+            ..MdpdfConfig::default()
+        };
+        let template = TypstCompiler::create_document_template(&config).unwrap();
+        assert!(template.contains("keywords: (\"pdf\", \"typst\",)"));
+
+        let typst_code = "= First\n\nToday is #datetime.today().display()\n\n== Second\n";
+        let pdf = typst_to_pdf(typst_code, &config, HashMap::new()).unwrap();
+        let pdf_text = String::from_utf8_lossy(&pdf.pdf_bytes);
+        assert!(pdf_text.contains("Metadata Test"));
+        assert!(pdf_text.contains("D:20240501123000"));
+        // Headings become bookmarks
+        assert!(pdf_text.contains("/Outlines"));
+        assert!(pdf_text.contains("/Title (First)"));
+        assert!(pdf_text.contains("/Title (Second)"));
+
+        // A date in the document takes precedence over the creation date
+        let config = MdpdfConfig {
+            date: Some("2023-01-02".to_string()),
+            ..config
+        };
+        let pdf = typst_to_pdf("Text", &config, HashMap::new()).unwrap();
+        assert!(String::from_utf8_lossy(&pdf.pdf_bytes).contains("D:20230102"));
+
+        let config = MdpdfConfig {
+            creation_date: Some("yesterday".to_string()),
+            ..config
+        };
+        let error = typst_to_pdf("Text", &config, HashMap::new()).unwrap_err();
+        assert_eq!(error.code(), "MDPDF_INPUT");
+    }
+
+    #[test]
+    fn test_deterministic_output() {
+        use crate::config::ImageHandlingConfig;
+
+        let markdown = std::fs::read_to_string("tests/demo.md").unwrap();
+        let config = MdpdfConfig {
+            deterministic: true,
+            base_dir: Some("tests".to_string()),
+            image_handling: Some(ImageHandlingConfig {
+                offline: true,
+                ..ImageHandlingConfig::default()
+            }),
+            ..MdpdfConfig::default()
+        };
+        let convert = || {
+            let output = markdown_to_typst(&markdown, &config).unwrap();
+            typst_to_pdf(&output.typst_code, &output.config, output.image_files)
+                .unwrap()
+                .pdf_bytes
+        };
+        let first = convert();
+        let second = convert();
+        assert!(first == second, "PDFs differ");
+        // Without SOURCE_DATE_EPOCH or a creation date, there is no creation date
+        if std::env::var_os("SOURCE_DATE_EPOCH").is_none() {
+            assert!(!String::from_utf8_lossy(&first).contains("/CreationDate"));
+        }
+    }
+
+    #[test]
+    fn test_pdf_standard() {
+        use crate::config::PdfStandard;
+
+        let config = MdpdfConfig {
+            pdf_standard: Some(PdfStandard::PdfA2b),
+            lang: Some("de".to_string()),
+            ..MdpdfConfig::default()
+        };
+        let pdf = typst_to_pdf("Hallo", &config, HashMap::new()).unwrap();
+        let pdf_text = String::from_utf8_lossy(&pdf.pdf_bytes);
+        assert!(pdf_text.contains("<pdfaid:part>2</pdfaid:part>"));
+        assert!(pdf_text.contains("/Lang (de)"));
+
+        // Violations are reported as export errors naming the standard
+        let typst_code = "#text(fill: cmyk(0%, 0%, 0%, 100%))[Black]";
+        let error = typst_to_pdf(typst_code, &config, HashMap::new()).unwrap_err();
+        let Error::PdfExport(diagnostics) = &error else {
+            panic!("expected a PDF export error, got {error:?}");
+        };
+        assert!(diagnostics[0].message.ends_with("(required by PDF/A-2b)"));
+        assert_eq!(error.code(), "MDPDF_PDF_EXPORT");
+
+        // Other export errors are left as Typst reported them
+        let config = MdpdfConfig {
+            pdf_standard: Some(PdfStandard::PdfA3b),
+            creation_date: Some("2024-01-01".to_string()),
+            ..MdpdfConfig::default()
+        };
+        let typst_code = "#pdf.embed(\"a.txt\", bytes(\"a\"), mime-type: \"text/plain\")\n#pdf.embed(\"a.txt\", bytes(\"b\"), mime-type: \"text/plain\")";
+        let error = typst_to_pdf(typst_code, &config, HashMap::new()).unwrap_err();
+        let Error::PdfExport(diagnostics) = &error else {
+            panic!("expected a PDF export error, got {error:?}");
+        };
+        assert!(
+            diagnostics[0]
+                .message
+                .starts_with("duplicate embedded file for path `a.txt`")
+        );
+        assert!(!diagnostics[0].message.contains("required by"));
+    }
+
+    #[test]
+    fn test_image_alt_text() {
+        use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+
+        let svg = BASE64.encode("<svg xmlns='http://www.w3.org/2000/svg' width='1' height='1'/>");
+        let markdown = format!(
+            "![A *bold* \"claim\" #1](data:image/svg+xml;base64,{svg})\n\n<img src=\"data:image/svg+xml;base64,{svg}\" alt='Say \"hi\"'>\n"
+        );
+        let output = run_async_test(convert_document(&markdown, &MdpdfConfig::default())).unwrap();
+        assert!(
+            output
+                .typst_code
+                .contains("alt: \"A bold \\\"claim\\\" #1\")"),
+            "{}",
+            output.typst_code
+        );
+        assert!(output.typst_code.contains("alt: \"Say \\\"hi\\\"\")"));
+        typst_to_pdf(&output.typst_code, &output.config, output.image_files).unwrap();
+    }
+
+    #[test]
+    fn test_script_fonts() {
+        // The language isn't guessed from the text, only configured
+        let output =
+            markdown_to_typst("# 你好\n\n这是一个测试文档。", &MdpdfConfig::default()).unwrap();
+        assert_eq!(output.config.lang, None);
+        let config = MdpdfConfig {
+            lang: Some("ja".to_string()),
+            ..MdpdfConfig::default()
+        };
+        let output = markdown_to_typst("这是一个测试文档。", &config).unwrap();
+        assert_eq!(output.config.lang.as_deref(), Some("ja"));
+        let output = markdown_to_typst("Some text", &MdpdfConfig::default()).unwrap();
+        assert_eq!(output.config.lang, None);
+
+        // Fallback fonts are only listed if they're available, with the document's language first
+        let template = TypstCompiler::create_document_template(&output.config).unwrap();
+        if cfg!(feature = "cjk-fonts") {
+            assert!(template.contains(
+                "#set text(font: (\"Libertinus Serif\", \"Noto Sans SC\", \"Noto Sans TC\""
+            ));
+            let template = TypstCompiler::create_document_template(&config).unwrap();
+            assert!(template.contains("(\"Libertinus Serif\", \"Noto Sans JP\","));
+        } else {
+            assert!(template.contains("#set text(font: (\"Libertinus Serif\",))"));
+        }
+    }
+
+    #[test]
+    fn test_themes() {
+        let markdown = "# Title\n\n## Section\n\nSome `code` and a [link](https://example.com).\n\n```rust\nfn main() {}\n```\n\n> A quote\n\n| a | b |\n|---|---|\n| 1 | 2 |\n";
+        for theme in ["default", "github", "academic", "compact"] {
+            let config = MdpdfConfig {
+                theme: Some(theme.to_string()),
+                ..MdpdfConfig::default()
+            };
+            let output = markdown_to_typst(markdown, &config).unwrap();
+            let pdf = typst_to_pdf(&output.typst_code, &config, output.image_files).unwrap();
+            assert!(pdf.diagnostics.is_empty(), "{theme}: {:?}", pdf.diagnostics);
+        }
+
+        // The default theme is used if none is configured
+        let template = TypstCompiler::create_document_template(&MdpdfConfig::default()).unwrap();
+        assert!(template.contains("#show link: underline\n"));
+        assert!(template.contains("#show quote.where(block: true): block.with(stroke: (left: 2pt + gray, rest: none), above: 1em, below: 1.2em)\n"));
+
+        let config = MdpdfConfig {
+            theme: Some("github".to_string()),
+            ..MdpdfConfig::default()
+        };
+        let template = TypstCompiler::create_document_template(&config).unwrap();
+        assert!(template.contains("#show link: set text(fill: rgb(\"#0969da\"))\n"));
+        assert!(template.contains("#set table(stroke: 1pt + rgb(\"#d1d9e0\")"));
+        assert!(!template.contains("#show link: underline"));
+
+        let config = MdpdfConfig {
+            theme: Some("missing-theme.toml".to_string()),
+            ..MdpdfConfig::default()
+        };
+        let error = typst_to_pdf("Text", &config, HashMap::new()).unwrap_err();
+        assert!(matches!(error, Error::Input(_)), "{error}");
+    }
+
+    #[test]
+    fn test_preamble_and_template() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("conf.typ"),
+            "#let conf(doc) = {\n  set text(fill: blue)\n  doc\n}\n#let signature = [Signed]\n",
+        )
+        .unwrap();
+        let config = MdpdfConfig {
+            base_dir: Some(dir.path().to_string_lossy().to_string()),
+            preamble: Some("#import \"conf.typ\": conf, signature".to_string()),
+            template: Some("#show: conf\n{{body}}\n#signature\n".to_string()),
+            ..MdpdfConfig::default()
+        };
+        let output = markdown_to_typst("# Title\n\nText", &config).unwrap();
+        let (document, body_start) =
+            TypstCompiler::create_document(&output.config, &output.typst_code).unwrap();
+        assert!(
+            document[..body_start].contains("#import \"conf.typ\": conf, signature\n#show: conf\n")
+        );
+        assert!(document[body_start..].trim_end().ends_with("#signature"));
+        let pdf = typst_to_pdf_with_source_map(
+            &output.typst_code,
+            &output.config,
+            output.image_files,
+            &output.source_map,
+        )
+        .unwrap();
+        assert!(pdf.diagnostics.is_empty(), "{:?}", pdf.diagnostics);
+
+        // Errors in imported files point at the file
+        std::fs::write(
+            dir.path().join("conf.typ"),
+            "#let conf(doc) = {\n  doc + 1\n}\n#let signature = []\n",
+        )
+        .unwrap();
+        let Err(Error::Compile(diagnostics)) = typst_to_pdf("Text", &config, HashMap::new()) else {
+            panic!("expected a compile error");
+        };
+        assert!(
+            diagnostics[0].message.ends_with("(in conf.typ, line 2)"),
+            "{diagnostics:?}"
+        );
+
+        // Only `.typ` files in the base directory can be imported
+        std::fs::create_dir(dir.path().join("docs")).unwrap();
+        std::fs::write(dir.path().join("docs/notes.txt"), "").unwrap();
+        for import in ["../conf.typ", "notes.txt"] {
+            let config = MdpdfConfig {
+                preamble: Some(format!("#import {import:?}")),
+                base_dir: Some(dir.path().join("docs").to_string_lossy().to_string()),
+                ..MdpdfConfig::default()
+            };
+            assert!(typst_to_pdf("Text", &config, HashMap::new()).is_err());
+        }
+
+        let config = MdpdfConfig {
+            template: Some("#show: it => it".to_string()),
+            ..MdpdfConfig::default()
+        };
+        let error = typst_to_pdf("Text", &config, HashMap::new()).unwrap_err();
+        assert!(matches!(error, Error::Input(_)), "{error}");
+    }
+
+    #[test]
+    fn test_multiple_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("guide/images")).unwrap();
+        std::fs::write(
+            dir.path().join("guide/images/pixel.png"),
+            base64::Engine::decode(
+                &base64::engine::general_purpose::STANDARD,
+                "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNk+M9QDwADhgGAWjR9awAAAABJRU5ErkJggg==",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let files = [
+            MarkdownFile {
+                path: "README.md".to_string(),
+                markdown: "---\ntitle: Handbook\n---\n# Intro\n\nSee [setup](guide/setup.md#install), [the guide](./guide/setup.md) and [nothing](guide/setup.md#missing)[^1].\n\n[^1]: A note.\n".to_string(),
+            },
+            MarkdownFile {
+                path: "guide/setup.md".to_string(),
+                markdown: "# Intro\n\n## Install\n\n![Pixel](images/pixel.png)\n\nBack to the [start](../README.md#intro) and [here](#intro)[^1].\n\n[^1]: Another note.\n".to_string(),
+            },
+        ];
+        let config = MdpdfConfig {
+            base_dir: Some(dir.path().to_string_lossy().to_string()),
+            page_break_between_files: true,
+            ..MdpdfConfig::default()
+        };
+        let output = markdown_files_to_typst(&files, &config).unwrap();
+        let code = &output.typst_code;
+        assert_eq!(output.config.title.as_deref(), Some("Handbook"));
+
+        // Labels are per file, and links between files point at them
+        assert!(code.contains("= Intro <file-1:intro>"));
+        assert!(code.contains("= Intro <file-2:intro>"));
+        assert!(code.contains("#link(label(\"file-2:install\"))[setup]"));
+        assert!(code.contains("#link(label(\"file-2\"))[the guide]"));
+        assert!(code.contains("#link(label(\"file-1:intro\"))[start]"));
+        assert!(code.contains("#link(label(\"file-2:intro\"))[here]"));
+        assert!(code.contains("<file-1:footnote:1>"));
+        assert!(code.contains("<file-2:footnote:1>"));
+        assert!(code.contains("#pagebreak(weak: true)"));
+        // Images are loaded relative to their file
+        assert_eq!(output.image_files.len(), 1);
+
+        // Diagnostics say which file they're in
+        let [diagnostic] = output.diagnostics.as_slice() else {
+            panic!("{:?}", output.diagnostics);
+        };
+        assert!(diagnostic.message.contains("guide/setup.md#missing"));
+        assert_eq!(diagnostic.file.as_deref(), Some("README.md"));
+        assert_eq!(diagnostic.line, Some(6));
+        let location = output
+            .source_map
+            .locate(code.find("<file-2:install>").unwrap())
+            .unwrap();
+        assert_eq!(location.file.as_deref(), Some("guide/setup.md"));
+        assert_eq!(location.line, 3);
+
+        let pdf = typst_to_pdf_with_source_map(
+            code,
+            &output.config,
+            output.image_files,
+            &output.source_map,
+        )
+        .unwrap();
+        assert!(pdf.diagnostics.is_empty(), "{:?}", pdf.diagnostics);
+    }
+
+    #[test]
+    fn test_multiple_files_toc() {
+        use crate::config::TocConfig;
+
+        let files = ["a.md", "b.md", "c.md"].map(|path| MarkdownFile {
+            path: path.to_string(),
+            markdown: format!("# {path}\n\n[[toc]]\n"),
+        });
+        let config = MdpdfConfig {
+            toc: Some(TocConfig {
+                placement: TocPlacement::Marker,
+                ..TocConfig::default()
+            }),
+            ..MdpdfConfig::default()
+        };
+        let output = markdown_files_to_typst(&files, &config).unwrap();
+        // Only the first marker is replaced by the table of contents
+        let code = &output.typst_code;
+        assert_eq!(code.matches("#outline(").count(), 1, "{code}");
+        assert!(code.find("#outline(").unwrap() < code.find("<file-2>").unwrap());
+    }
+
+    #[test]
+    fn test_config_overrides() {
+        let markdown = "---\ntoc:\n  placement: top\n---\n# Intro\n\n[[toc]]\n";
+        let output = markdown_to_typst(markdown, &MdpdfConfig::default()).unwrap();
+        assert!(!output.typst_code.contains("#outline("));
+
+        // Overrides win over the front matter, and change how the body is converted
+        let at_marker = |config: &mut MdpdfConfig| {
+            if let Some(toc) = &mut config.toc {
+                toc.placement = TocPlacement::Marker;
+            }
+        };
+        let config = MdpdfConfig::default();
+        let output = markdown_to_typst_with_overrides(markdown, &config, &at_marker).unwrap();
+        assert!(output.typst_code.contains("#outline("));
+        assert_eq!(output.config.toc.unwrap().placement, TocPlacement::Marker);
+
+        let files = [MarkdownFile {
+            path: "a.md".to_string(),
+            markdown: markdown.to_string(),
+        }];
+        let output = markdown_files_to_typst_with_overrides(&files, &config, &at_marker).unwrap();
+        assert!(output.typst_code.contains("#outline("));
+    }
+
+    #[test]
+    fn test_multiple_files_html() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("guide/deep/images")).unwrap();
+        std::fs::write(
+            dir.path().join("guide/deep/images/pixel.svg"),
+            "<svg xmlns='http://www.w3.org/2000/svg' width='1' height='1'/>",
+        )
+        .unwrap();
+        let files = [
+            MarkdownFile {
+                path: "README.md".to_string(),
+                markdown: "# Intro\n\nSee <a href=\"guide/deep/setup.md#install\">setup</a>.\n"
+                    .to_string(),
+            },
+            MarkdownFile {
+                path: "guide/deep/setup.md".to_string(),
+                markdown: "## Install\n\n<div><img src=\"images/pixel.svg\" alt=\"Block\"></div>\n\nInline <img src=\"./images/pixel.svg\" alt=\"Inline\">.\n\n<p><a href=\"../../README.md#intro\">Back</a> and <a href=\"../../README.md#missing\">nowhere</a></p>\n".to_string(),
+            },
+        ];
+        let config = MdpdfConfig {
+            base_dir: Some(dir.path().to_string_lossy().to_string()),
+            ..MdpdfConfig::default()
+        };
+        let output = markdown_files_to_typst(&files, &config).unwrap();
+        let code = &output.typst_code;
+
+        // HTML links between files point at their labels, like Markdown links
+        assert!(
+            code.contains("#link(label(\"file-2:install\"))[setup]"),
+            "{code}"
+        );
+        assert!(
+            code.contains("#link(label(\"file-1:intro\"))[Back]"),
+            "{code}"
+        );
+        assert!(code.contains("#link(label(\"file-1\"))[nowhere]"), "{code}");
+        // HTML images are loaded relative to their file
+        assert!(!code.contains("Image:"), "{code}");
+        assert_eq!(output.image_files.len(), 1);
+
+        let [diagnostic] = output.diagnostics.as_slice() else {
+            panic!("{:?}", output.diagnostics);
+        };
+        assert!(diagnostic.message.contains("../../README.md#missing"));
+        assert_eq!(diagnostic.file.as_deref(), Some("guide/deep/setup.md"));
+    }
+
+    #[test]
+    fn test_shared_fonts() {
+        use ::typst::World;
+        let world = |config: &MdpdfConfig| {
+            crate::typst::MdpdfWorld::new(config.clone(), String::new(), HashMap::new())
+        };
+        let config = MdpdfConfig::default();
+        let (first, second) = (world(&config), world(&config));
+        // Documents without their own fonts share the embedded font book and fonts
+        assert!(std::ptr::eq(first.book(), second.book()));
+        let font = first.font(0).unwrap();
+        assert!(std::ptr::eq(
+            font.data().as_slice(),
+            second.font(0).unwrap().data().as_slice()
+        ));
+
+        // Documents with their own fonts still share the embedded fonts
+        let config = MdpdfConfig {
+            font_paths: vec!["fonts/dejavu/DejaVuSansMono.ttf".to_string()],
+            ..MdpdfConfig::default()
+        };
+        let third = world(&config);
+        assert!(!std::ptr::eq(first.book(), third.book()));
+        assert!(std::ptr::eq(
+            font.data().as_slice(),
+            third.font(0).unwrap().data().as_slice()
+        ));
+        // And share the font book with documents given the same loaded fonts
+        let fonts = LoadedFonts::load(&config);
+        let with_fonts = || {
+            crate::typst::MdpdfWorld::with_fonts(
+                config.clone(),
+                String::new(),
+                HashMap::new(),
+                fonts.clone(),
+            )
+        };
+        assert!(std::ptr::eq(with_fonts().book(), with_fonts().book()));
+        // Other documents read the font files again, so they see changes to them
+        assert!(!std::ptr::eq(third.book(), world(&config).book()));
+
+        let fonts_dir = tempfile::tempdir().unwrap();
+        let config = MdpdfConfig {
+            font_paths: vec![fonts_dir.path().to_string_lossy().to_string()],
+            ..MdpdfConfig::default()
+        };
+        let fonts = LoadedFonts::load(&config);
+        std::fs::copy(
+            "fonts/dejavu/DejaVuSansMono-Bold.ttf",
+            fonts_dir.path().join("Added.ttf"),
+        )
+        .unwrap();
+        let before = crate::typst::MdpdfWorld::with_fonts(
+            config.clone(),
+            String::new(),
+            HashMap::new(),
+            fonts,
+        );
+        // Fonts added after loading are only seen by documents that load the fonts again
+        let faces = |world: &crate::typst::MdpdfWorld| {
+            world.book().select_family("dejavu sans mono").count()
+        };
+        assert_eq!(faces(&before), faces(&first));
+        assert_eq!(faces(&world(&config)), faces(&first) + 1);
+    }
+
+    #[test]
+    fn test_compiler_fonts_loaded_once() {
+        use crate::typst::world::FONT_LOADS;
+        let config = MdpdfConfig {
+            font_paths: vec!["fonts/dejavu/DejaVuSansMono.ttf".to_string()],
+            ..MdpdfConfig::default()
+        };
+        let output = run_async_test(convert_document("# Title\n\nSome text.", &config)).unwrap();
+        let loads = || FONT_LOADS.with(|loads| loads.get());
+        let compile = |compiler: &mut PdfCompiler| {
+            compiler
+                .compile(
+                    &output.typst_code,
+                    &output.config,
+                    output.image_files.clone(),
+                    &output.source_map,
+                )
+                .unwrap()
+        };
+
+        let fonts = LoadedFonts::load(&config);
+        let before = loads();
+        // Compiling, template included, uses the fonts it was given
+        let mut compiler = PdfCompiler::with_fonts(fonts.clone());
+        compile(&mut compiler);
+        compile(&mut compiler);
+        compile(&mut PdfCompiler::with_fonts(fonts));
+        assert_eq!(loads(), before);
+
+        // Other compilers load the fonts once for every version
+        let mut compiler = PdfCompiler::new();
+        compile(&mut compiler);
+        compile(&mut compiler);
+        assert_eq!(loads(), before + 2);
+    }
+
+    #[test]
+    fn test_user_fonts() {
+        let fonts_dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(fonts_dir.path().join("nested")).unwrap();
+        std::fs::copy(
+            "fonts/dejavu/DejaVuSansMono-Bold.ttf",
+            fonts_dir.path().join("nested/Custom.ttf"),
+        )
+        .unwrap();
+        std::fs::write(fonts_dir.path().join("notes.txt"), "not a font").unwrap();
+
+        let config = MdpdfConfig {
+            heading_font: Some("DejaVu Sans Mono".to_string()),
+            mono_font: Some("No Such Mono".to_string()),
+            font_paths: vec![
+                fonts_dir.path().to_string_lossy().to_string(),
+                "missing-font.ttf".to_string(),
+            ],
+            ..MdpdfConfig::default()
+        };
+        // Only the font file in the directory is added
+        let font_count = |config: &MdpdfConfig| {
+            use ::typst::World;
+            let world =
+                crate::typst::MdpdfWorld::new(config.clone(), String::new(), HashMap::new());
+            world
+                .book()
+                .families()
+                .map(|(_, infos)| infos.count())
+                .sum::<usize>()
+        };
+        assert_eq!(font_count(&config), font_count(&MdpdfConfig::default()) + 1);
+
+        let template = TypstCompiler::create_document_template(&config).unwrap();
+        assert!(template.contains("#show heading: set text(font: \"DejaVu Sans Mono\")"));
+        assert!(template.contains("#show raw: set text(font: \"No Such Mono\")"));
+
+        let pdf = typst_to_pdf("= Title\n\n`code`", &config, HashMap::new()).unwrap();
+        let messages: Vec<&str> = pdf
+            .diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.message.as_str())
+            .collect();
+        assert!(
+            pdf.diagnostics
+                .iter()
+                .all(|diagnostic| diagnostic.category == DiagnosticCategory::Font),
+            "{messages:?}"
+        );
+        assert_eq!(messages.len(), 2, "{messages:?}");
+        assert!(messages[0].starts_with("couldn't read font 'missing-font.ttf'"));
+        assert!(messages[1].starts_with("font family 'No Such Mono' was not found"));
+    }
 }
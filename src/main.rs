@@ -1,10 +1,22 @@
 use clap::Parser;
 use std::fs;
 use std::io::{self, Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use mdpdf::{config::MdpdfConfig, markdown_to_typst, typst_to_pdf};
+use mdpdf::{
+    Error, LoadedFonts, MarkdownFile, PdfCompiler,
+    config::{
+        DEFAULT_FONT_SIZE, LinkStyle, Margins, MdpdfConfig, PageSize, PdfStandard, Theme,
+        TocPlacement, parse_length,
+    },
+    diagnostics::Diagnostic,
+    markdown_files_to_typst_with_overrides, markdown_to_typst_with_overrides,
+};
 
 #[derive(Parser)]
 #[command(
@@ -14,71 +26,494 @@ use mdpdf::{config::MdpdfConfig, markdown_to_typst, typst_to_pdf};
     long_about = "A fast Markdown to PDF converter that uses Typst for high-quality output."
 )]
 struct Args {
-    /// Input Markdown file (use - for stdin)
+    /// Input Markdown file (use - for stdin). Several files, or glob patterns like
+    /// "docs/**/*.md", are combined into one PDF.
     #[arg(value_name = "FILE")]
-    input: Option<PathBuf>,
+    inputs: Vec<PathBuf>,
+
+    /// Combine the Markdown files linked from a SUMMARY.md-style list, in order
+    #[arg(long, value_name = "FILE", conflicts_with = "inputs")]
+    summary: Option<PathBuf>,
+
+    /// Start each input file on a new page
+    #[arg(long)]
+    page_breaks: bool,
 
     /// Output PDF file (defaults to stdout)
     #[arg(short, long, value_name = "FILE")]
     output: Option<PathBuf>,
 
-    /// Page size (letter, legal, a4, or custom like "8.5inx11in")
-    #[arg(long, value_name = "SIZE", default_value = "letter")]
-    page_size: String,
+    /// Convert each input to its own PDF in this directory, keeping the inputs' directory
+    /// structure
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["output", "summary"])]
+    out_dir: Option<PathBuf>,
+
+    /// Number of files to convert at once with --out-dir (defaults to the number of CPUs)
+    #[arg(short, long, value_name = "N", requires = "out_dir")]
+    jobs: Option<usize>,
+
+    /// Convert again whenever the input, its images, or the config, preamble, template or
+    /// theme file change
+    #[arg(short, long, requires = "output", conflicts_with = "out_dir")]
+    watch: bool,
+
+    /// Configuration file (defaults to the first mdpdf.toml or .mdpdfrc.json in the input's
+    /// directory or above)
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Don't look for a configuration file
+    #[arg(long, conflicts_with = "config")]
+    no_config: bool,
+
+    /// Page size (letter, legal, a4, or custom like "8.5inx11in"; defaults to letter)
+    #[arg(long, value_name = "SIZE", help_heading = "Page")]
+    page_size: Option<String>,
+
+    /// Margin size on all sides (e.g., "1in", "20mm", "72pt", "96px" or "4em"; defaults to
+    /// 1in)
+    #[arg(long, value_name = "SIZE", help_heading = "Page")]
+    margin: Option<String>,
+
+    /// Top margin, overriding --margin
+    #[arg(long, value_name = "SIZE", help_heading = "Page")]
+    margin_top: Option<String>,
+
+    /// Bottom margin, overriding --margin
+    #[arg(long, value_name = "SIZE", help_heading = "Page")]
+    margin_bottom: Option<String>,
+
+    /// Left margin, overriding --margin
+    #[arg(long, value_name = "SIZE", help_heading = "Page")]
+    margin_left: Option<String>,
+
+    /// Right margin, overriding --margin
+    #[arg(long, value_name = "SIZE", help_heading = "Page")]
+    margin_right: Option<String>,
+
+    /// Page header, as Typst markup (e.g. "Draft")
+    #[arg(long, value_name = "MARKUP", help_heading = "Page")]
+    header: Option<String>,
+
+    /// Page footer, as Typst markup (e.g. "#context counter(page).display()")
+    #[arg(long, value_name = "MARKUP", help_heading = "Page")]
+    footer: Option<String>,
+
+    /// Font family for body text (defaults to Libertinus Serif)
+    #[arg(long, value_name = "FAMILY", help_heading = "Fonts")]
+    font: Option<String>,
+
+    /// Font family for headings (defaults to --font)
+    #[arg(long, value_name = "FAMILY", help_heading = "Fonts")]
+    heading_font: Option<String>,
+
+    /// Font family for code (defaults to DejaVu Sans Mono)
+    #[arg(long, value_name = "FAMILY", help_heading = "Fonts")]
+    mono_font: Option<String>,
+
+    /// Font size in points (defaults to 13)
+    #[arg(long, value_name = "POINTS", help_heading = "Fonts")]
+    font_size: Option<f64>,
+
+    /// Font file, or directory of font files, to use in addition to the embedded fonts (can be
+    /// repeated)
+    #[arg(long, value_name = "PATH", help_heading = "Fonts")]
+    font_path: Vec<PathBuf>,
+
+    /// Also use the fonts installed on the system (needs the system-fonts feature)
+    #[arg(long, help_heading = "Fonts")]
+    system_fonts: bool,
+
+    /// Directory for resolving local image paths (defaults to the input file's directory)
+    #[arg(long, value_name = "DIR", help_heading = "Images")]
+    base_dir: Option<PathBuf>,
+
+    /// Allow local images outside of the base directory
+    #[arg(long, help_heading = "Images")]
+    allow_outside_base_dir: bool,
+
+    /// Directory to cache downloaded images in
+    #[arg(long, value_name = "DIR", help_heading = "Images")]
+    image_cache: Option<PathBuf>,
+
+    /// Only download images from this domain, or its subdomains with "*.example.com" (can be
+    /// repeated; defaults to any domain)
+    #[arg(long, value_name = "DOMAIN", help_heading = "Images")]
+    allow_domain: Vec<String>,
 
-    /// Margin size (e.g., "1in", "20mm")
-    #[arg(long, value_name = "SIZE", default_value = "1in")]
-    margin: String,
+    /// How long to wait for an image download, in milliseconds (defaults to 2000)
+    #[arg(long, value_name = "MS", help_heading = "Images")]
+    download_timeout: Option<u64>,
 
-    /// Font size in points
-    #[arg(long, value_name = "POINTS", default_value = "13")]
-    font_size: f64,
+    /// Largest image to load, in bytes or with a unit like "500KB" or "10MB" (defaults to 10MB)
+    #[arg(long, value_name = "SIZE", help_heading = "Images")]
+    max_image_size: Option<String>,
+
+    /// Never access the network (remote images are only loaded from the cache)
+    #[arg(long, help_heading = "Images")]
+    offline: bool,
+
+    /// Document title, written to the PDF metadata
+    #[arg(long, value_name = "TITLE", help_heading = "Document")]
+    title: Option<String>,
+
+    /// Document author (can be repeated)
+    #[arg(long, value_name = "NAME", help_heading = "Document")]
+    author: Vec<String>,
+
+    /// Document keyword (can be repeated)
+    #[arg(long, value_name = "KEYWORD", help_heading = "Document")]
+    keyword: Vec<String>,
+
+    /// Document date, shown in the title block (e.g. "2024-05-01")
+    #[arg(long, value_name = "DATE", help_heading = "Document")]
+    date: Option<String>,
+
+    /// Language of the text, for hyphenation and smart quotes (e.g. "en" or "de-CH")
+    #[arg(long, value_name = "LANG", help_heading = "Document")]
+    lang: Option<String>,
+
+    /// Show the title, authors and date at the top of the first page
+    #[arg(long, help_heading = "Document")]
+    title_block: bool,
+
+    /// Add a table of contents
+    #[arg(long, help_heading = "Document")]
+    toc: bool,
+
+    /// Only list headings up to this level in the table of contents
+    #[arg(long, value_name = "LEVEL", help_heading = "Document")]
+    toc_depth: Option<u8>,
+
+    /// Title of the table of contents ("" for none)
+    #[arg(long, value_name = "TITLE", help_heading = "Document")]
+    toc_title: Option<String>,
+
+    /// Where the table of contents goes (top, or marker for a [[toc]] paragraph)
+    #[arg(long, value_name = "PLACEMENT", help_heading = "Document")]
+    toc_placement: Option<String>,
+
+    /// How links are shown (inline-url, text-only, footnote-url, or endnotes; defaults to
+    /// inline-url)
+    #[arg(long, value_name = "STYLE", help_heading = "Document")]
+    link_style: Option<String>,
+
+    /// Theme (default, github, academic, compact, or the path to a theme file)
+    #[arg(long, value_name = "THEME", help_heading = "Styling")]
+    theme: Option<String>,
+
+    /// Typst file to add after the generated template (its imports are resolved against the
+    /// base directory)
+    #[arg(long, value_name = "FILE", help_heading = "Styling")]
+    preamble: Option<PathBuf>,
+
+    /// Typst file to place the document in, at {{body}}
+    #[arg(long, value_name = "FILE", help_heading = "Styling")]
+    template: Option<PathBuf>,
+
+    /// PDF standard to conform to (1.7, a-2b, or a-3b; defaults to 1.7)
+    #[arg(long, value_name = "STANDARD", help_heading = "PDF")]
+    pdf_standard: Option<String>,
+
+    /// Produce the same PDF for the same input (the creation date is taken from
+    /// SOURCE_DATE_EPOCH, or left out)
+    #[arg(long, help_heading = "PDF")]
+    deterministic: bool,
+
+    /// When the PDF was created, as YYYY-MM-DD or YYYY-MM-DDTHH:MM:SS in UTC (defaults to now)
+    #[arg(long, value_name = "DATE", help_heading = "PDF")]
+    creation_date: Option<String>,
 }
 
-fn parse_custom_page_size(value: &str) -> Result<mdpdf::config::PageSize, String> {
-    // Parse custom page sizes like "8.5inx11in" or "210mmx297mm"
-    let parts: Vec<&str> = value.split('x').collect();
-    if parts.len() != 2 {
-        return Err(
-            "Custom page size must be in format 'WIDTHxHEIGHT' (e.g., '8.5inx11in')".to_string(),
-        );
+/// The settings given as flags, which win over the config file and the front matter.
+struct Flags {
+    page_size: Option<PageSize>,
+    margins: Option<Margins>,
+    // Margins for single sides, in inches
+    margin_top: Option<f64>,
+    margin_bottom: Option<f64>,
+    margin_left: Option<f64>,
+    margin_right: Option<f64>,
+    header: Option<String>,
+    footer: Option<String>,
+    font_family: Option<String>,
+    heading_font: Option<String>,
+    mono_font: Option<String>,
+    font_size: Option<f64>,
+    font_paths: Vec<String>,
+    system_fonts: bool,
+    allow_outside_base_dir: bool,
+    cache_directory: Option<String>,
+    allowed_domains: Vec<String>,
+    download_timeout: Option<u64>,
+    max_image_size: Option<u64>,
+    offline: bool,
+    title: Option<String>,
+    authors: Vec<String>,
+    keywords: Vec<String>,
+    date: Option<String>,
+    lang: Option<String>,
+    title_block: bool,
+    toc: bool,
+    toc_depth: Option<u8>,
+    toc_title: Option<String>,
+    toc_placement: Option<TocPlacement>,
+    link_style: Option<LinkStyle>,
+    theme: Option<String>,
+    page_breaks: bool,
+    pdf_standard: Option<PdfStandard>,
+    deterministic: bool,
+    creation_date: Option<String>,
+}
+
+impl Flags {
+    /// Parse and check the values of the flags.
+    fn parse(args: &Args) -> Result<Self, String> {
+        if let Some(font_size) = args.font_size
+            && !(font_size > 0.0 && font_size <= 1000.0)
+        {
+            return Err(format!(
+                "Invalid font size {font_size}, expected a size in points like 11"
+            ));
+        }
+        if args.toc_depth == Some(0) {
+            return Err("Invalid --toc-depth 0, it must be at least 1".to_string());
+        }
+        // `em` is relative to the font size
+        let font_size = args.font_size.unwrap_or(DEFAULT_FONT_SIZE);
+        let length = |flag: &str, value: &Option<String>| {
+            value
+                .as_deref()
+                .map(|value| parse_length(value, font_size))
+                .transpose()
+                .map_err(|e| format!("Invalid --{flag}: {e}"))
+        };
+        let link_style = args
+            .link_style
+            .as_deref()
+            .map(|link_style| match link_style.to_lowercase().as_str() {
+                "inline-url" => Ok(LinkStyle::InlineUrl),
+                "text-only" => Ok(LinkStyle::TextOnly),
+                "footnote-url" => Ok(LinkStyle::FootnoteUrl),
+                "endnotes" => Ok(LinkStyle::Endnotes),
+                _ => Err(format!(
+                    "Invalid link style '{link_style}'. Use 'inline-url', 'text-only', 'footnote-url', or 'endnotes'"
+                )),
+            })
+            .transpose()?;
+        let toc_placement = args
+            .toc_placement
+            .as_deref()
+            .map(|placement| match placement.to_lowercase().as_str() {
+                "top" => Ok(TocPlacement::Top),
+                "marker" => Ok(TocPlacement::Marker),
+                _ => Err(format!(
+                    "Invalid table of contents placement '{placement}'. Use 'top' or 'marker'"
+                )),
+            })
+            .transpose()?;
+
+        Ok(Self {
+            page_size: args.page_size.as_deref().map(PageSize::parse).transpose()?,
+            margins: length("margin", &args.margin)?.map(Margins::uniform),
+            margin_top: length("margin-top", &args.margin_top)?,
+            margin_bottom: length("margin-bottom", &args.margin_bottom)?,
+            margin_left: length("margin-left", &args.margin_left)?,
+            margin_right: length("margin-right", &args.margin_right)?,
+            header: args.header.clone(),
+            footer: args.footer.clone(),
+            font_family: args.font.clone(),
+            heading_font: args.heading_font.clone(),
+            mono_font: args.mono_font.clone(),
+            font_size: args.font_size,
+            font_paths: args
+                .font_path
+                .iter()
+                .map(|path| path.to_string_lossy().to_string())
+                .collect(),
+            system_fonts: args.system_fonts,
+            allow_outside_base_dir: args.allow_outside_base_dir,
+            cache_directory: args
+                .image_cache
+                .as_ref()
+                .map(|path| path.to_string_lossy().to_string()),
+            allowed_domains: args.allow_domain.clone(),
+            download_timeout: args.download_timeout,
+            max_image_size: args.max_image_size.as_deref().map(parse_size).transpose()?,
+            offline: args.offline,
+            title: args.title.clone(),
+            authors: args.author.clone(),
+            keywords: args.keyword.clone(),
+            date: args.date.clone(),
+            lang: args.lang.clone(),
+            title_block: args.title_block,
+            toc: args.toc,
+            toc_depth: args.toc_depth,
+            toc_title: args.toc_title.clone(),
+            toc_placement,
+            link_style,
+            theme: args.theme.clone(),
+            page_breaks: args.page_breaks,
+            pdf_standard: args
+                .pdf_standard
+                .as_deref()
+                .map(PdfStandard::parse)
+                .transpose()?,
+            deterministic: args.deterministic,
+            creation_date: args.creation_date.clone(),
+        })
     }
 
-    let width = parse_dimension(parts[0])?;
-    let height = parse_dimension(parts[1])?;
-
-    Ok(mdpdf::config::PageSize::Custom { width, height })
-}
-
-fn parse_dimension(s: &str) -> Result<f64, String> {
-    let s = s.trim();
-
-    if s.ends_with("in") {
-        let value = s[..s.len() - 2]
-            .parse::<f64>()
-            .map_err(|_| format!("Invalid dimension: {}", s))?;
-        Ok(value)
-    } else if s.ends_with("mm") {
-        let value = s[..s.len() - 2]
-            .parse::<f64>()
-            .map_err(|_| format!("Invalid dimension: {}", s))?;
-        // Convert mm to inches (1 inch = 25.4 mm)
-        Ok(value / 25.4)
-    } else if s.ends_with("cm") {
-        let value = s[..s.len() - 2]
-            .parse::<f64>()
-            .map_err(|_| format!("Invalid dimension: {}", s))?;
-        // Convert cm to inches (1 inch = 2.54 cm)
-        Ok(value / 2.54)
-    } else {
-        // Assume inches if no unit specified
-        s.parse::<f64>()
-            .map_err(|_| format!("Invalid dimension: {}", s))
+    /// Override the settings in `config` that were given as flags.
+    fn apply(&self, config: &mut MdpdfConfig) {
+        // Options that are only changed if the flag was given
+        fn set<T: Clone>(setting: &mut Option<T>, flag: &Option<T>) {
+            if let Some(value) = flag {
+                *setting = Some(value.clone());
+            }
+        }
+        fn set_list(setting: &mut Vec<String>, flag: &[String]) {
+            if !flag.is_empty() {
+                *setting = flag.to_vec();
+            }
+        }
+
+        set(&mut config.page_size, &self.page_size);
+        set(&mut config.margins, &self.margins);
+        let sides = [
+            self.margin_top,
+            self.margin_bottom,
+            self.margin_left,
+            self.margin_right,
+        ];
+        if sides.iter().any(Option::is_some) {
+            let margins = config.margins.get_or_insert_default();
+            for (side, flag) in [
+                (&mut margins.top, self.margin_top),
+                (&mut margins.bottom, self.margin_bottom),
+                (&mut margins.left, self.margin_left),
+                (&mut margins.right, self.margin_right),
+            ] {
+                if let Some(inches) = flag {
+                    *side = inches;
+                }
+            }
+        }
+        set(&mut config.header, &self.header);
+        set(&mut config.footer, &self.footer);
+        set(&mut config.font_family, &self.font_family);
+        set(&mut config.heading_font, &self.heading_font);
+        set(&mut config.mono_font, &self.mono_font);
+        set(&mut config.font_size, &self.font_size);
+        set_list(&mut config.font_paths, &self.font_paths);
+        config.system_fonts |= self.system_fonts;
+
+        let image_handling = config.image_handling.get_or_insert_default();
+        image_handling.allow_outside_base_dir |= self.allow_outside_base_dir;
+        set(&mut image_handling.cache_directory, &self.cache_directory);
+        set_list(&mut image_handling.allowed_domains, &self.allowed_domains);
+        if let Some(download_timeout) = self.download_timeout {
+            image_handling.download_timeout = download_timeout;
+        }
+        set(&mut image_handling.max_image_size, &self.max_image_size);
+        image_handling.offline |= self.offline;
+
+        set(&mut config.title, &self.title);
+        set_list(&mut config.authors, &self.authors);
+        set_list(&mut config.keywords, &self.keywords);
+        set(&mut config.date, &self.date);
+        set(&mut config.lang, &self.lang);
+        config.title_block |= self.title_block;
+        // Any of the table of contents flags adds one
+        if self.toc
+            || self.toc_depth.is_some()
+            || self.toc_title.is_some()
+            || self.toc_placement.is_some()
+        {
+            let toc = config.toc.get_or_insert_default();
+            set(&mut toc.depth, &self.toc_depth);
+            set(&mut toc.title, &self.toc_title);
+            if let Some(placement) = self.toc_placement {
+                toc.placement = placement;
+            }
+        }
+        set(&mut config.link_style, &self.link_style);
+        set(&mut config.theme, &self.theme);
+        config.page_break_between_files |= self.page_breaks;
+        set(&mut config.pdf_standard, &self.pdf_standard);
+        config.deterministic |= self.deterministic;
+        set(&mut config.creation_date, &self.creation_date);
+    }
+}
+
+/// Parse a size in bytes like `500000`, `500KB` or `10MB`.
+fn parse_size(size: &str) -> Result<u64, String> {
+    let invalid = || format!("Invalid size '{size}', expected e.g. '500KB' or '10MB'");
+    let upper = size.trim().to_uppercase();
+    let (number, unit) = [("KB", 1 << 10), ("MB", 1 << 20), ("GB", 1 << 30), ("B", 1)]
+        .into_iter()
+        .find_map(|(suffix, unit)| Some((upper.strip_suffix(suffix)?, unit)))
+        .unwrap_or((&upper, 1));
+    let number: f64 = number.trim().parse().map_err(|_| invalid())?;
+    if !(number.is_finite() && number >= 0.0) {
+        return Err(invalid());
     }
+    Ok((number * unit as f64) as u64)
 }
 
-fn parse_margin(s: &str) -> Result<f64, String> {
-    parse_dimension(s)
+/// Configuration files that are found automatically, in order of preference
+const CONFIG_FILES: [&str; 2] = ["mdpdf.toml", ".mdpdfrc.json"];
+
+/// The configuration file: `--config`, or the first one found in the input's directory or
+/// the directories above it.
+fn config_path(args: &Args, inputs: &[PathBuf]) -> Option<PathBuf> {
+    if args.no_config {
+        return None;
+    }
+    if let Some(path) = &args.config {
+        return Some(path.clone());
+    }
+    let start =
+        fs::canonicalize(default_base_dir(args.summary.as_ref().or(inputs.first()))).ok()?;
+    start
+        .ancestors()
+        .flat_map(|directory| CONFIG_FILES.map(|name| directory.join(name)))
+        .find(|path| path.is_file())
+}
+
+/// The configuration file's settings (or the defaults), overridden by the flags.
+fn load_config(
+    args: &Args,
+    config_path: Option<&Path>,
+    flags: &Flags,
+) -> Result<MdpdfConfig, String> {
+    let mut config = match config_path {
+        Some(path) => MdpdfConfig::load(path)?,
+        None => MdpdfConfig::default(),
+    };
+    flags.apply(&mut config);
+    if let Some(preamble) = read_typst(&args.preamble)? {
+        config.preamble = Some(preamble);
+    }
+    if let Some(template) = read_typst(&args.template)? {
+        config.template = Some(template);
+    }
+    if let Some(theme) = &config.theme {
+        Theme::load(theme)?;
+    }
+    Ok(config)
+}
+
+fn default_base_dir(input: Option<&PathBuf>) -> PathBuf {
+    match input {
+        Some(path) if path.to_string_lossy() != "-" => path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(".")),
+        // Resolve images from stdin against the current directory
+        _ => PathBuf::from("."),
+    }
 }
 
 fn read_input(input: Option<PathBuf>) -> Result<String, String> {
@@ -125,86 +560,613 @@ fn write_output(output: Option<PathBuf>, pdf_bytes: &[u8]) -> Result<(), String>
     }
 }
 
+/// The input files: the files listed in the summary, or the inputs with glob patterns
+/// expanded. No files means stdin.
+fn input_files(args: &Args) -> Result<Vec<PathBuf>, String> {
+    if let Some(summary) = &args.summary {
+        let content = fs::read_to_string(summary)
+            .map_err(|e| format!("Failed to read file '{}': {}", summary.display(), e))?;
+        let directory = default_base_dir(Some(summary));
+        let files: Vec<PathBuf> = summary_files(&content)
+            .into_iter()
+            .map(|file| directory.join(file))
+            .collect();
+        if files.is_empty() {
+            return Err(format!(
+                "'{}' doesn't link to any Markdown files",
+                summary.display()
+            ));
+        }
+        return Ok(files);
+    }
+
+    let mut files = Vec::new();
+    for input in &args.inputs {
+        let pattern = input.to_string_lossy();
+        if !pattern.contains(['*', '?']) {
+            files.push(input.clone());
+            continue;
+        }
+        let matches = expand_glob(&pattern);
+        if matches.is_empty() {
+            return Err(format!("No files match '{pattern}'"));
+        }
+        files.extend(matches);
+    }
+    if files.len() > 1 && files.iter().any(|file| file.to_string_lossy() == "-") {
+        return Err("stdin (-) can't be combined with other inputs".to_string());
+    }
+    Ok(files)
+}
+
+/// The Markdown files linked from a SUMMARY.md-style list, in order and without duplicates.
+fn summary_files(summary: &str) -> Vec<String> {
+    let mut files: Vec<String> = Vec::new();
+    for event in pulldown_cmark::Parser::new(summary) {
+        if let pulldown_cmark::Event::Start(pulldown_cmark::Tag::Link { dest_url, .. }) = event {
+            let path = dest_url.split('#').next().unwrap_or_default();
+            if path.ends_with(".md") && !path.contains("://") && !files.iter().any(|f| f == path) {
+                files.push(path.to_string());
+            }
+        }
+    }
+    files
+}
+
+/// Find the files matching a glob pattern, where `*` and `?` match within a path component
+/// and `**` matches any number of directories. The matches are sorted.
+fn expand_glob(pattern: &str) -> Vec<PathBuf> {
+    let (root, pattern) = match pattern.strip_prefix('/') {
+        Some(pattern) => (PathBuf::from("/"), pattern),
+        None => (PathBuf::new(), pattern),
+    };
+    let components: Vec<&str> = pattern.split('/').filter(|c| !c.is_empty()).collect();
+    let mut matches = Vec::new();
+    glob_from(&root, &components, &mut matches);
+    matches.sort();
+    matches.dedup();
+    matches
+}
+
+fn glob_from(directory: &Path, components: &[&str], matches: &mut Vec<PathBuf>) {
+    let Some((component, rest)) = components.split_first() else {
+        if directory.is_file() {
+            matches.push(directory.to_path_buf());
+        }
+        return;
+    };
+    if !component.contains(['*', '?']) {
+        glob_from(&directory.join(component), rest, matches);
+        return;
+    }
+    let read_from = if directory.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        directory
+    };
+    let Ok(entries) = fs::read_dir(read_from) else {
+        return;
+    };
+    let mut entries: Vec<_> = entries.flatten().collect();
+    entries.sort_by_key(|entry| entry.file_name());
+    if *component == "**" {
+        // Zero directories, or one more and still in `**`
+        glob_from(directory, rest, matches);
+        for entry in entries {
+            let name = entry.file_name();
+            if entry.path().is_dir() && !name.to_string_lossy().starts_with('.') {
+                glob_from(&directory.join(name), components, matches);
+            }
+        }
+        return;
+    }
+    for entry in entries {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        // Like shells, `*` doesn't match hidden files
+        if wildcard_match(component, &name)
+            && (!name.starts_with('.') || component.starts_with('.'))
+        {
+            glob_from(&directory.join(name.as_ref()), rest, matches);
+        }
+    }
+}
+
+/// Match a file name against a pattern with `*` and `?`.
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // Whether the pattern so far matches each prefix of the name
+    let mut matched = vec![false; name.len() + 1];
+    matched[0] = true;
+    for c in pattern {
+        let mut next = vec![false; name.len() + 1];
+        for i in 0..=name.len() {
+            next[i] = match c {
+                '*' => matched[i] || (i > 0 && next[i - 1]),
+                '?' => i > 0 && matched[i - 1],
+                c => i > 0 && matched[i - 1] && name[i - 1] == c,
+            };
+        }
+        matched = next;
+    }
+    matched[name.len()]
+}
+
+/// A file's path relative to the base directory, which is how files refer to each other.
+fn relative_path(path: &Path, base_dir: &Path) -> String {
+    let relative = fs::canonicalize(path)
+        .ok()
+        .zip(fs::canonicalize(base_dir).ok())
+        .and_then(|(path, base_dir)| path.strip_prefix(base_dir).ok().map(Path::to_path_buf))
+        .unwrap_or_else(|| path.to_path_buf());
+    relative.to_string_lossy().replace('\\', "/")
+}
+
+/// Print diagnostics to stderr as `file:line:column: warning[category]: message`.
+fn report_diagnostics(input_name: &str, base_dir: &Path, diagnostics: &[Diagnostic]) {
+    for diagnostic in diagnostics {
+        // In multi-file documents, the file is relative to the base directory
+        let file = diagnostic
+            .file
+            .as_ref()
+            .map(|file| match base_dir.to_str() {
+                Some(".") => file.clone(),
+                _ => base_dir.join(file).display().to_string(),
+            });
+        let input_name = file.as_deref().unwrap_or(input_name);
+        if diagnostic.line.is_some() {
+            eprintln!("{input_name}:{diagnostic}");
+        } else {
+            eprintln!("{input_name}: {diagnostic}");
+        }
+    }
+}
+
+/// Convert a document, or several files combined into one, to a PDF, printing its diagnostics.
+/// Returns the PDF and the local images it uses.
+fn convert(
+    files: &[MarkdownFile],
+    combine: bool,
+    config: &MdpdfConfig,
+    input_name: &str,
+    base_dir: &Path,
+    flags: &Flags,
+    compiler: &mut PdfCompiler,
+) -> Result<(Vec<u8>, Vec<PathBuf>), String> {
+    // The front matter is merged over the config file and flags, but the flags win
+    let overrides = |config: &mut MdpdfConfig| flags.apply(config);
+    let output = if combine {
+        markdown_files_to_typst_with_overrides(files, config, &overrides)
+    } else {
+        markdown_to_typst_with_overrides(&files[0].markdown, config, &overrides)
+    }
+    .map_err(|e| format!("Error converting markdown to Typst: {}", e))?;
+    report_diagnostics(input_name, base_dir, &output.diagnostics);
+
+    let pdf = match compiler.compile(
+        &output.typst_code,
+        &output.config,
+        output.image_files,
+        &output.source_map,
+    ) {
+        Ok(pdf) => pdf,
+        Err(Error::Compile(errors) | Error::PdfExport(errors)) => {
+            report_diagnostics(input_name, base_dir, &errors);
+            return Err(match errors.len() {
+                1 => "Error converting Typst to PDF: 1 error".to_string(),
+                count => format!("Error converting Typst to PDF: {count} errors"),
+            });
+        }
+        Err(e) => return Err(format!("Error converting Typst to PDF: {}", e)),
+    };
+    report_diagnostics(input_name, base_dir, &pdf.diagnostics);
+    Ok((pdf.pdf_bytes, output.local_files))
+}
+
+/// Read the input files, as given or from stdin.
+fn read_files(
+    inputs: &[PathBuf],
+    combine: bool,
+    input_name: &str,
+    base_dir: &Path,
+) -> Result<Vec<MarkdownFile>, String> {
+    if !combine {
+        let markdown = read_input(inputs.first().cloned())?;
+        return Ok(vec![MarkdownFile {
+            path: input_name.to_string(),
+            markdown,
+        }]);
+    }
+    inputs
+        .iter()
+        .map(|path| {
+            let markdown = fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read file '{}': {}", path.display(), e))?;
+            Ok(MarkdownFile {
+                path: relative_path(path, base_dir),
+                markdown,
+            })
+        })
+        .collect()
+}
+
+/// Read the preamble or template file, if there is one.
+fn read_typst(path: &Option<PathBuf>) -> Result<Option<String>, String> {
+    path.as_ref()
+        .map(|path| {
+            fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read file '{}': {}", path.display(), e))
+        })
+        .transpose()
+}
+
+/// Convert the document to `args.output` whenever one of its files changes, until interrupted.
+fn watch(
+    args: &Args,
+    combine: bool,
+    input_name: &str,
+    base_dir: &Path,
+    flags: &Flags,
+    config_path: Option<&Path>,
+) -> ! {
+    let output = args.output.clone().unwrap_or_default();
+    // Keeps the parsed document and Typst's caches, so only what changed is compiled again
+    let mut compiler = PdfCompiler::new();
+    let mut theme = None;
+    let mut images = Vec::new();
+    loop {
+        let started = Instant::now();
+        let inputs = input_files(args).unwrap_or_default();
+        let result = (|| {
+            let config = MdpdfConfig {
+                base_dir: Some(base_dir.to_string_lossy().to_string()),
+                ..load_config(args, config_path, flags).map_err(|e| format!("Error: {e}"))?
+            };
+            theme = config.theme.clone();
+            let files = read_files(&inputs, combine, input_name, base_dir)
+                .map_err(|e| format!("Error: {e}"))?;
+            let (pdf, local_files) = convert(
+                &files,
+                combine,
+                &config,
+                input_name,
+                base_dir,
+                flags,
+                &mut compiler,
+            )?;
+            images = local_files;
+            write_output(Some(output.clone()), &pdf).map_err(|e| format!("Error: {e}"))
+        })();
+        match result {
+            Ok(()) => eprintln!(
+                "Wrote {} in {:.2}s",
+                output.display(),
+                started.elapsed().as_secs_f64()
+            ),
+            Err(e) => eprintln!("{}", e),
+        }
+        // Forget what Typst cached for versions of the document that are long gone
+        comemo::evict(10);
+
+        let mut watched = inputs;
+        watched.extend(
+            [&args.summary, &args.preamble, &args.template]
+                .into_iter()
+                .flatten()
+                .cloned(),
+        );
+        watched.extend(config_path.map(Path::to_path_buf));
+        if let Some(theme) = &theme
+            && Path::new(theme).is_file()
+        {
+            watched.push(PathBuf::from(theme));
+        }
+        // The images of the last successful conversion
+        watched.extend(images.iter().cloned());
+        let changed = wait_for_change(&watched);
+        eprintln!("\n{} changed, converting again", changed.display());
+    }
+}
+
+/// Wait until one of the files is modified, created or deleted, and return it. Checking the
+/// modification times a few times a second is plenty for the handful of files in a document.
+fn wait_for_change(paths: &[PathBuf]) -> PathBuf {
+    let modified = |path: &PathBuf| fs::metadata(path).and_then(|m| m.modified()).ok();
+    let before: Vec<_> = paths.iter().map(modified).collect();
+    loop {
+        thread::sleep(Duration::from_millis(200));
+        if let Some(path) = paths
+            .iter()
+            .zip(&before)
+            .find_map(|(path, before)| (modified(path) != *before).then_some(path))
+        {
+            // Editors often save a file in several steps
+            thread::sleep(Duration::from_millis(50));
+            return path.clone();
+        }
+    }
+}
+
+/// A file converted with --out-dir
+struct BatchResult {
+    input: PathBuf,
+    output: PathBuf,
+    duration: Duration,
+    error: Option<String>,
+}
+
+/// Convert each input to its own PDF in `out_dir`, `jobs` at a time, and print a summary.
+/// Returns whether every file was converted.
+fn convert_batch(
+    inputs: &[PathBuf],
+    out_dir: &Path,
+    jobs: usize,
+    base_dir: Option<&Path>,
+    config: &MdpdfConfig,
+    flags: &Flags,
+) -> bool {
+    let root = common_directory(inputs);
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::new());
+    let started = Instant::now();
+
+    // The workers share the fonts, which are only loaded once, and the image cache
+    let fonts = LoadedFonts::load(config);
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, inputs.len()) {
+            scope.spawn(|| {
+                while let Some(input) = inputs.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let output = out_dir.join(batch_output_path(input, &root));
+                    let started = Instant::now();
+                    let error = convert_file(input, &output, base_dir, config, flags, &fonts).err();
+                    let result = BatchResult {
+                        input: input.clone(),
+                        output,
+                        duration: started.elapsed(),
+                        error,
+                    };
+                    results.lock().unwrap().push(result);
+                }
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by(|a, b| a.input.cmp(&b.input));
+    print_summary(&results, started.elapsed());
+    results.iter().all(|result| result.error.is_none())
+}
+
+/// Convert one file with --out-dir. Without a `base_dir`, images are loaded relative to the
+/// file.
+fn convert_file(
+    input: &Path,
+    output: &Path,
+    base_dir: Option<&Path>,
+    config: &MdpdfConfig,
+    flags: &Flags,
+    fonts: &LoadedFonts,
+) -> Result<(), String> {
+    let markdown = fs::read_to_string(input)
+        .map_err(|e| format!("Failed to read file '{}': {}", input.display(), e))?;
+    let base_dir = base_dir
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| default_base_dir(Some(&input.to_path_buf())));
+    let config = MdpdfConfig {
+        base_dir: Some(base_dir.to_string_lossy().to_string()),
+        ..config.clone()
+    };
+    let file = MarkdownFile {
+        path: input.display().to_string(),
+        markdown,
+    };
+    let (pdf, _) = convert(
+        &[file],
+        false,
+        &config,
+        &input.display().to_string(),
+        &base_dir,
+        flags,
+        &mut PdfCompiler::with_fonts(fonts.clone()),
+    )?;
+
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create directory '{}': {}", parent.display(), e))?;
+    }
+    write_output(Some(output.to_path_buf()), &pdf)
+}
+
+/// The deepest directory containing all the inputs, whose structure the output directory
+/// mirrors.
+fn common_directory(inputs: &[PathBuf]) -> PathBuf {
+    let mut common: Option<PathBuf> = None;
+    for input in inputs {
+        let Some(directory) = fs::canonicalize(input)
+            .ok()
+            .and_then(|path| path.parent().map(Path::to_path_buf))
+        else {
+            continue;
+        };
+        common = Some(match common {
+            None => directory,
+            Some(common) => common
+                .components()
+                .zip(directory.components())
+                .take_while(|(a, b)| a == b)
+                .map(|(component, _)| component)
+                .collect(),
+        });
+    }
+    common.unwrap_or_default()
+}
+
+/// Where an input's PDF goes in the output directory.
+fn batch_output_path(input: &Path, root: &Path) -> PathBuf {
+    fs::canonicalize(input)
+        .ok()
+        .and_then(|path| path.strip_prefix(root).ok().map(Path::to_path_buf))
+        .unwrap_or_else(|| PathBuf::from(input.file_name().unwrap_or_default()))
+        .with_extension("pdf")
+}
+
+/// Print a table of the converted files and how long they took to stderr.
+fn print_summary(results: &[BatchResult], duration: Duration) {
+    eprintln!();
+    eprintln!("{:<6}  {:>8}  File", "Status", "Time");
+    for result in results {
+        let time = format!("{:.2}s", result.duration.as_secs_f64());
+        match &result.error {
+            None => eprintln!(
+                "{:<6}  {:>8}  {} -> {}",
+                "ok",
+                time,
+                result.input.display(),
+                result.output.display()
+            ),
+            Some(error) => eprintln!(
+                "{:<6}  {:>8}  {}: {}",
+                "failed",
+                time,
+                result.input.display(),
+                error
+            ),
+        }
+    }
+    let failed = results
+        .iter()
+        .filter(|result| result.error.is_some())
+        .count();
+    eprintln!(
+        "{} converted, {} failed in {:.2}s",
+        results.len() - failed,
+        failed,
+        duration.as_secs_f64()
+    );
+}
+
 fn main() {
     let args = Args::parse();
 
-    // Read input
-    let markdown = match read_input(args.input) {
-        Ok(content) => content,
+    let inputs = match input_files(&args) {
+        Ok(inputs) => inputs,
         Err(e) => {
             eprintln!("Error: {}", e);
             process::exit(1);
         }
     };
+    if args.out_dir.is_some()
+        && (inputs.is_empty() || inputs.iter().any(|input| input.to_string_lossy() == "-"))
+    {
+        eprintln!("Error: --out-dir needs input files, and can't read from stdin");
+        process::exit(1);
+    }
+    if args.watch && inputs.iter().all(|input| input.to_string_lossy() == "-") {
+        eprintln!("Error: --watch needs input files, and can't read from stdin");
+        process::exit(1);
+    }
+    let multiple_files = args.out_dir.is_none() && (inputs.len() > 1 || args.summary.is_some());
 
-    // Parse page size
-    let page_size = match args.page_size.to_lowercase().as_str() {
-        "letter" => mdpdf::config::PageSize::Letter,
-        "legal" => mdpdf::config::PageSize::Legal,
-        "a4" => mdpdf::config::PageSize::A4,
-        custom if custom.contains('x') => {
-            // Parse custom page size
-            match parse_custom_page_size(custom) {
-                Ok(size) => size,
-                Err(e) => {
-                    eprintln!("Error: {}", e);
-                    process::exit(1);
-                }
-            }
-        }
-        _ => {
-            eprintln!(
-                "Error: Invalid page size '{}'. Use 'letter', 'legal', 'a4', or custom format like '8.5inx11in'",
-                args.page_size
-            );
+    let flags = match Flags::parse(&args) {
+        Ok(flags) => flags,
+        Err(e) => {
+            eprintln!("Error: {}", e);
             process::exit(1);
         }
     };
-
-    // Parse margin
-    let margin_size = match parse_margin(&args.margin) {
-        Ok(size) => size,
+    let config_path = config_path(&args, &inputs);
+    let mut config = match load_config(&args, config_path.as_deref(), &flags) {
+        Ok(config) => config,
         Err(e) => {
             eprintln!("Error: {}", e);
             process::exit(1);
         }
     };
 
-    // Create configuration
-    let config = MdpdfConfig {
-        page_size: Some(page_size),
-        margins: Some(mdpdf::config::Margins {
-            top: margin_size,
-            bottom: margin_size,
-            left: margin_size,
-            right: margin_size,
-        }),
-        font_family: Some("Libertinus Serif".to_string()),
-        font_size: Some(args.font_size),
-        header: None,
-        footer: None,
-        image_handling: Some(mdpdf::config::ImageHandlingConfig::default()),
+    // The base directory from the flag or the config file, or the input's directory
+    let explicit_base_dir = args.base_dir.clone().or_else(|| config.base_dir_path());
+    let base_dir = explicit_base_dir
+        .clone()
+        .unwrap_or_else(|| match &args.summary {
+            Some(summary) => default_base_dir(Some(summary)),
+            // Several files link to each other relative to the current directory
+            None if multiple_files => PathBuf::from("."),
+            None => default_base_dir(inputs.first()),
+        });
+    config.base_dir = Some(base_dir.to_string_lossy().to_string());
+
+    let input_name = match (&args.summary, inputs.first()) {
+        (Some(summary), _) => summary.display().to_string(),
+        (None, Some(path)) if path.to_string_lossy() != "-" => path.display().to_string(),
+        _ => "<stdin>".to_string(),
     };
 
-    // Convert markdown to PDF
-    let (typst_code, image_files) = match markdown_to_typst(&markdown, &config) {
-        Ok(result) => result,
+    if let Some(out_dir) = &args.out_dir {
+        // The files share an image cache, so each image is only downloaded once
+        let cache = tempfile::tempdir();
+        let mut config = config;
+        if let (Ok(cache), Some(image_handling)) = (&cache, &mut config.image_handling)
+            && image_handling.cache_directory.is_none()
+        {
+            image_handling.cache_directory = Some(cache.path().to_string_lossy().to_string());
+        }
+        let jobs = args
+            .jobs
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |jobs| jobs.get()));
+        if !convert_batch(
+            &inputs,
+            out_dir,
+            jobs,
+            explicit_base_dir.as_deref(),
+            &config,
+            &flags,
+        ) {
+            process::exit(1);
+        }
+        return;
+    }
+
+    if args.watch {
+        watch(
+            &args,
+            multiple_files,
+            &input_name,
+            &base_dir,
+            &flags,
+            config_path.as_deref(),
+        );
+    }
+
+    // Read input
+    let files = match read_files(&inputs, multiple_files, &input_name, &base_dir) {
+        Ok(files) => files,
         Err(e) => {
-            eprintln!("Error converting markdown to Typst: {}", e);
+            eprintln!("Error: {}", e);
             process::exit(1);
         }
     };
 
-    let pdf_bytes: Vec<u8> = match typst_to_pdf(&typst_code, &config, image_files) {
-        Ok(bytes) => bytes,
+    // Convert markdown to PDF
+    let mut compiler = PdfCompiler::new();
+    let pdf = match convert(
+        &files,
+        multiple_files,
+        &config,
+        &input_name,
+        &base_dir,
+        &flags,
+        &mut compiler,
+    ) {
+        Ok((pdf, _)) => pdf,
         Err(e) => {
-            eprintln!("Error converting Typst to PDF: {}", e);
+            eprintln!("{}", e);
             process::exit(1);
         }
     };
 
     // Write output
-    if let Err(e) = write_output(args.output, &pdf_bytes) {
+    if let Err(e) = write_output(args.output, &pdf) {
         eprintln!("Error: {}", e);
         process::exit(1);
     }
@@ -0,0 +1,1051 @@
+// Translation of LaTeX math into Typst math syntax.
+//
+// This covers the subset of TeX that shows up in Markdown documents: fractions, roots,
+// sub/superscripts, Greek letters, common operators and relations, accents, font commands,
+// `\text{}`, `\left`/`\right` and the usual matrix/cases/align environments.
+//
+// Anything we don't recognise makes the whole translation fail (`None`), so callers can fall
+// back to rendering the raw TeX source instead of producing broken Typst code.
+
+use crate::is_rtl_character;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Command(String),
+    Char(char),
+    Space,
+    Open,
+    Close,
+    Sub,
+    Sup,
+    Align,
+    NewRow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stop {
+    // End of input
+    End,
+    // Closing `}` of a group
+    Group,
+    // Closing `]` of an optional argument
+    Bracket,
+    // `\right` of a `\left ... \right` pair
+    Right,
+    // `\end` of an environment whose rows are rendered inline (align, gather, ...)
+    Environment,
+    // `&`, `\\` or `\end` of a matrix-like environment
+    Cell,
+}
+
+/// How deeply groups and command arguments can be nested. Deeper input isn't translated, so it
+/// can't overflow the stack.
+const MAX_NESTING: usize = 256;
+
+/// Translate TeX math source into Typst math markup (without the surrounding `$`).
+///
+/// Returns `None` if the source uses a construct that can't be translated.
+pub fn tex_to_typst(tex: &str) -> Option<String> {
+    if tex.chars().any(is_rtl_character) {
+        return None;
+    }
+    let tokens = tokenize(tex)?;
+    let mut parser = TexParser {
+        tokens,
+        pos: 0,
+        arg_depth: 0,
+        depth: 0,
+    };
+    let result = parser.parse_sequence(Stop::End)?;
+    if result.trim().is_empty() {
+        None
+    } else {
+        Some(result)
+    }
+}
+
+fn tokenize(tex: &str) -> Option<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = tex.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                let next = chars.next()?;
+                if next.is_ascii_alphabetic() {
+                    let mut name = next.to_string();
+                    while let Some(&c) = chars.peek() {
+                        if !c.is_ascii_alphabetic() {
+                            break;
+                        }
+                        name.push(c);
+                        chars.next();
+                    }
+                    tokens.push(Token::Command(name));
+                } else if next == '\\' {
+                    tokens.push(Token::NewRow);
+                } else {
+                    tokens.push(Token::Command(next.to_string()));
+                }
+            }
+            '{' => tokens.push(Token::Open),
+            '}' => tokens.push(Token::Close),
+            '_' => tokens.push(Token::Sub),
+            '^' => tokens.push(Token::Sup),
+            '&' => tokens.push(Token::Align),
+            '%' => {
+                // Comment until the end of the line
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            c if c.is_whitespace() => {
+                if tokens.last() != Some(&Token::Space) {
+                    tokens.push(Token::Space);
+                }
+            }
+            c => tokens.push(Token::Char(c)),
+        }
+    }
+
+    Some(tokens)
+}
+
+struct TexParser {
+    tokens: Vec<Token>,
+    pos: usize,
+    // Nesting depth of Typst function call arguments. Inside of these, `,` `;` and `:` would be
+    // parsed as argument syntax, so they have to be written as symbols instead.
+    arg_depth: usize,
+    // Nesting depth of atoms and script arguments, which are parsed recursively
+    depth: usize,
+}
+
+impl TexParser {
+    fn skip_spaces(&mut self) {
+        while self.tokens.get(self.pos) == Some(&Token::Space) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<Token> {
+        self.skip_spaces();
+        self.tokens.get(self.pos).cloned()
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        self.skip_spaces();
+        self.next_raw()
+    }
+
+    fn next_raw(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        if token.is_some() {
+            self.pos += 1;
+        }
+        token
+    }
+
+    /// Run `parse` one level deeper, failing if the input is nested more than `MAX_NESTING`
+    /// levels deep.
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> Option<T>) -> Option<T> {
+        if self.depth >= MAX_NESTING {
+            return None;
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn parse_sequence(&mut self, stop: Stop) -> Option<String> {
+        let mut atoms: Vec<String> = Vec::new();
+
+        loop {
+            match self.peek() {
+                None => {
+                    if stop != Stop::End {
+                        return None;
+                    }
+                    break;
+                }
+                Some(Token::Close) => {
+                    if stop != Stop::Group {
+                        return None;
+                    }
+                    self.next();
+                    break;
+                }
+                Some(Token::Char(']')) if stop == Stop::Bracket => {
+                    self.next();
+                    break;
+                }
+                Some(Token::Command(name)) if name == "right" => {
+                    if stop != Stop::Right {
+                        return None;
+                    }
+                    break;
+                }
+                Some(Token::Command(name)) if name == "end" => {
+                    if stop != Stop::Environment && stop != Stop::Cell {
+                        return None;
+                    }
+                    break;
+                }
+                Some(Token::Align | Token::NewRow) if stop == Stop::Cell => break,
+                Some(Token::Align) => {
+                    self.next();
+                    atoms.push("&".to_string());
+                }
+                Some(Token::NewRow) => {
+                    self.next();
+                    self.skip_optional_argument()?;
+                    atoms.push("\\".to_string());
+                }
+                Some(_) => {
+                    let (atom, compound) = self.parse_atom()?;
+                    let atom = self.parse_scripts(atom, compound)?;
+                    if !atom.is_empty() {
+                        atoms.push(atom);
+                    }
+                }
+            }
+        }
+
+        // A trailing line break would escape whatever follows it (e.g. the closing `$`)
+        while atoms.last().is_some_and(|atom| atom == "\\") {
+            atoms.pop();
+        }
+
+        Some(atoms.join(" "))
+    }
+
+    /// Parse a single atom, returning its Typst code and whether it consists of multiple atoms
+    /// (which need to be grouped before attaching sub/superscripts).
+    fn parse_atom(&mut self) -> Option<(String, bool)> {
+        self.nested(|parser| match parser.next()? {
+            Token::Open => {
+                let inner = parser.parse_group()?;
+                let compound = inner.contains(' ');
+                Some((inner, compound))
+            }
+            Token::Char(c) => Some((parser.parse_char(c)?, false)),
+            Token::Command(name) => {
+                let command = parser.parse_command(&name)?;
+                Some((command, false))
+            }
+            Token::Sub | Token::Sup => {
+                // Script without a base, e.g. `{}^{14}C` or `_2`
+                parser.pos -= 1;
+                Some(("\"\"".to_string(), false))
+            }
+            _ => None,
+        })
+    }
+
+    fn parse_group(&mut self) -> Option<String> {
+        self.arg_depth += 1;
+        let inner = self.parse_sequence(Stop::Group);
+        self.arg_depth -= 1;
+        inner
+    }
+
+    fn parse_char(&mut self, c: char) -> Option<String> {
+        let atom = match c {
+            '0'..='9' => {
+                let mut number = c.to_string();
+                while let Some(Token::Char(next)) = self.tokens.get(self.pos) {
+                    let next = *next;
+                    let continues_number = next.is_ascii_digit()
+                        || (next == '.'
+                            && matches!(self.tokens.get(self.pos + 1), Some(Token::Char(d)) if d.is_ascii_digit()));
+                    if !continues_number {
+                        break;
+                    }
+                    number.push(next);
+                    self.pos += 1;
+                }
+                number
+            }
+            'a'..='z' | 'A'..='Z' => c.to_string(),
+            '+' | '-' | '=' | '<' | '>' | '(' | ')' | '[' | ']' | '|' | '!' | '.' | '?' | '*' => {
+                c.to_string()
+            }
+            ',' if self.arg_depth > 0 => "comma".to_string(),
+            ';' if self.arg_depth > 0 => "semi".to_string(),
+            ':' if self.arg_depth > 0 => "colon".to_string(),
+            ',' | ';' | ':' => c.to_string(),
+            '\'' => "prime".to_string(),
+            '/' => "slash".to_string(),
+            '~' => "space.nobreak".to_string(),
+            c if !c.is_ascii() && !c.is_control() => c.to_string(),
+            _ => return None,
+        };
+        Some(atom)
+    }
+
+    fn parse_scripts(&mut self, base: String, compound: bool) -> Option<String> {
+        let mut primes = String::new();
+        let mut sub = None;
+        let mut sup = None;
+
+        loop {
+            match self.peek() {
+                Some(Token::Char('\'')) => {
+                    self.next();
+                    primes.push('\'');
+                }
+                Some(Token::Sub) if sub.is_none() => {
+                    self.next();
+                    sub = Some(self.parse_script_argument()?);
+                }
+                Some(Token::Sup) if sup.is_none() => {
+                    self.next();
+                    sup = Some(self.parse_script_argument()?);
+                }
+                // Double sub/superscripts are an error in TeX as well
+                Some(Token::Sub | Token::Sup) => return None,
+                _ => break,
+            }
+        }
+
+        if primes.is_empty() && sub.is_none() && sup.is_none() {
+            return Some(base);
+        }
+
+        let mut result = if compound {
+            format!("lr({base})")
+        } else if base.is_empty() {
+            "\"\"".to_string()
+        } else {
+            base
+        };
+        result.push_str(&primes);
+        if let Some(sub) = sub {
+            result.push('_');
+            result.push_str(&script(&sub));
+        }
+        if let Some(sup) = sup {
+            result.push('^');
+            result.push_str(&script(&sup));
+        }
+        Some(result)
+    }
+
+    /// Parse the argument of `^` or `_`: either a group or a single token.
+    fn parse_script_argument(&mut self) -> Option<String> {
+        self.nested(|parser| match parser.next()? {
+            Token::Open => parser.parse_group(),
+            // Only the first digit is part of the script, e.g. `x_12` is `x_1 2`
+            Token::Char(c) if c.is_ascii_digit() => Some(c.to_string()),
+            Token::Char(c) => parser.parse_char(c),
+            Token::Command(name) => parser.parse_command(&name),
+            _ => None,
+        })
+    }
+
+    /// Parse a mandatory command argument that will be passed to a Typst function.
+    fn parse_argument(&mut self) -> Option<String> {
+        self.arg_depth += 1;
+        let argument = self.parse_script_argument();
+        self.arg_depth -= 1;
+        argument.map(|argument| {
+            if argument.is_empty() {
+                "\"\"".to_string()
+            } else {
+                argument
+            }
+        })
+    }
+
+    /// Parse an optional `[...]` argument, if present.
+    fn parse_optional_argument(&mut self) -> Option<Option<String>> {
+        if self.peek() != Some(Token::Char('[')) {
+            return Some(None);
+        }
+        self.next();
+        self.arg_depth += 1;
+        let argument = self.parse_sequence(Stop::Bracket);
+        self.arg_depth -= 1;
+        argument.map(Some)
+    }
+
+    /// Skip an optional `[...]` argument such as the spacing in `\\[2pt]`.
+    fn skip_optional_argument(&mut self) -> Option<()> {
+        if self.peek() != Some(Token::Char('[')) {
+            return Some(());
+        }
+        loop {
+            match self.next()? {
+                Token::Char(']') => return Some(()),
+                Token::Char(_) | Token::Space => {}
+                _ => return None,
+            }
+        }
+    }
+
+    /// Parse a group containing plain text (as in `\text{...}`) verbatim.
+    fn parse_text_argument(&mut self) -> Option<String> {
+        match self.next()? {
+            Token::Open => {}
+            Token::Char(c) => return Some(c.to_string()),
+            _ => return None,
+        }
+
+        let mut text = String::new();
+        let mut depth = 1;
+        loop {
+            match self.next_raw()? {
+                Token::Space => text.push(' '),
+                Token::Char(c) => text.push(c),
+                Token::Open => depth += 1,
+                Token::Close => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                Token::Command(name) => match name.as_str() {
+                    "$" | "%" | "&" | "_" | "{" | "}" | "#" => text.push_str(&name),
+                    " " | "," | ";" | ":" | "quad" | "qquad" => text.push(' '),
+                    _ => return None,
+                },
+                _ => return None,
+            }
+        }
+        Some(text)
+    }
+
+    fn parse_command(&mut self, name: &str) -> Option<String> {
+        if let Some(symbol) = symbol(name) {
+            return Some(symbol.to_string());
+        }
+        if let Some(function) = accent_or_style(name) {
+            let argument = self.parse_argument()?;
+            return Some(format!("{function}({argument})"));
+        }
+
+        let result = match name {
+            "frac" | "dfrac" | "tfrac" | "cfrac" => {
+                let numerator = self.parse_argument()?;
+                let denominator = self.parse_argument()?;
+                format!("frac({numerator}, {denominator})")
+            }
+            "binom" | "dbinom" | "tbinom" => {
+                let n = self.parse_argument()?;
+                let k = self.parse_argument()?;
+                format!("binom({n}, {k})")
+            }
+            "sqrt" => {
+                let index = self.parse_optional_argument()?;
+                let radicand = self.parse_argument()?;
+                match index {
+                    Some(index) if !index.is_empty() => format!("root({index}, {radicand})"),
+                    _ => format!("sqrt({radicand})"),
+                }
+            }
+            "overset" | "stackrel" => {
+                let over = self.parse_argument()?;
+                let base = self.parse_argument()?;
+                format!("limits({base})^({over})")
+            }
+            "underset" => {
+                let under = self.parse_argument()?;
+                let base = self.parse_argument()?;
+                format!("limits({base})_({under})")
+            }
+            "text" | "textrm" | "textnormal" | "mbox" | "hbox" => {
+                string_literal(&self.parse_text_argument()?)
+            }
+            "textbf" => format!("bold({})", string_literal(&self.parse_text_argument()?)),
+            "textit" | "emph" => {
+                format!("italic({})", string_literal(&self.parse_text_argument()?))
+            }
+            "texttt" => format!("mono({})", string_literal(&self.parse_text_argument()?)),
+            "textsf" => format!("sans({})", string_literal(&self.parse_text_argument()?)),
+            "operatorname" => {
+                let limits = self.peek() == Some(Token::Char('*'));
+                if limits {
+                    self.next();
+                }
+                let name = string_literal(&self.parse_text_argument()?);
+                if limits {
+                    format!("op({name}, limits: #true)")
+                } else {
+                    format!("op({name})")
+                }
+            }
+            "pmod" => format!("(mod {})", self.parse_argument()?),
+            "not" => match self.next()? {
+                Token::Char('=') => "eq.not".to_string(),
+                Token::Char('<') => "lt.not".to_string(),
+                Token::Char('>') => "gt.not".to_string(),
+                Token::Command(name) => match name.as_str() {
+                    "in" => "in.not".to_string(),
+                    "subset" => "subset.not".to_string(),
+                    "supset" => "supset.not".to_string(),
+                    "equiv" => "equiv.not".to_string(),
+                    "sim" => "tilde.not".to_string(),
+                    "exists" => "exists.not".to_string(),
+                    _ => return None,
+                },
+                _ => return None,
+            },
+            "left" => self.parse_left_right()?,
+            "begin" => self.parse_environment()?,
+            "tag" | "label" => {
+                if self.peek() == Some(Token::Char('*')) {
+                    self.next();
+                }
+                self.parse_text_argument()?;
+                String::new()
+            }
+            // Sizing and spacing hints that Typst handles on its own
+            "displaystyle" | "textstyle" | "scriptstyle" | "scriptscriptstyle" | "limits"
+            | "nolimits" | "nonumber" | "notag" | "big" | "Big" | "bigg" | "Bigg" | "bigl"
+            | "Bigl" | "biggl" | "Biggl" | "bigr" | "Bigr" | "biggr" | "Biggr" | "bigm"
+            | "Bigm" | "biggm" | "Biggm" | "!" => String::new(),
+            _ => return None,
+        };
+        Some(result)
+    }
+
+    fn parse_left_right(&mut self) -> Option<String> {
+        let left = self.parse_delimiter()?;
+        self.arg_depth += 1;
+        let body = self.parse_sequence(Stop::Right);
+        self.arg_depth -= 1;
+        let body = body?;
+        if self.next()? != Token::Command("right".to_string()) {
+            return None;
+        }
+        let right = self.parse_delimiter()?;
+
+        let parts: Vec<&str> = [left, body.as_str(), right]
+            .into_iter()
+            .filter(|part| !part.is_empty())
+            .collect();
+        Some(format!("lr({})", parts.join(" ")))
+    }
+
+    fn parse_delimiter(&mut self) -> Option<&'static str> {
+        let delimiter = match self.next()? {
+            Token::Char('(') => "paren.l",
+            Token::Char(')') => "paren.r",
+            Token::Char('[') => "bracket.l",
+            Token::Char(']') => "bracket.r",
+            Token::Char('|') => "bar.v",
+            Token::Char('<') => "angle.l",
+            Token::Char('>') => "angle.r",
+            Token::Char('/') => "slash",
+            Token::Char('.') => "",
+            Token::Command(name) => match name.as_str() {
+                "{" | "lbrace" => "brace.l",
+                "}" | "rbrace" => "brace.r",
+                "|" | "Vert" | "lVert" | "rVert" => "bar.v.double",
+                "vert" | "lvert" | "rvert" => "bar.v",
+                "langle" => "angle.l",
+                "rangle" => "angle.r",
+                "lfloor" => "floor.l",
+                "rfloor" => "floor.r",
+                "lceil" => "ceil.l",
+                "rceil" => "ceil.r",
+                "lbrack" => "bracket.l",
+                "rbrack" => "bracket.r",
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some(delimiter)
+    }
+
+    fn parse_environment_name(&mut self) -> Option<String> {
+        if self.next()? != Token::Open {
+            return None;
+        }
+        let mut name = String::new();
+        loop {
+            match self.next()? {
+                Token::Char(c) if c.is_ascii_alphabetic() || c == '*' => name.push(c),
+                Token::Close => return Some(name),
+                _ => return None,
+            }
+        }
+    }
+
+    fn parse_environment(&mut self) -> Option<String> {
+        let name = self.parse_environment_name()?;
+
+        let result = match name.as_str() {
+            "matrix" | "smallmatrix" => self.parse_matrix("#none")?,
+            "pmatrix" => self.parse_matrix("\"(\"")?,
+            "bmatrix" => self.parse_matrix("\"[\"")?,
+            "Bmatrix" => self.parse_matrix("\"{\"")?,
+            "vmatrix" => self.parse_matrix("\"|\"")?,
+            "Vmatrix" => self.parse_matrix("\"||\"")?,
+            "array" => {
+                // Column specification, e.g. `{cc|c}`
+                self.parse_text_argument()?;
+                self.parse_matrix("#none")?
+            }
+            "cases" | "dcases" => {
+                let rows = self.parse_rows()?;
+                let rows: Vec<String> = rows.iter().map(|row| row.join(" & ")).collect();
+                format!("cases({})", rows.join(", "))
+            }
+            "alignat" | "alignat*" | "alignedat" => {
+                // Number of columns
+                self.parse_text_argument()?;
+                self.parse_sequence(Stop::Environment)?
+            }
+            "align" | "align*" | "aligned" | "split" | "gather" | "gather*" | "gathered"
+            | "equation" | "equation*" | "multline" | "multline*" | "eqnarray" | "eqnarray*"
+            | "flalign" | "flalign*" => self.parse_sequence(Stop::Environment)?,
+            _ => return None,
+        };
+
+        if self.next()? != Token::Command("end".to_string()) {
+            return None;
+        }
+        if self.parse_environment_name()? != name {
+            return None;
+        }
+        Some(result)
+    }
+
+    fn parse_matrix(&mut self, delim: &str) -> Option<String> {
+        let rows = self.parse_rows()?;
+        let rows: Vec<String> = rows
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|cell| {
+                        if cell.is_empty() {
+                            "\"\"".to_string()
+                        } else {
+                            cell.clone()
+                        }
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            })
+            .collect();
+        Some(format!("mat(delim: {delim}, {})", rows.join("; ")))
+    }
+
+    fn parse_rows(&mut self) -> Option<Vec<Vec<String>>> {
+        let mut rows = Vec::new();
+        let mut row = Vec::new();
+
+        self.arg_depth += 1;
+        loop {
+            let Some(cell) = self.parse_sequence(Stop::Cell) else {
+                self.arg_depth -= 1;
+                return None;
+            };
+            row.push(cell);
+            match self.peek() {
+                Some(Token::Align) => {
+                    self.next();
+                }
+                Some(Token::NewRow) => {
+                    self.next();
+                    self.skip_optional_argument()?;
+                    rows.push(std::mem::take(&mut row));
+                }
+                _ => {
+                    rows.push(std::mem::take(&mut row));
+                    break;
+                }
+            }
+        }
+        self.arg_depth -= 1;
+
+        // A trailing `\\` before `\end` doesn't start a new row
+        if rows.len() > 1
+            && rows
+                .last()
+                .is_some_and(|row| row.iter().all(|cell| cell.is_empty()))
+        {
+            rows.pop();
+        }
+        Some(rows)
+    }
+}
+
+/// Wrap a sub/superscript in parentheses unless it's a single identifier or number.
+fn script(content: &str) -> String {
+    if !content.is_empty()
+        && content.chars().all(|c| c.is_alphanumeric() || c == '.')
+        && !content.starts_with('.')
+        && !content.ends_with('.')
+    {
+        content.to_string()
+    } else {
+        format!("({content})")
+    }
+}
+
+fn string_literal(text: &str) -> String {
+    let escaped = text
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('$', "\\u{24}");
+    format!("\"{escaped}\"")
+}
+
+/// Commands that take a single argument and map onto a Typst function.
+fn accent_or_style(name: &str) -> Option<&'static str> {
+    let function = match name {
+        // Accents
+        "hat" | "widehat" => "hat",
+        "tilde" | "widetilde" => "tilde",
+        "bar" => "macron",
+        "vec" | "overrightarrow" => "arrow",
+        "overleftarrow" => "arrow.l",
+        "dot" => "dot",
+        "ddot" => "dot.double",
+        "dddot" => "dot.triple",
+        "acute" => "acute",
+        "grave" => "grave",
+        "breve" => "breve",
+        "check" => "caron",
+        "mathring" => "circle",
+        "overline" => "overline",
+        "underline" => "underline",
+        "overbrace" => "overbrace",
+        "underbrace" => "underbrace",
+        "cancel" => "cancel",
+        "phantom" => "hide",
+        // Font styles
+        "mathbf" | "boldsymbol" | "bm" => "bold",
+        "mathit" => "italic",
+        "mathrm" | "mathup" => "upright",
+        "mathbb" => "bb",
+        "mathcal" => "cal",
+        "mathfrak" => "frak",
+        "mathsf" => "sans",
+        "mathtt" => "mono",
+        _ => return None,
+    };
+    Some(function)
+}
+
+/// Commands that map directly onto a Typst symbol or operator.
+fn symbol(name: &str) -> Option<&'static str> {
+    let symbol = match name {
+        // Lowercase Greek letters
+        "alpha" => "alpha",
+        "beta" => "beta",
+        "gamma" => "gamma",
+        "delta" => "delta",
+        "epsilon" => "epsilon.alt",
+        "varepsilon" => "epsilon",
+        "zeta" => "zeta",
+        "eta" => "eta",
+        "theta" => "theta",
+        "vartheta" => "theta.alt",
+        "iota" => "iota",
+        "kappa" => "kappa",
+        "varkappa" => "kappa.alt",
+        "lambda" => "lambda",
+        "mu" => "mu",
+        "nu" => "nu",
+        "xi" => "xi",
+        "omicron" => "omicron",
+        "pi" => "pi",
+        "varpi" => "pi.alt",
+        "rho" => "rho",
+        "varrho" => "rho.alt",
+        "sigma" => "sigma",
+        "varsigma" => "sigma.alt",
+        "tau" => "tau",
+        "upsilon" => "upsilon",
+        "phi" => "phi.alt",
+        "varphi" => "phi",
+        "chi" => "chi",
+        "psi" => "psi",
+        "omega" => "omega",
+        // Uppercase Greek letters
+        "Gamma" => "Gamma",
+        "Delta" => "Delta",
+        "Theta" => "Theta",
+        "Lambda" => "Lambda",
+        "Xi" => "Xi",
+        "Pi" => "Pi",
+        "Sigma" => "Sigma",
+        "Upsilon" => "Upsilon",
+        "Phi" => "Phi",
+        "Psi" => "Psi",
+        "Omega" => "Omega",
+        // Binary operators
+        "times" => "times",
+        "div" => "div",
+        "pm" => "plus.minus",
+        "mp" => "minus.plus",
+        "cdot" => "dot.op",
+        "ast" => "ast.op",
+        "star" => "star.op",
+        "circ" => "compose",
+        "bullet" => "bullet",
+        "oplus" => "plus.circle",
+        "ominus" => "minus.circle",
+        "otimes" => "times.circle",
+        "odot" => "dot.circle",
+        "cup" => "union",
+        "cap" => "sect",
+        "setminus" => "without",
+        "wedge" | "land" => "and",
+        "vee" | "lor" => "or",
+        "neg" | "lnot" => "not",
+        "bmod" | "mod" => "mod",
+        // Relations
+        "leq" | "le" => "lt.eq",
+        "geq" | "ge" => "gt.eq",
+        "neq" | "ne" => "eq.not",
+        "ll" => "lt.double",
+        "gg" => "gt.double",
+        "approx" => "approx",
+        "equiv" => "equiv",
+        "cong" => "tilde.equiv",
+        "sim" => "tilde.op",
+        "simeq" => "tilde.eq",
+        "propto" => "prop",
+        "perp" => "perp",
+        "parallel" => "parallel",
+        "mid" => "divides",
+        "in" => "in",
+        "notin" => "in.not",
+        "ni" => "in.rev",
+        "subset" => "subset",
+        "subseteq" => "subset.eq",
+        "supset" => "supset",
+        "supseteq" => "supset.eq",
+        "coloneqq" => "colon.eq",
+        // Arrows
+        "to" | "rightarrow" => "arrow.r",
+        "gets" | "leftarrow" => "arrow.l",
+        "leftrightarrow" => "arrow.l.r",
+        "Rightarrow" => "arrow.r.double",
+        "Leftarrow" => "arrow.l.double",
+        "Leftrightarrow" => "arrow.l.r.double",
+        "iff" => "arrow.l.r.double.long",
+        "implies" => "arrow.r.double.long",
+        "impliedby" => "arrow.l.double.long",
+        "longrightarrow" => "arrow.r.long",
+        "longleftarrow" => "arrow.l.long",
+        "longleftrightarrow" => "arrow.l.r.long",
+        "mapsto" => "arrow.r.bar",
+        "uparrow" => "arrow.t",
+        "downarrow" => "arrow.b",
+        "Uparrow" => "arrow.t.double",
+        "Downarrow" => "arrow.b.double",
+        // Big operators
+        "sum" => "sum",
+        "prod" => "product",
+        "coprod" => "product.co",
+        "int" => "integral",
+        "iint" => "integral.double",
+        "iiint" => "integral.triple",
+        "oint" => "integral.cont",
+        "bigcup" => "union.big",
+        "bigcap" => "sect.big",
+        "bigoplus" => "plus.circle.big",
+        "bigotimes" => "times.circle.big",
+        "bigwedge" => "and.big",
+        "bigvee" => "or.big",
+        // Function names
+        "sin" => "sin",
+        "cos" => "cos",
+        "tan" => "tan",
+        "cot" => "cot",
+        "sec" => "sec",
+        "csc" => "csc",
+        "arcsin" => "arcsin",
+        "arccos" => "arccos",
+        "arctan" => "arctan",
+        "sinh" => "sinh",
+        "cosh" => "cosh",
+        "tanh" => "tanh",
+        "coth" => "coth",
+        "log" => "log",
+        "ln" => "ln",
+        "lg" => "lg",
+        "exp" => "exp",
+        "lim" => "lim",
+        "liminf" => "liminf",
+        "limsup" => "limsup",
+        "max" => "max",
+        "min" => "min",
+        "sup" => "sup",
+        "inf" => "inf",
+        "det" => "det",
+        "dim" => "dim",
+        "ker" => "ker",
+        "gcd" => "gcd",
+        "deg" => "deg",
+        "arg" => "arg",
+        "hom" => "hom",
+        "Pr" => "Pr",
+        // Delimiters
+        "langle" => "angle.l",
+        "rangle" => "angle.r",
+        "lfloor" => "floor.l",
+        "rfloor" => "floor.r",
+        "lceil" => "ceil.l",
+        "rceil" => "ceil.r",
+        "lbrace" | "{" => "brace.l",
+        "rbrace" | "}" => "brace.r",
+        "vert" | "lvert" | "rvert" => "bar.v",
+        "Vert" | "lVert" | "rVert" | "|" => "bar.v.double",
+        // Dots
+        "ldots" | "dots" | "dotsc" | "dotsb" => "dots.h",
+        "cdots" => "dots.c",
+        "vdots" => "dots.v",
+        "ddots" => "dots.down",
+        // Other symbols
+        "infty" => "infinity",
+        "partial" => "diff",
+        "nabla" => "nabla",
+        "forall" => "forall",
+        "exists" => "exists",
+        "nexists" => "exists.not",
+        "emptyset" | "varnothing" => "emptyset",
+        "ell" => "ell",
+        "hbar" => "planck.reduce",
+        "Re" => "Re",
+        "Im" => "Im",
+        "aleph" => "aleph",
+        "angle" => "angle",
+        "triangle" => "triangle.t",
+        "prime" => "prime",
+        "degree" => "degree",
+        "top" => "top",
+        "bot" => "bot",
+        "dagger" => "dagger",
+        "therefore" => "therefore",
+        "because" => "because",
+        "colon" => "colon",
+        "checkmark" => "checkmark",
+        // Spacing
+        "," | "thinspace" => "thin",
+        ":" | ">" | "medspace" => "med",
+        ";" | "thickspace" => "thick",
+        " " => "space",
+        "quad" => "quad",
+        "qquad" => "wide",
+        // Escaped characters
+        "%" => "%",
+        "$" => "\\$",
+        "#" => "\\#",
+        "&" => "\\&",
+        "_" => "\\_",
+        _ => return None,
+    };
+    Some(symbol)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_basic_expressions() {
+        assert_eq!(tex_to_typst("x^2 + y^2 = z^2").unwrap(), "x^2 + y^2 = z^2");
+        assert_eq!(tex_to_typst("a_{ij}").unwrap(), "a_(i j)");
+        assert_eq!(tex_to_typst("x_1^{n+1}").unwrap(), "x_1^(n + 1)");
+        assert_eq!(tex_to_typst("3.14 r").unwrap(), "3.14 r");
+        assert_eq!(tex_to_typst("f'(x)").unwrap(), "f' ( x )");
+    }
+
+    #[test]
+    fn test_commands() {
+        assert_eq!(
+            tex_to_typst(r"\frac{1}{2}\alpha").unwrap(),
+            "frac(1, 2) alpha"
+        );
+        assert_eq!(
+            tex_to_typst(r"\sqrt{3x-1}+(1+x)^2").unwrap(),
+            "sqrt(3 x - 1) + ( 1 + x )^2"
+        );
+        assert_eq!(tex_to_typst(r"\sqrt[3]{x}").unwrap(), "root(3, x)");
+        assert_eq!(
+            tex_to_typst(r"\sum_{k=1}^n k \leq \infty").unwrap(),
+            "sum_(k = 1)^n k lt.eq infinity"
+        );
+        assert_eq!(
+            tex_to_typst(r"\mathbb{R} \to \mathbf{v}").unwrap(),
+            "bb(R) arrow.r bold(v)"
+        );
+        assert_eq!(
+            tex_to_typst(r"\frac{a, b}{c}").unwrap(),
+            "frac(a comma b, c)"
+        );
+    }
+
+    #[test]
+    fn test_text() {
+        assert_eq!(
+            tex_to_typst(r#"x \text{ if "y" } y"#).unwrap(),
+            r#"x " if \"y\" " y"#
+        );
+        assert_eq!(
+            tex_to_typst(r"\operatorname{argmax}_x").unwrap(),
+            r#"op("argmax")_x"#
+        );
+    }
+
+    #[test]
+    fn test_left_right() {
+        assert_eq!(
+            tex_to_typst(r"\left( \sum_{k=1}^n a_k b_k \right)^2").unwrap(),
+            "lr(paren.l sum_(k = 1)^n a_k b_k paren.r)^2"
+        );
+        assert_eq!(tex_to_typst(r"\left\{ x \right.").unwrap(), "lr(brace.l x)");
+    }
+
+    #[test]
+    fn test_environments() {
+        assert_eq!(
+            tex_to_typst(r"\begin{pmatrix} a & b \\ c & d \end{pmatrix}").unwrap(),
+            "mat(delim: \"(\", a, b; c, d)"
+        );
+        assert_eq!(
+            tex_to_typst(r"\begin{bmatrix} 1 & 0 \\ 0 & 1 \\ \end{bmatrix}").unwrap(),
+            "mat(delim: \"[\", 1, 0; 0, 1)"
+        );
+        assert_eq!(
+            tex_to_typst(r"|x| = \begin{cases} x & x \geq 0 \\ -x & \text{otherwise} \end{cases}")
+                .unwrap(),
+            "| x | = cases(x & x gt.eq 0, - x & \"otherwise\")"
+        );
+        assert_eq!(
+            tex_to_typst(r"\begin{align} a &= b \\ &= c \\ \end{align}").unwrap(),
+            "a & = b \\ & = c"
+        );
+    }
+
+    #[test]
+    fn test_untranslatable() {
+        assert_eq!(tex_to_typst(r"\unknowncommand{x}"), None);
+        assert_eq!(tex_to_typst(r"\frac{1}{2"), None);
+        assert_eq!(tex_to_typst(r"x}"), None);
+        assert_eq!(tex_to_typst(r"\begin{tikzcd} a \end{tikzcd}"), None);
+        assert_eq!(tex_to_typst(r"\left( x"), None);
+        assert_eq!(tex_to_typst("-* !0`"), None);
+        assert_eq!(tex_to_typst("x^2^3"), None);
+        assert_eq!(tex_to_typst("مرحبا"), None);
+        assert_eq!(tex_to_typst("   "), None);
+    }
+
+    #[test]
+    fn test_deep_nesting() {
+        let nested = |depth: usize| format!("{}x{}", "{".repeat(depth), "}".repeat(depth));
+        assert_eq!(tex_to_typst(&nested(100)).unwrap(), "x");
+        // Deeper input isn't translated, instead of overflowing the stack
+        assert_eq!(tex_to_typst(&nested(10000)), None);
+        assert_eq!(tex_to_typst(&format!("{}x", r"\sqrt".repeat(10000))), None);
+    }
+}
@@ -1,24 +1,45 @@
-use crate::config::MdpdfConfig;
+use crate::config::{MdpdfConfig, TocConfig};
+use crate::diagnostics::{Diagnostic, SourceMap};
+use crate::error::Result;
 use crate::typst::MdpdfWorld;
-use anyhow::Result;
 use std::collections::HashMap;
 
 pub struct TypstCompiler;
 
 impl TypstCompiler {
+    /// Compile the generated `body` in the document template to PDF. `source_map` maps offsets
+    /// in the body to the Markdown it was generated from.
     pub fn compile_to_pdf(
-        typst_code: String,
+        body: &str,
         config: MdpdfConfig,
         image_files: HashMap<String, Vec<u8>>,
-    ) -> Result<Vec<u8>> {
-        // Create a Typst world with the configuration, main Typst code, and image files
-        let world = MdpdfWorld::new(config, typst_code, image_files);
-        world.compile_to_pdf()
+        source_map: Option<&SourceMap>,
+    ) -> Result<(Vec<u8>, Vec<Diagnostic>)> {
+        // Create a Typst world with the configuration and image files, then add the document
+        let mut world = MdpdfWorld::new(config, String::new(), image_files);
+        let (document, body_start) = world.document(body)?;
+        world.set_main(&document);
+
+        // Offsets in the full document are shifted by the template
+        let locate = |offset: usize| source_map?.locate(offset.checked_sub(body_start)?);
+        world.compile_to_pdf(&locate)
     }
 
-    pub fn create_document_template(config: &MdpdfConfig) -> String {
+    #[cfg(test)]
+    pub fn create_document_template(config: &MdpdfConfig) -> Result<String> {
         // Use an empty main_code and files for template generation
         let world = MdpdfWorld::new(config.clone(), String::new(), HashMap::new());
         world.create_document_template()
     }
+
+    /// The full Typst document for the generated `body`, and the offset where the body starts.
+    #[cfg(any(test, feature = "node"))]
+    pub fn create_document(config: &MdpdfConfig, body: &str) -> Result<(String, usize)> {
+        let world = MdpdfWorld::new(config.clone(), String::new(), HashMap::new());
+        world.document(body)
+    }
+
+    pub fn outline(toc: &TocConfig) -> String {
+        MdpdfWorld::outline(toc)
+    }
 }
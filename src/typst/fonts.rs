@@ -0,0 +1,184 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use typst::foundations::Bytes;
+use typst::text::{Font, FontBook, FontInfo};
+use typst::utils::LazyHash;
+
+use crate::diagnostics::{Diagnostic, DiagnosticCategory};
+
+/// The fonts available to a document, in the order they were added.
+///
+/// Cloning shares the font data and the parsed fonts, so fonts can be loaded once and then
+/// reused by every document.
+#[derive(Clone, Default)]
+pub struct Fonts {
+    pub book: FontBook,
+    pub slots: Vec<FontSlot>,
+    /// Fonts that couldn't be loaded
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// A font face, which is only parsed once it's used.
+#[derive(Clone)]
+pub struct FontSlot {
+    source: FontSource,
+    index: u32,
+    font: Arc<OnceLock<Option<Font>>>,
+}
+
+#[derive(Clone)]
+enum FontSource {
+    Data(Bytes),
+    /// System fonts are only read if they're used
+    #[cfg_attr(not(feature = "system-fonts"), allow(dead_code))]
+    File(PathBuf),
+}
+
+impl FontSlot {
+    pub fn get(&self) -> Option<Font> {
+        self.font
+            .get_or_init(|| {
+                let data = match &self.source {
+                    FontSource::Data(data) => data.clone(),
+                    FontSource::File(path) => Bytes::new(std::fs::read(path).ok()?),
+                };
+                Font::new(data, self.index)
+            })
+            .clone()
+    }
+}
+
+impl Fonts {
+    /// Add every face in a font file (or collection).
+    pub fn add_data(&mut self, data: Bytes) -> usize {
+        let mut count = 0;
+        for (index, info) in FontInfo::iter(&data).enumerate() {
+            self.book.push(info);
+            self.slots.push(FontSlot {
+                source: FontSource::Data(data.clone()),
+                index: index as u32,
+                font: Arc::default(),
+            });
+            count += 1;
+        }
+        count
+    }
+
+    /// Add a font file, or all font files in a directory and its subdirectories.
+    pub fn add_path(&mut self, path: &Path) {
+        if path.is_dir() {
+            let entries = match std::fs::read_dir(path) {
+                Ok(entries) => entries,
+                Err(error) => {
+                    self.warn(format!(
+                        "couldn't read font directory '{}': {error}",
+                        path.display()
+                    ));
+                    return;
+                }
+            };
+            let mut paths: Vec<PathBuf> = entries.flatten().map(|entry| entry.path()).collect();
+            // Directory order isn't stable, and it decides which font wins for duplicate names
+            paths.sort();
+            for path in paths {
+                if path.is_dir() || is_font_file(&path) {
+                    self.add_path(&path);
+                }
+            }
+            return;
+        }
+
+        match std::fs::read(path) {
+            Ok(data) => {
+                if self.add_data(Bytes::new(data)) == 0 {
+                    self.warn(format!("'{}' isn't a font file", path.display()));
+                }
+            }
+            Err(error) => {
+                self.warn(format!("couldn't read font '{}': {error}", path.display()));
+            }
+        }
+    }
+
+    /// Add the fonts installed on the system. Their files are only read once they're used.
+    #[cfg(feature = "system-fonts")]
+    pub fn add_system_fonts(&mut self) {
+        let mut database = fontdb::Database::new();
+        database.load_system_fonts();
+        let mut paths: Vec<(PathBuf, u32)> = database
+            .faces()
+            .filter_map(|face| match &face.source {
+                fontdb::Source::File(path) | fontdb::Source::SharedFile(path, _) => {
+                    Some((path.clone(), face.index))
+                }
+                fontdb::Source::Binary(_) => None,
+            })
+            .collect();
+        paths.sort();
+
+        // fontdb has already found the faces, but Typst needs its own info about them
+        let mut current: Option<(PathBuf, Vec<u8>)> = None;
+        for (path, index) in paths {
+            if current.as_ref().is_none_or(|(current, _)| *current != path) {
+                let Ok(data) = std::fs::read(&path) else {
+                    continue;
+                };
+                current = Some((path.clone(), data));
+            }
+            let Some((_, data)) = &current else {
+                continue;
+            };
+            if let Some(info) = FontInfo::new(data, index) {
+                self.book.push(info);
+                self.slots.push(FontSlot {
+                    source: FontSource::File(path),
+                    index,
+                    font: Arc::default(),
+                });
+            }
+        }
+    }
+
+    /// Add the fonts in `other` after the ones already added.
+    #[cfg_attr(not(feature = "system-fonts"), allow(dead_code))]
+    pub fn extend(&mut self, other: &Fonts) {
+        for (index, slot) in other.slots.iter().enumerate() {
+            if let Some(info) = other.book.info(index) {
+                self.book.push(info.clone());
+                self.slots.push(slot.clone());
+            }
+        }
+        self.diagnostics.extend(other.diagnostics.iter().cloned());
+    }
+
+    fn warn(&mut self, message: String) {
+        self.diagnostics
+            .push(Diagnostic::warning(DiagnosticCategory::Font, message));
+    }
+}
+
+/// Fonts that are done loading, as Typst uses them.
+pub struct FontLibrary {
+    pub book: LazyHash<FontBook>,
+    pub slots: Vec<FontSlot>,
+}
+
+impl From<Fonts> for FontLibrary {
+    fn from(fonts: Fonts) -> Self {
+        Self {
+            book: LazyHash::new(fonts.book),
+            slots: fonts.slots,
+        }
+    }
+}
+
+fn is_font_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            matches!(
+                extension.to_lowercase().as_str(),
+                "ttf" | "otf" | "ttc" | "otc"
+            )
+        })
+}
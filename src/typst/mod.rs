@@ -1,7 +1,8 @@
 // Typst module exports
 
 pub mod compiler;
+mod fonts;
 pub mod world;
 
 pub use compiler::TypstCompiler;
-pub use world::MdpdfWorld;
+pub use world::{LoadedFonts, MdpdfWorld};
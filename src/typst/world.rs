@@ -1,93 +1,496 @@
-use crate::config::MdpdfConfig;
-use anyhow::Result;
+use crate::config::{MdpdfConfig, PdfStandard, Theme, TocConfig, TocPlacement};
+use crate::diagnostics::{Diagnostic, DiagnosticCategory, SourceLocation};
+use crate::error::{Error, Result};
+use crate::typst::fonts::{FontLibrary, Fonts};
 use std::collections::HashMap;
-use std::path::PathBuf;
-use typst::diag::FileError;
-use typst::foundations::Bytes;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock};
+use time::{OffsetDateTime, UtcOffset};
+use typst::diag::{FileError, Severity, SourceDiagnostic};
+use typst::foundations::{Bytes, Datetime, Smart};
 use typst::syntax::{FileId, Source, VirtualPath};
 use typst::text::{Font, FontBook};
-use typst::utils::LazyHash;
+use typst::utils::{LazyHash, hash128};
 use typst::{World, compile};
+use typst_pdf::{PdfStandards, Timestamp};
+
+/// The embedded fonts, which are only loaded once per process and shared by every document.
+static EMBEDDED_FONTS: LazyLock<Fonts> = LazyLock::new(|| {
+    let mut fonts = Fonts::default();
+    for data in MdpdfWorld::get_embedded_fonts() {
+        fonts.add_data(Bytes::new(data));
+    }
+    fonts
+});
+
+/// The fonts of documents that don't add any, so their font book is only hashed once.
+static EMBEDDED_LIBRARY: LazyLock<Arc<FontLibrary>> =
+    LazyLock::new(|| Arc::new(FontLibrary::from(EMBEDDED_FONTS.clone())));
+
+/// The fonts of a document, with the problems loading them.
+///
+/// Cloning shares the fonts, so documents converted with the same `font_paths` and
+/// `system_fonts` (e.g. in a batch) can load them once.
+#[derive(Clone)]
+pub struct LoadedFonts {
+    library: Arc<FontLibrary>,
+    diagnostics: Vec<Diagnostic>,
+    font_paths: Vec<String>,
+    system_fonts: bool,
+}
+
+/// Finding the system fonts reads every font file, so it's only done once.
+#[cfg(feature = "system-fonts")]
+static SYSTEM_FONTS: LazyLock<Fonts> = LazyLock::new(|| {
+    let mut fonts = Fonts::default();
+    fonts.add_system_fonts();
+    fonts
+});
+
+#[cfg(test)]
+thread_local! {
+    /// How many times this thread read font files, so tests can check fonts are shared.
+    pub static FONT_LOADS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+impl LoadedFonts {
+    /// Load the fonts for `config`: the embedded fonts, the fonts in its `font_paths`, and
+    /// the system fonts if `system_fonts` is set. The font files are read again every time,
+    /// so changes to them are picked up.
+    pub fn load(config: &MdpdfConfig) -> Self {
+        let (font_paths, system_fonts) = (config.font_paths.clone(), config.system_fonts);
+        if font_paths.is_empty() && !system_fonts {
+            return Self {
+                library: EMBEDDED_LIBRARY.clone(),
+                diagnostics: Vec::new(),
+                font_paths,
+                system_fonts,
+            };
+        }
+
+        #[cfg(test)]
+        FONT_LOADS.with(|loads| loads.set(loads.get() + 1));
+        // Embedded fonts come first, so they win over user fonts with the same name
+        let mut fonts = EMBEDDED_FONTS.clone();
+        for path in &font_paths {
+            fonts.add_path(Path::new(path));
+        }
+        if system_fonts {
+            #[cfg(feature = "system-fonts")]
+            fonts.extend(&SYSTEM_FONTS);
+            #[cfg(not(feature = "system-fonts"))]
+            fonts.diagnostics.push(Diagnostic::warning(
+                DiagnosticCategory::Font,
+                "system fonts aren't available, since mdpdf was built without the `system-fonts` feature",
+            ));
+        }
+
+        let diagnostics = std::mem::take(&mut fonts.diagnostics);
+        Self {
+            library: Arc::new(FontLibrary::from(fonts)),
+            diagnostics,
+            font_paths,
+            system_fonts,
+        }
+    }
+
+    /// These fonts, or the fonts for `config` if it uses different ones.
+    fn or_load(self, config: &MdpdfConfig) -> Self {
+        if self.font_paths == config.font_paths && self.system_fonts == config.system_fonts {
+            self
+        } else {
+            Self::load(config)
+        }
+    }
+}
+
+/// A font file of an optional font feature, which build.rs finds in `MDPDF_FONTS_DIR`.
+#[cfg(any(feature = "cjk-fonts", feature = "devanagari-fonts"))]
+macro_rules! feature_font {
+    ($file:literal) => {
+        include_bytes!(concat!(env!("MDPDF_FONTS_DIR"), "/", $file)).as_slice()
+    };
+}
+
+/// Where the document goes in a user's template
+const BODY_SLOT: &str = "{{body}}";
 
 pub struct MdpdfWorld {
     config: MdpdfConfig,
-    main_code: String,
+    main: Source,
     files: HashMap<String, Vec<u8>>,
-    fonts: LazyHash<FontBook>,
-    font_data: Vec<Vec<u8>>, // Keep font data alive
+    fonts: LoadedFonts,
 }
 
 impl MdpdfWorld {
     pub fn new(config: MdpdfConfig, main_code: String, files: HashMap<String, Vec<u8>>) -> Self {
-        // Create a font book and populate it with embedded fonts
-        let mut font_book = FontBook::new();
-        let mut font_data = Vec::new();
-
-        // Load embedded fonts
-        let embedded_fonts = Self::get_embedded_fonts();
-
-        for font_bytes in embedded_fonts {
-            if let Some(font) = Font::new(Bytes::new(font_bytes.clone()), 0) {
-                // Get font info and add to book
-                let info = font.info();
-                font_book.push(info.clone());
-                font_data.push(font_bytes);
-            }
-        }
-
-        let fonts = LazyHash::new(font_book);
+        let fonts = LoadedFonts::load(&config);
+        Self::with_fonts(config, main_code, files, fonts)
+    }
 
+    /// Create a world that uses fonts loaded before, if they were loaded for the same
+    /// `font_paths` and `system_fonts` as `config`.
+    pub fn with_fonts(
+        config: MdpdfConfig,
+        main_code: String,
+        files: HashMap<String, Vec<u8>>,
+        fonts: LoadedFonts,
+    ) -> Self {
         Self {
+            main: Source::new(Self::main_id(), main_code),
+            fonts: fonts.or_load(&config),
             config,
-            main_code,
             files,
-            fonts,
-            font_data,
         }
     }
 
-    fn get_embedded_fonts() -> Vec<Vec<u8>> {
-        vec![
+    /// Change the configuration, images and fonts, e.g. for a new version of the document.
+    pub fn update(
+        &mut self,
+        config: MdpdfConfig,
+        files: HashMap<String, Vec<u8>>,
+        fonts: LoadedFonts,
+    ) {
+        self.fonts = fonts.or_load(&config);
+        self.config = config;
+        self.files = files;
+    }
+
+    /// Replace the Typst code of the document. Only the parts that changed are parsed again, so
+    /// Typst can reuse what it compiled for the previous version.
+    pub fn set_main(&mut self, main_code: &str) {
+        self.main.replace(main_code);
+    }
+
+    /// The full Typst document for the generated `body`, and the offset where the body starts.
+    pub fn document(&self, body: &str) -> Result<(String, usize)> {
+        let template = self.create_document_template()?;
+        let (_, template_end) = self.user_template()?;
+        let prefix = format!("\n{template}\n\n");
+        // force newlines
+        let document = format!("{prefix}{body}\n{template_end}");
+        Ok((document, prefix.len()))
+    }
+
+    fn main_id() -> FileId {
+        FileId::new(None, VirtualPath::new("main"))
+    }
+
+    fn get_embedded_fonts() -> Vec<&'static [u8]> {
+        #[allow(unused_mut)]
+        let mut fonts = vec![
             // Noto Emoji fonts (this is first so we don't use emoji that are available in other fonts)
-            include_bytes!("../../fonts/noto-emoji/NotoEmoji-Regular.ttf").to_vec(),
+            include_bytes!("../../fonts/noto-emoji/NotoEmoji-Regular.ttf").as_slice(),
             // Libertinus fonts
-            include_bytes!("../../fonts/libertinus/LibertinusSerif-Regular.ttf").to_vec(),
-            include_bytes!("../../fonts/libertinus/LibertinusSerif-Bold.ttf").to_vec(),
-            include_bytes!("../../fonts/libertinus/LibertinusSerif-Italic.ttf").to_vec(),
-            include_bytes!("../../fonts/libertinus/LibertinusSerif-BoldItalic.ttf").to_vec(),
-            // include_bytes!("../../fonts/libertinus/LibertinusSerif-Semibold.ttf").to_vec(),
-            // include_bytes!("../../fonts/libertinus/LibertinusSerif-SemiboldItalic.ttf").to_vec(),
+            include_bytes!("../../fonts/libertinus/LibertinusSerif-Regular.ttf").as_slice(),
+            include_bytes!("../../fonts/libertinus/LibertinusSerif-Bold.ttf").as_slice(),
+            include_bytes!("../../fonts/libertinus/LibertinusSerif-Italic.ttf").as_slice(),
+            include_bytes!("../../fonts/libertinus/LibertinusSerif-BoldItalic.ttf").as_slice(),
+            // include_bytes!("../../fonts/libertinus/LibertinusSerif-Semibold.ttf").as_slice(),
+            // include_bytes!("../../fonts/libertinus/LibertinusSerif-SemiboldItalic.ttf").as_slice(),
             // DejaVu fonts
-            include_bytes!("../../fonts/dejavu/DejaVuSansMono.ttf").to_vec(),
-            include_bytes!("../../fonts/dejavu/DejaVuSansMono-Bold.ttf").to_vec(),
-        ]
+            include_bytes!("../../fonts/dejavu/DejaVuSansMono.ttf").as_slice(),
+            include_bytes!("../../fonts/dejavu/DejaVuSansMono-Bold.ttf").as_slice(),
+            // New Computer Modern Math (Typst's default math font, needed for equations)
+            include_bytes!("../../fonts/newcm/NewCMMath-Regular.otf").as_slice(),
+        ];
+        // Noto Sans CJK subsets (`make fonts` downloads them, see build.rs)
+        #[cfg(feature = "cjk-fonts")]
+        fonts.extend([
+            feature_font!("noto-cjk/NotoSansSC-Regular.otf"),
+            feature_font!("noto-cjk/NotoSansTC-Regular.otf"),
+            feature_font!("noto-cjk/NotoSansJP-Regular.otf"),
+            feature_font!("noto-cjk/NotoSansKR-Regular.otf"),
+        ]);
+        #[cfg(feature = "devanagari-fonts")]
+        fonts.extend([
+            feature_font!("noto-devanagari/NotoSansDevanagari-Regular.ttf"),
+            feature_font!("noto-devanagari/NotoSansDevanagari-Bold.ttf"),
+        ]);
+        fonts
     }
 
-    pub fn compile_to_pdf(&self) -> Result<Vec<u8>> {
+    /// Compile the document, returning the PDF and any warnings from Typst.
+    ///
+    /// `locate` maps byte offsets in the main Typst source to positions in the Markdown it was
+    /// generated from, so problems can be reported where the user can fix them.
+    pub fn compile_to_pdf(
+        &self,
+        locate: &dyn Fn(usize) -> Option<SourceLocation>,
+    ) -> Result<(Vec<u8>, Vec<Diagnostic>)> {
         let warned = compile(self);
+
+        // Report missing fonts once, instead of everywhere Typst looks for them
+        let missing_families = self.missing_font_families();
+        let mut warnings = self.fonts.diagnostics.clone();
+        warnings.extend(missing_families.iter().map(|family| {
+            Diagnostic::warning(
+                DiagnosticCategory::Font,
+                format!(
+                    "font family '{family}' was not found (hint: add its files with `font_paths`)"
+                ),
+            )
+        }));
+        warnings.extend(
+            warned
+                .warnings
+                .iter()
+                .filter(|warning| {
+                    !missing_families.iter().any(|family| {
+                        warning.message == format!("unknown font family: {}", family.to_lowercase())
+                    })
+                })
+                .map(|warning| self.to_diagnostic(warning, locate)),
+        );
         let document = warned.output.map_err(|errors| {
-            let error_messages: Vec<String> = errors
-                .into_iter()
-                .map(|error| format!("{error:?}"))
-                .collect();
-            anyhow::anyhow!("Typst compilation failed: {}", error_messages.join(", "))
+            Error::Compile(
+                errors
+                    .iter()
+                    .map(|error| self.to_diagnostic(error, locate))
+                    .collect(),
+            )
         })?;
 
-        // Generate PDF using Typst's library
-        let pdf_options = typst_pdf::PdfOptions::default();
+        // Generate PDF using Typst's library. The timestamp is the creation date, unless the
+        // document sets its own date. By default, Typst derives the document identifier from the
+        // title and authors; in deterministic mode, it's derived from the whole document.
+        let ident = self
+            .config
+            .deterministic
+            .then(|| format!("{:032x}", hash128(self.main.text())));
+        let standard = self.config.pdf_standard.unwrap_or_default();
+        let standards = PdfStandards::new(&[match standard {
+            PdfStandard::Pdf17 => typst_pdf::PdfStandard::V_1_7,
+            PdfStandard::PdfA2b => typst_pdf::PdfStandard::A_2b,
+            PdfStandard::PdfA3b => typst_pdf::PdfStandard::A_3b,
+        }])
+        .map_err(|error| Error::Input(error.to_string()))?;
+        let pdf_options = typst_pdf::PdfOptions {
+            ident: ident.as_deref().map_or(Smart::Auto, Smart::Custom),
+            timestamp: self.creation_date()?.map(Timestamp::new_utc),
+            standards,
+            ..typst_pdf::PdfOptions::default()
+        };
         let pdf_bytes = typst_pdf::pdf(&document, &pdf_options).map_err(|errors| {
-            let error_messages: Vec<String> = errors
-                .into_iter()
-                .map(|error| format!("{error:?}"))
-                .collect();
-            anyhow::anyhow!("PDF generation failed: {}", error_messages.join(", "))
+            Error::PdfExport(
+                errors
+                    .iter()
+                    .map(|error| {
+                        let mut diagnostic = self.to_diagnostic(error, locate);
+                        if standard != PdfStandard::Pdf17 && is_pdfa_error(&diagnostic.message) {
+                            diagnostic
+                                .message
+                                .push_str(&format!(" (required by {standard})"));
+                        }
+                        diagnostic
+                    })
+                    .collect(),
+            )
         })?;
 
-        Ok(pdf_bytes)
+        Ok((pdf_bytes, warnings))
+    }
+
+    /// Fonts for scripts the body font probably doesn't cover, in the order Typst should try
+    /// them. Han characters are shared by Chinese, Japanese and Korean but drawn differently in
+    /// each, so the font for the document's language goes first. Only families that are
+    /// available are listed, so this doesn't warn when the font features are disabled.
+    fn script_fallback_fonts(&self) -> Vec<&'static str> {
+        let lang = self
+            .config
+            .lang
+            .as_deref()
+            .unwrap_or_default()
+            .to_lowercase();
+        let mut parts = lang.split(['-', '_']);
+        let language = parts.next().unwrap_or_default();
+        let region = parts.next().unwrap_or_default();
+        let mut cjk = match (language, region) {
+            ("ja", _) => vec![
+                "Noto Sans JP",
+                "Noto Sans SC",
+                "Noto Sans TC",
+                "Noto Sans KR",
+            ],
+            ("ko", _) => vec![
+                "Noto Sans KR",
+                "Noto Sans SC",
+                "Noto Sans TC",
+                "Noto Sans JP",
+            ],
+            ("zh", "tw" | "hk" | "mo") => {
+                vec![
+                    "Noto Sans TC",
+                    "Noto Sans SC",
+                    "Noto Sans JP",
+                    "Noto Sans KR",
+                ]
+            }
+            _ => vec![
+                "Noto Sans SC",
+                "Noto Sans TC",
+                "Noto Sans JP",
+                "Noto Sans KR",
+            ],
+        };
+        cjk.push("Noto Sans Devanagari");
+        cjk.into_iter()
+            .filter(|family| {
+                self.fonts
+                    .library
+                    .book
+                    .contains_family(&family.to_lowercase())
+            })
+            .collect()
+    }
+
+    /// Configured font families that aren't in the font book.
+    fn missing_font_families(&self) -> Vec<&str> {
+        let mut missing = Vec::new();
+        let families = [
+            &self.config.font_family,
+            &self.config.heading_font,
+            &self.config.mono_font,
+        ];
+        for family in families.into_iter().flatten() {
+            if !self
+                .fonts
+                .library
+                .book
+                .contains_family(&family.to_lowercase())
+                && !missing.contains(&family)
+            {
+                missing.push(family);
+            }
+        }
+        missing.into_iter().map(String::as_str).collect()
+    }
+
+    /// The configured creation date, or the current time. In deterministic mode, the date comes
+    /// from `SOURCE_DATE_EPOCH` if it isn't configured, and there's none if that isn't set.
+    fn creation_date(&self) -> Result<Option<Datetime>> {
+        if let Some(value) = self.config.creation_date.as_deref() {
+            return parse_datetime(value).map(Some).ok_or_else(|| {
+                Error::Input(format!(
+                    "Invalid creation_date '{value}', expected YYYY-MM-DD or YYYY-MM-DDTHH:MM:SS"
+                ))
+            });
+        }
+        let now = if self.config.deterministic {
+            let Ok(epoch) = std::env::var("SOURCE_DATE_EPOCH") else {
+                return Ok(None);
+            };
+            epoch
+                .trim()
+                .parse()
+                .ok()
+                .and_then(|seconds| OffsetDateTime::from_unix_timestamp(seconds).ok())
+                .ok_or_else(|| {
+                    Error::Input(format!(
+                        "Invalid SOURCE_DATE_EPOCH '{epoch}', expected a Unix timestamp"
+                    ))
+                })?
+        } else {
+            OffsetDateTime::now_utc()
+        };
+        Datetime::from_ymd_hms(
+            now.year(),
+            now.month().into(),
+            now.day(),
+            now.hour(),
+            now.minute(),
+            now.second(),
+        )
+        .map(Some)
+        .ok_or_else(|| Error::Input("The creation date is out of range".to_string()))
+    }
+
+    fn to_diagnostic(
+        &self,
+        source_diagnostic: &SourceDiagnostic,
+        locate: &dyn Fn(usize) -> Option<SourceLocation>,
+    ) -> Diagnostic {
+        let new_diagnostic = match source_diagnostic.severity {
+            Severity::Warning => Diagnostic::warning,
+            Severity::Error => Diagnostic::error,
+        };
+        let mut message = source_diagnostic.message.to_string();
+        for hint in &source_diagnostic.hints {
+            message.push_str(&format!(" (hint: {hint})"));
+        }
+
+        // Problems in files imported by the preamble or template are reported there
+        if let Some(id) = source_diagnostic.span.id()
+            && id != self.main()
+            && let Ok(source) = self.source(id)
+            && let Some(range) = source.range(source_diagnostic.span)
+        {
+            let line = source.byte_to_line(range.start).unwrap_or_default() + 1;
+            message.push_str(&format!(
+                " (in {}, line {line})",
+                id.vpath().as_rootless_path().display()
+            ));
+            return new_diagnostic(DiagnosticCategory::Typst, message);
+        }
+
+        // Use the first span in the main source, going up the call trace if needed (e.g. for
+        // errors inside the template's functions)
+        let source = &self.main;
+        let offset = std::iter::once(source_diagnostic.span)
+            .chain(source_diagnostic.trace.iter().map(|point| point.span))
+            .filter(|span| span.id() == Some(self.main()))
+            .find_map(|span| source.range(span))
+            .map(|range| range.start);
+        let Some(offset) = offset else {
+            return new_diagnostic(DiagnosticCategory::Typst, message);
+        };
+        match locate(offset) {
+            Some(location) => {
+                new_diagnostic(DiagnosticCategory::Typst, message).with_location(location)
+            }
+            None => {
+                // Not generated from the Markdown, so point at the generated Typst code instead
+                let line = source.byte_to_line(offset).unwrap_or_default() + 1;
+                message.push_str(&format!(" (in generated Typst code, line {line})"));
+                new_diagnostic(DiagnosticCategory::Typst, message)
+            }
+        }
     }
 
-    pub fn create_document_template(&self) -> String {
+    pub fn create_document_template(&self) -> Result<String> {
         let mut template = String::new();
+
+        // Document metadata
+        let mut metadata = Vec::new();
+        if let Some(title) = &self.config.title {
+            metadata.push(format!("title: {}", typst_string(title)));
+        }
+        if !self.config.authors.is_empty() {
+            let authors: Vec<String> = self
+                .config
+                .authors
+                .iter()
+                .map(|a| typst_string(a))
+                .collect();
+            metadata.push(format!("author: ({},)", authors.join(", ")));
+        }
+        if !self.config.keywords.is_empty() {
+            let keywords: Vec<String> = self
+                .config
+                .keywords
+                .iter()
+                .map(|keyword| typst_string(keyword))
+                .collect();
+            metadata.push(format!("keywords: ({},)", keywords.join(", ")));
+        }
+        // Free-form dates like "Spring 2024" are only shown in the title block
+        if let Some(date) = self.config.date.as_deref().and_then(parse_datetime) {
+            metadata.push(format!("date: {}", typst_datetime(&date)));
+        }
+        if !metadata.is_empty() {
+            template.push_str(&format!("#set document({})\n", metadata.join(", ")));
+        }
+
         template.push_str("#set page(");
 
         // Set page size
@@ -120,33 +523,55 @@ impl MdpdfWorld {
             .font_family
             .as_deref()
             .unwrap_or("Libertinus Serif");
-        template.push_str(&format!("#set text(font: \"{body_font}\")\n"));
+        let mut body_fonts = vec![typst_string(body_font)];
+        body_fonts.extend(self.script_fallback_fonts().into_iter().map(typst_string));
+        template.push_str(&format!("#set text(font: ({},))\n", body_fonts.join(", ")));
+        if let Some(heading_font) = &self.config.heading_font {
+            template.push_str(&format!(
+                "#show heading: set text(font: {})\n",
+                typst_string(heading_font)
+            ));
+        }
+        if let Some(mono_font) = &self.config.mono_font {
+            template.push_str(&format!(
+                "#show raw: set text(font: {})\n",
+                typst_string(mono_font)
+            ));
+        }
 
         // Set font size if specified in config
         if let Some(font_size) = &self.config.font_size {
             template.push_str(&format!("#set text(size: {font_size}pt)\n"));
         }
 
+        // Language (and region) for hyphenation and smart quotes, e.g. "de-CH"
+        if let Some(lang) = &self.config.lang {
+            let mut parts = lang.split(['-', '_']);
+            let language = parts.next().unwrap_or_default().to_lowercase();
+            template.push_str(&format!("#set text(lang: {}", typst_string(&language)));
+            if let Some(region) = parts.next() {
+                template.push_str(&format!(
+                    ", region: {}",
+                    typst_string(&region.to_uppercase())
+                ));
+            }
+            template.push_str(")\n");
+        }
+
         // Note: Code blocks will use the default font from the font book
         // The font book includes DejaVu Sans which works well for code
 
         // Other preamble settings:
-        // links
-        template.push_str("#show link: underline\n");
-
         // horizontal rules
         // template.push_str("#let hrule = line(length: 100%, stroke: 1pt + gray)\n");
         template.push_str("#let hrule = line(length: 100%)\n");
         // blockquotes
         template.push_str("#set quote(block: true)\n");
-        template.push_str("#show quote.where(block: true): block.with(stroke: (left: 2pt + gray, rest: none), above: 1em, below: 1.2em)\n");
-
-        // This would be nice to enable but it's pretty expensive to run
-        // template.push_str("#show raw.where(block: true): block.with(stroke: 1pt + gray, inset: 1em, radius: 4pt, width: 100%)\n");
 
-        // increase spacing below h1/h2
-        template.push_str("#show heading.where(level: 1): set block(below: 0.8em)\n");
-        template.push_str("#show heading.where(level: 2): set block(below: 0.7em)\n");
+        // Colors, spacing, links, headings, code blocks, blockquotes and tables
+        let theme =
+            Theme::load(self.config.theme.as_deref().unwrap_or("default")).map_err(Error::Input)?;
+        template.push_str(&Self::theme_styles(&theme));
 
         // numbering formats for nested ordered lists
         template.push_str("#set enum(numbering: \"1.a.i.A.I.α.\")\n");
@@ -162,8 +587,334 @@ impl MdpdfWorld {
         if let Some(footer) = &self.config.footer {
             template.push_str(&format!("#set page(footer: [{footer}])\n"));
         }
-        template
+
+        if let Some(preamble) = &self.config.preamble {
+            template.push_str(preamble);
+            template.push('\n');
+        }
+        // The user's template wraps the content, so that its show rules apply to all of it
+        let (template_start, _) = self.user_template()?;
+        template.push_str(template_start);
+
+        // Content goes last, since `#set page` after content starts a new page
+        if self.config.title_block {
+            template.push_str(&self.title_block());
+        }
+        if let Some(toc) = &self.config.toc
+            && toc.placement == TocPlacement::Top
+        {
+            template.push_str(&Self::outline(toc));
+        }
+        Ok(template)
+    }
+
+    /// The user's template, before and after `{{body}}`.
+    pub fn user_template(&self) -> Result<(&str, &str)> {
+        let Some(template) = &self.config.template else {
+            return Ok(("", ""));
+        };
+        let mut parts = template.split(BODY_SLOT);
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(start), Some(end), None) => Ok((start, end)),
+            _ => Err(Error::Input(format!(
+                "The template must contain {BODY_SLOT} exactly once"
+            ))),
+        }
+    }
+
+    /// Read a `.typ` file imported by the preamble or template from `base_dir`.
+    fn read_typst_file(&self, id: FileId) -> Result<String, FileError> {
+        let vpath = id.vpath().as_rootless_path();
+        let not_found = || FileError::NotFound(vpath.to_path_buf());
+        let Some(base_dir) = &self.config.base_dir else {
+            return Err(not_found());
+        };
+        if id.package().is_some() || vpath.extension().is_none_or(|extension| extension != "typ") {
+            return Err(not_found());
+        }
+
+        let base_dir = std::fs::canonicalize(base_dir)
+            .map_err(|error| FileError::from_io(error, Path::new(base_dir)))?;
+        let path = id
+            .vpath()
+            .resolve(&base_dir)
+            .ok_or(FileError::AccessDenied)?;
+        let path =
+            std::fs::canonicalize(&path).map_err(|error| FileError::from_io(error, &path))?;
+        // Symlinks could point anywhere
+        if !path.starts_with(&base_dir) {
+            return Err(FileError::AccessDenied);
+        }
+        let data = std::fs::read(&path).map_err(|error| FileError::from_io(error, &path))?;
+        String::from_utf8(data).map_err(|_| FileError::InvalidUtf8)
+    }
+
+    /// Show and set rules that apply a theme.
+    fn theme_styles(theme: &Theme) -> String {
+        let mut styles = String::new();
+
+        let text = &theme.text;
+        if let Some(color) = &text.color {
+            styles.push_str(&format!("#set text(fill: {})\n", color.to_typst()));
+        }
+        let mut par = Vec::new();
+        if let Some(leading) = &text.leading {
+            par.push(format!("leading: {}", leading.to_typst()));
+        }
+        if let Some(spacing) = &text.paragraph_spacing {
+            par.push(format!("spacing: {}", spacing.to_typst()));
+        }
+        if let Some(justify) = text.justify {
+            par.push(format!("justify: {justify}"));
+        }
+        if !par.is_empty() {
+            styles.push_str(&format!("#set par({})\n", par.join(", ")));
+        }
+
+        if let Some(color) = &theme.link.color {
+            styles.push_str(&format!(
+                "#show link: set text(fill: {})\n",
+                color.to_typst()
+            ));
+        }
+        if theme.link.underline {
+            styles.push_str("#show link: underline\n");
+        }
+
+        let heading = &theme.heading;
+        if let Some(numbering) = &heading.numbering {
+            styles.push_str(&format!(
+                "#set heading(numbering: {})\n",
+                typst_string(numbering)
+            ));
+        }
+        let mut text = Vec::new();
+        if let Some(color) = &heading.color {
+            text.push(format!("fill: {}", color.to_typst()));
+        }
+        if let Some(weight) = &heading.weight {
+            text.push(format!("weight: {}", weight.to_typst()));
+        }
+        if !text.is_empty() {
+            styles.push_str(&format!("#show heading: set text({})\n", text.join(", ")));
+        }
+        for level in 1..=6 {
+            let index = level - 1;
+            if let Some(size) = heading.sizes.get(index) {
+                styles.push_str(&format!(
+                    "#show heading.where(level: {level}): set text(size: {})\n",
+                    size.to_typst()
+                ));
+            }
+            let mut block = Vec::new();
+            if let Some(above) = heading.above.get(index) {
+                block.push(format!("above: {}", above.to_typst()));
+            }
+            if let Some(below) = heading.below.get(index) {
+                block.push(format!("below: {}", below.to_typst()));
+            }
+            if let Some(rule) = &heading.rule
+                && level <= heading.rule_levels as usize
+            {
+                block.push(format!(
+                    "stroke: (bottom: {}), inset: (bottom: 0.3em)",
+                    rule.to_typst()
+                ));
+            }
+            if !block.is_empty() {
+                styles.push_str(&format!(
+                    "#show heading.where(level: {level}): set block({})\n",
+                    block.join(", ")
+                ));
+            }
+        }
+
+        let code = &theme.code;
+        let mut block = Vec::new();
+        if let Some(fill) = &code.fill {
+            block.push(format!("fill: {}", fill.to_typst()));
+        }
+        if let Some(stroke) = &code.stroke {
+            block.push(format!("stroke: {}", stroke.to_typst()));
+        }
+        if let Some(inset) = &code.inset {
+            block.push(format!("inset: {}", inset.to_typst()));
+        }
+        if let Some(radius) = &code.radius {
+            block.push(format!("radius: {}", radius.to_typst()));
+        }
+        if !block.is_empty() {
+            styles.push_str(&format!(
+                "#show raw.where(block: true): block.with({}, width: 100%)\n",
+                block.join(", ")
+            ));
+        }
+        if let Some(fill) = &code.inline_fill {
+            styles.push_str(&format!(
+                "#show raw.where(block: false): box.with(fill: {}, inset: (x: 3pt), outset: (y: 3pt), radius: 2pt)\n",
+                fill.to_typst()
+            ));
+        }
+
+        let quote = &theme.blockquote;
+        let mut block = Vec::new();
+        if let Some(border) = &quote.border {
+            block.push(format!("stroke: (left: {}, rest: none)", border.to_typst()));
+        }
+        if let Some(fill) = &quote.fill {
+            block.push(format!("fill: {}", fill.to_typst()));
+        }
+        if let Some(inset) = &quote.inset {
+            block.push(format!("inset: {}", inset.to_typst()));
+        }
+        if let Some(above) = &quote.above {
+            block.push(format!("above: {}", above.to_typst()));
+        }
+        if let Some(below) = &quote.below {
+            block.push(format!("below: {}", below.to_typst()));
+        }
+        if !block.is_empty() {
+            styles.push_str(&format!(
+                "#show quote.where(block: true): block.with({})\n",
+                block.join(", ")
+            ));
+        }
+        if let Some(color) = &quote.color {
+            styles.push_str(&format!(
+                "#show quote.where(block: true): set text(fill: {})\n",
+                color.to_typst()
+            ));
+        }
+
+        let table = &theme.table;
+        let mut set = Vec::new();
+        if let Some(stroke) = &table.stroke {
+            set.push(format!("stroke: {}", stroke.to_typst()));
+        }
+        if let Some(fill) = &table.header_fill {
+            set.push(format!(
+                "fill: (_, y) => if y == 0 {{ {} }}",
+                fill.to_typst()
+            ));
+        }
+        if let Some(inset) = &table.inset {
+            set.push(format!("inset: {}", inset.to_typst()));
+        }
+        if !set.is_empty() {
+            styles.push_str(&format!("#set table({})\n", set.join(", ")));
+        }
+
+        styles
     }
+
+    /// The table of contents. Typst links each entry to its heading.
+    pub fn outline(toc: &TocConfig) -> String {
+        let mut args = Vec::new();
+        match toc.title.as_deref() {
+            Some("") => args.push("title: none".to_string()),
+            Some(title) => args.push(format!("title: {}", typst_string(title))),
+            None => {}
+        }
+        if let Some(depth) = toc.depth {
+            args.push(format!("depth: {depth}"));
+        }
+        format!("#outline({})\n", args.join(", "))
+    }
+
+    fn title_block(&self) -> String {
+        let mut lines = Vec::new();
+        if let Some(title) = &self.config.title {
+            lines.push(format!(
+                "block(text(size: 1.8em, weight: \"bold\", {}))",
+                typst_string(title)
+            ));
+        }
+        if !self.config.authors.is_empty() {
+            lines.push(format!(
+                "block(text(size: 1.1em, {}))",
+                typst_string(&self.config.authors.join(", "))
+            ));
+        }
+        if let Some(date) = &self.config.date {
+            lines.push(format!("block({})", typst_string(date)));
+        }
+        if lines.is_empty() {
+            return String::new();
+        }
+        format!(
+            "#align(center, {{\n  {}\n}})\n#v(1em)\n",
+            lines.join("\n  ")
+        )
+    }
+}
+
+/// Export errors that Typst only reports when a PDF/A standard is chosen, but that don't say so.
+const PDFA_ERRORS: [&str; 4] = [
+    "could not be displayed with any font",
+    "could not be exported",
+    "alt text is too long",
+    "too many PDF objects",
+];
+
+/// Whether an export error is about something the chosen PDF/A standard doesn't allow.
+fn is_pdfa_error(message: &str) -> bool {
+    message.contains("PDF/A") || PDFA_ERRORS.iter().any(|error| message.contains(error))
+}
+
+/// Parse a `YYYY-MM-DD` date, optionally followed by a `HH:MM:SS` or `HH:MM` time.
+fn parse_datetime(value: &str) -> Option<Datetime> {
+    let value = value.trim();
+    let (date, time) = match value.split_once(['T', ' ']) {
+        Some((date, time)) => (date, Some(time.trim_end_matches('Z'))),
+        None => (value, None),
+    };
+    let mut date_parts = date.split('-');
+    let year = date_parts.next()?.parse().ok()?;
+    let month = date_parts.next()?.parse().ok()?;
+    let day = date_parts.next()?.parse().ok()?;
+    if date_parts.next().is_some() {
+        return None;
+    }
+    let Some(time) = time else {
+        return Datetime::from_ymd(year, month, day);
+    };
+    let mut time_parts = time.split(':');
+    let hour = time_parts.next()?.parse().ok()?;
+    let minute = time_parts.next()?.parse().ok()?;
+    let second = time_parts
+        .next()
+        .map_or(Some(0), |second| second.parse().ok())?;
+    if time_parts.next().is_some() {
+        return None;
+    }
+    Datetime::from_ymd_hms(year, month, day, hour, minute, second)
+}
+
+/// Write `datetime` as a Typst `datetime(...)` call.
+fn typst_datetime(datetime: &Datetime) -> String {
+    let fields = [
+        ("year", datetime.year().map(i64::from)),
+        ("month", datetime.month().map(i64::from)),
+        ("day", datetime.day().map(i64::from)),
+        ("hour", datetime.hour().map(i64::from)),
+        ("minute", datetime.minute().map(i64::from)),
+        ("second", datetime.second().map(i64::from)),
+    ];
+    let args: Vec<String> = fields
+        .iter()
+        .filter_map(|(name, value)| value.map(|value| format!("{name}: {value}")))
+        .collect();
+    format!("datetime({})", args.join(", "))
+}
+
+/// Quote `value` as a Typst string literal.
+pub(crate) fn typst_string(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r");
+    format!("\"{escaped}\"")
 }
 
 impl World for MdpdfWorld {
@@ -173,18 +924,16 @@ impl World for MdpdfWorld {
         &LIBRARY
     }
     fn book(&self) -> &LazyHash<FontBook> {
-        &self.fonts
+        &self.fonts.library.book
     }
     fn main(&self) -> FileId {
-        FileId::new(None, VirtualPath::new("main"))
+        Self::main_id()
     }
     fn source(&self, id: FileId) -> Result<Source, FileError> {
-        let vpath = id.vpath().clone();
-        let vpath_str = vpath.as_rootless_path().to_string_lossy();
-        if vpath_str == "main" {
-            Ok(Source::new(id, self.main_code.clone()))
+        if id == self.main() {
+            Ok(self.main.clone())
         } else {
-            Err(FileError::NotFound(PathBuf::from(vpath_str.as_ref())))
+            self.read_typst_file(id).map(|text| Source::new(id, text))
         }
     }
     fn file(&self, id: FileId) -> Result<Bytes, FileError> {
@@ -197,15 +946,17 @@ impl World for MdpdfWorld {
         }
     }
     fn font(&self, id: usize) -> Option<Font> {
-        // Return font from our stored font data
-        if id < self.font_data.len()
-            && let Some(font) = Font::new(Bytes::new(self.font_data[id].clone()), 0)
-        {
-            return Some(font);
-        }
-        None
+        self.fonts.library.slots.get(id)?.get()
     }
-    fn today(&self, _offset: Option<i64>) -> Option<typst::foundations::Datetime> {
-        None
+    fn today(&self, offset: Option<i64>) -> Option<Datetime> {
+        if self.config.creation_date.is_some() || self.config.deterministic {
+            let date = self.creation_date().ok()??;
+            return Datetime::from_ymd(date.year()?, date.month()?, date.day()?);
+        }
+        // Without an offset, Typst wants the local date. We don't know the local time zone (and
+        // it shouldn't change the output), so use UTC.
+        let offset = UtcOffset::from_hms(offset.unwrap_or(0).try_into().ok()?, 0, 0).ok()?;
+        let now = OffsetDateTime::now_utc().checked_to_offset(offset)?;
+        Datetime::from_ymd(now.year(), now.month().into(), now.day())
     }
 }
@@ -1,39 +1,171 @@
-use anyhow::Result;
 use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
 use image::ImageFormat;
+#[cfg(feature = "network")]
 use reqwest::Client;
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
 use tokio::fs;
+use typst::utils::hash128;
 use url::Url;
 
+use crate::config::{ImageHandlingConfig, MdpdfConfig};
+use crate::diagnostics::{Diagnostic, DiagnosticCategory};
+use crate::error::{ImageError, Result};
+use crate::typst::world::typst_string;
+
 pub struct ImageProcessor {
+    #[cfg(feature = "network")]
     client: Client,
     cache_dir: Option<PathBuf>,
+    base_dir: Option<PathBuf>,
+    allow_outside_base_dir: bool,
+    #[cfg_attr(not(feature = "network"), allow(dead_code))]
+    download_timeout_ms: u64,
+    max_image_size: Option<u64>,
+    allowed_domains: Vec<String>,
+    offline: bool,
+    // Local images that were read, or that were missing
+    local_files: Mutex<Vec<PathBuf>>,
 }
 
 impl ImageProcessor {
     pub fn new(cache_dir: Option<PathBuf>) -> Self {
+        let config = MdpdfConfig {
+            image_handling: Some(ImageHandlingConfig {
+                cache_directory: cache_dir.map(|dir| dir.to_string_lossy().to_string()),
+                ..ImageHandlingConfig::default()
+            }),
+            ..MdpdfConfig::default()
+        };
+        Self::from_config(&config)
+    }
+
+    pub fn from_config(config: &MdpdfConfig) -> Self {
+        let image_handling = config.image_handling.clone().unwrap_or_default();
+
+        // Redirects must not lead us to hosts outside of the allowlist
+        #[cfg(feature = "network")]
+        let allowed_domains = image_handling.allowed_domains.clone();
+        #[cfg(feature = "network")]
+        let redirect_policy = reqwest::redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= 10 {
+                attempt.error("too many redirects")
+            } else if is_domain_allowed(&allowed_domains, attempt.url().host_str()) {
+                attempt.follow()
+            } else {
+                let url = attempt.url().to_string();
+                let host = attempt.url().host_str().unwrap_or_default().to_string();
+                attempt.error(ImageError::DomainNotAllowed { url, host })
+            }
+        });
+        #[cfg(feature = "network")]
+        let client = Client::builder()
+            .redirect(redirect_policy)
+            .build()
+            .unwrap_or_default();
+
         Self {
-            client: Client::new(),
-            cache_dir,
+            #[cfg(feature = "network")]
+            client,
+            cache_dir: image_handling.cache_directory_path(),
+            base_dir: config.base_dir_path(),
+            allow_outside_base_dir: image_handling.allow_outside_base_dir,
+            download_timeout_ms: image_handling.download_timeout,
+            max_image_size: image_handling.max_image_size,
+            allowed_domains: image_handling.allowed_domains,
+            offline: image_handling.offline,
+            local_files: Mutex::default(),
         }
     }
 
+    /// The local image files that the document uses, e.g. to watch them for changes.
+    pub fn local_files(&self) -> Vec<PathBuf> {
+        self.local_files
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
     pub async fn process_image_url(&self, url: &str) -> Result<Vec<u8>> {
         if url.starts_with("data:") {
             self.process_data_url(url).await
         } else if url.starts_with("http://") || url.starts_with("https://") {
             self.download_image(url).await
+        } else if let Some(base_dir) = &self.base_dir {
+            self.read_local_image(url, base_dir).await
         } else {
-            Err(anyhow::anyhow!("Unsupported image URL format: {}", url))
+            Err(ImageError::UnsupportedUrl {
+                url: url.to_string(),
+            }
+            .into())
+        }
+    }
+
+    fn check_size(&self, url: &str, size: u64) -> Result<()> {
+        match self.max_image_size {
+            Some(max_size) if size > max_size => Err(ImageError::TooLarge {
+                url: url.to_string(),
+                max_size,
+            }
+            .into()),
+            _ => Ok(()),
         }
     }
 
+    async fn read_local_image(&self, path: &str, base_dir: &Path) -> Result<Vec<u8>> {
+        let read_error = |reason: String| ImageError::Read {
+            path: path.to_string(),
+            reason,
+        };
+        let base_dir = fs::canonicalize(base_dir).await.map_err(|e| {
+            read_error(format!(
+                "invalid base directory '{}': {e}",
+                base_dir.display()
+            ))
+        })?;
+
+        // Resolve the path like a URL relative to the base directory, which handles absolute
+        // paths, `file://` URLs and percent-encoded characters (e.g. `my%20image.png`)
+        let file_path = Url::from_directory_path(&base_dir)
+            .ok()
+            .and_then(|base_url| base_url.join(path).ok())
+            .and_then(|url| url.to_file_path().ok())
+            .ok_or_else(|| read_error("invalid image path".to_string()))?;
+        self.local_files
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(file_path.clone());
+        let file_path = fs::canonicalize(&file_path)
+            .await
+            .map_err(|e| read_error(e.to_string()))?;
+
+        if !self.allow_outside_base_dir && !file_path.starts_with(&base_dir) {
+            return Err(ImageError::OutsideBaseDir {
+                path: path.to_string(),
+                base_dir: base_dir.display().to_string(),
+            }
+            .into());
+        }
+
+        let metadata = fs::metadata(&file_path)
+            .await
+            .map_err(|e| read_error(e.to_string()))?;
+        self.check_size(path, metadata.len())?;
+        Ok(fs::read(&file_path)
+            .await
+            .map_err(|e| read_error(e.to_string()))?)
+    }
+
     async fn process_data_url(&self, data_url: &str) -> Result<Vec<u8>> {
         // Parse data URL format: data:[<mediatype>][;base64],<data>
         let parts: Vec<&str> = data_url.splitn(2, ',').collect();
         if parts.len() != 2 {
-            return Err(anyhow::anyhow!("Invalid data URL format"));
+            return Err(ImageError::InvalidDataUrl {
+                reason: "missing `,`".to_string(),
+            }
+            .into());
         }
 
         let header = parts[0];
@@ -41,20 +173,39 @@ impl ImageProcessor {
 
         // Check if it's base64 encoded
         if !header.contains(";base64") {
-            return Err(anyhow::anyhow!(
-                "Only base64 encoded data URLs are supported"
-            ));
+            return Err(ImageError::InvalidDataUrl {
+                reason: "only base64 encoded data URLs are supported".to_string(),
+            }
+            .into());
         }
 
         // Decode base64 data
         let decoded = BASE64
             .decode(data)
-            .map_err(|e| anyhow::anyhow!("Failed to decode base64: {}", e))?;
+            .map_err(|e| ImageError::InvalidDataUrl {
+                reason: format!("failed to decode base64: {e}"),
+            })?;
+        self.check_size("data URL", decoded.len() as u64)?;
 
         Ok(decoded)
     }
 
     async fn download_image(&self, url: &str) -> Result<Vec<u8>> {
+        let host = Url::parse(url)
+            .map_err(|e| ImageError::Download {
+                url: url.to_string(),
+                reason: e.to_string(),
+            })?
+            .host_str()
+            .map(str::to_string);
+        if !is_domain_allowed(&self.allowed_domains, host.as_deref()) {
+            return Err(ImageError::DomainNotAllowed {
+                url: url.to_string(),
+                host: host.unwrap_or_default(),
+            }
+            .into());
+        }
+
         // Check if we have a cached version
         if let Some(cache_path) = self.get_cache_path(url).await?
             && cache_path.exists()
@@ -62,37 +213,100 @@ impl ImageProcessor {
             return Ok(fs::read(&cache_path).await?);
         }
 
-        // Download the image
-        let response = self
-            .client
-            .get(url)
-            .timeout(std::time::Duration::from_secs(30))
-            .send()
-            .await?;
-
-        if !response.status().is_success() {
-            return Err(anyhow::anyhow!(
-                "Failed to download image: {}",
-                response.status()
-            ));
+        if self.offline {
+            return Err(ImageError::Offline {
+                url: url.to_string(),
+            }
+            .into());
         }
-
-        let image_data = response.bytes().await?;
+        let image_data = self.fetch_image(url).await?;
 
         // Cache the image if we have a cache directory
         if let Some(cache_path) = self.get_cache_path(url).await? {
             if let Some(parent) = cache_path.parent() {
                 fs::create_dir_all(parent).await?;
             }
-            fs::write(&cache_path, &image_data).await?;
+            // Write to a temporary file first, so conversions sharing the cache never read a
+            // partly written image
+            static DOWNLOADS: AtomicU64 = AtomicU64::new(0);
+            let partial = cache_path.with_file_name(format!(
+                "{}.{}-{}.part",
+                cache_path.file_name().unwrap_or_default().to_string_lossy(),
+                std::process::id(),
+                DOWNLOADS.fetch_add(1, Ordering::Relaxed)
+            ));
+            fs::write(&partial, &image_data).await?;
+            fs::rename(&partial, &cache_path).await?;
+        }
+
+        Ok(image_data)
+    }
+
+    #[cfg(feature = "network")]
+    async fn fetch_image(&self, url: &str) -> Result<Vec<u8>> {
+        // Download the image. The timeout covers the whole request, including reading the body.
+        let request_error = |e: reqwest::Error| -> ImageError {
+            let redirect_error = std::error::Error::source(&e)
+                .and_then(|source| source.downcast_ref::<ImageError>())
+                .cloned();
+            if let Some(redirect_error) = redirect_error {
+                redirect_error
+            } else if e.is_timeout() {
+                ImageError::Timeout {
+                    url: url.to_string(),
+                    timeout_ms: self.download_timeout_ms,
+                }
+            } else {
+                ImageError::Download {
+                    url: url.to_string(),
+                    reason: e.to_string(),
+                }
+            }
+        };
+        let mut response = self
+            .client
+            .get(url)
+            .timeout(std::time::Duration::from_millis(self.download_timeout_ms))
+            .send()
+            .await
+            .map_err(request_error)?;
+
+        if !response.status().is_success() {
+            return Err(ImageError::Download {
+                url: url.to_string(),
+                reason: response.status().to_string(),
+            }
+            .into());
+        }
+
+        // Stop reading as soon as the image is too large, rather than downloading all of it
+        if let Some(content_length) = response.content_length() {
+            self.check_size(url, content_length)?;
+        }
+        let mut image_data = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(request_error)? {
+            image_data.extend_from_slice(&chunk);
+            self.check_size(url, image_data.len() as u64)?;
         }
 
-        Ok(image_data.to_vec())
+        Ok(image_data)
+    }
+
+    #[cfg(not(feature = "network"))]
+    async fn fetch_image(&self, url: &str) -> Result<Vec<u8>> {
+        // Built without network support
+        Err(ImageError::Offline {
+            url: url.to_string(),
+        }
+        .into())
     }
 
     async fn get_cache_path(&self, url: &str) -> Result<Option<PathBuf>> {
         if let Some(ref cache_dir) = self.cache_dir {
-            let url_obj = Url::parse(url)?;
+            let url_obj = Url::parse(url).map_err(|e| ImageError::Download {
+                url: url.to_string(),
+                reason: e.to_string(),
+            })?;
             let host = url_obj.host_str().unwrap_or("unknown");
             let path = url_obj.path();
             let filename = path.split('/').next_back().unwrap_or("image");
@@ -150,16 +364,11 @@ impl ImageProcessor {
     ) -> Result<(String, Vec<u8>)> {
         // Check if this is an SVG image
         if self.is_svg(image_data) {
-            // Generate a unique filename for this SVG
-            use std::collections::hash_map::DefaultHasher;
-            use std::hash::{Hash, Hasher};
-            let mut hasher = DefaultHasher::new();
-            image_data.hash(&mut hasher);
-            let hash = hasher.finish();
-            let filename = format!("image_{hash:x}.svg");
+            // Name the file after its content, so the output is the same on every run
+            let filename = format!("image_{:032x}.svg", hash128(image_data));
 
             // Return the SVG data as-is with .svg extension
-            let typst_syntax = format!("#image(\"{filename}\", alt: \"{alt_text}\")");
+            let typst_syntax = format!("#image(\"{filename}\", alt: {})", typst_string(alt_text));
             return Ok((typst_syntax, image_data.to_vec()));
         }
 
@@ -167,7 +376,10 @@ impl ImageProcessor {
         let img = match image::load_from_memory(image_data) {
             Ok(img) => img,
             Err(e) => {
-                return Err(e.into());
+                return Err(ImageError::Decode {
+                    reason: e.to_string(),
+                }
+                .into());
             }
         };
 
@@ -176,22 +388,79 @@ impl ImageProcessor {
         match img.write_to(&mut std::io::Cursor::new(&mut output), ImageFormat::Png) {
             Ok(_) => {}
             Err(e) => {
-                return Err(e.into());
+                return Err(ImageError::Decode {
+                    reason: e.to_string(),
+                }
+                .into());
             }
         }
 
-        // Generate a unique filename for this image
-        use std::collections::hash_map::DefaultHasher;
-        use std::hash::{Hash, Hasher};
-        let mut hasher = DefaultHasher::new();
-        image_data.hash(&mut hasher);
-        let hash = hasher.finish();
-        let filename = format!("image_{hash:x}.png");
+        // Name the file after its content, so the output is the same on every run
+        let filename = format!("image_{:032x}.png", hash128(image_data));
 
         // Return both the Typst syntax and the converted PNG data
-        let typst_syntax = format!("#image(\"{filename}\", alt: \"{alt_text}\")");
+        let typst_syntax = format!("#image(\"{filename}\", alt: {})", typst_string(alt_text));
         Ok((typst_syntax, output))
     }
+
+    /// Load an image, convert it for Typst, and add it to `image_files`. Returns the Typst code
+    /// that shows the image, or a diagnostic saying why it couldn't be embedded.
+    pub async fn embed_image(
+        &self,
+        url: &str,
+        alt_text: &str,
+        image_files: &mut HashMap<String, Vec<u8>>,
+    ) -> std::result::Result<String, Diagnostic> {
+        // Don't repeat whole data URLs in messages
+        let source = if url.starts_with("data:") {
+            "data URL".to_string()
+        } else {
+            format!("`{url}`")
+        };
+        let image_data = self.process_image_url(url).await.map_err(|e| {
+            Diagnostic::warning(
+                DiagnosticCategory::Image,
+                format!("couldn't load image {source}: {e}"),
+            )
+        })?;
+        let (typst_image_code, converted_data) = self
+            .convert_to_typst_format(&image_data, alt_text)
+            .await
+            .map_err(|e| {
+                Diagnostic::warning(
+                    DiagnosticCategory::Image,
+                    format!("couldn't convert image {source}: {e}"),
+                )
+            })?;
+        // Extract filename from the generated code
+        let filename = typst_image_code.split('"').nth(1).ok_or_else(|| {
+            Diagnostic::warning(
+                DiagnosticCategory::Image,
+                format!("couldn't embed image {source}"),
+            )
+        })?;
+        image_files.insert(filename.to_string(), converted_data);
+        Ok(typst_image_code)
+    }
+}
+
+/// Check a host against an allowlist of domains. An empty allowlist allows every host, and
+/// entries like `*.example.com` allow any subdomain of `example.com`.
+fn is_domain_allowed(allowed_domains: &[String], host: Option<&str>) -> bool {
+    if allowed_domains.is_empty() {
+        return true;
+    }
+    let Some(host) = host else {
+        return false;
+    };
+    let host = host.to_lowercase();
+    allowed_domains.iter().any(|domain| {
+        let domain = domain.to_lowercase();
+        match domain.strip_prefix("*.") {
+            Some(parent) => host.ends_with(&format!(".{parent}")),
+            None => host == domain,
+        }
+    })
 }
 
 impl Default for ImageProcessor {
@@ -199,3 +468,249 @@ impl Default for ImageProcessor {
         Self::new(None)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    fn image_error(error: &Error) -> Option<&ImageError> {
+        match error {
+            Error::Image(error) => Some(error),
+            _ => None,
+        }
+    }
+
+    fn write_test_png(path: &Path) {
+        let image = image::RgbImage::new(1, 1);
+        image.save_with_format(path, ImageFormat::Png).unwrap();
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mdpdf-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("docs/img")).unwrap();
+        dir
+    }
+
+    fn processor_for(base_dir: &Path, allow_outside_base_dir: bool) -> ImageProcessor {
+        let config = MdpdfConfig {
+            base_dir: Some(base_dir.to_string_lossy().to_string()),
+            image_handling: Some(crate::config::ImageHandlingConfig {
+                allow_outside_base_dir,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        ImageProcessor::from_config(&config)
+    }
+
+    #[tokio::test]
+    async fn test_local_images() {
+        let dir = test_dir("local-images");
+        write_test_png(&dir.join("docs/img/arch diagram.png"));
+        let processor = processor_for(&dir.join("docs"), false);
+
+        assert!(
+            processor
+                .process_image_url("img/arch diagram.png")
+                .await
+                .is_ok()
+        );
+        assert!(
+            processor
+                .process_image_url("./img/arch%20diagram.png")
+                .await
+                .is_ok()
+        );
+        let absolute = dir.join("docs/img/arch diagram.png");
+        assert!(
+            processor
+                .process_image_url(&absolute.to_string_lossy())
+                .await
+                .is_ok()
+        );
+        assert!(
+            processor
+                .process_image_url("img/missing.png")
+                .await
+                .is_err()
+        );
+
+        // Without a base directory, local paths aren't read at all
+        let processor = ImageProcessor::default();
+        assert!(
+            processor
+                .process_image_url("img/arch diagram.png")
+                .await
+                .is_err()
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Serve a single HTTP response on a local port and return its URL.
+    #[cfg(feature = "network")]
+    fn serve_once(response: Vec<u8>, delay: std::time::Duration) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            use std::io::{Read, Write};
+            if let Ok((mut stream, _)) = listener.accept() {
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request);
+                std::thread::sleep(delay);
+                let _ = stream.write_all(&response);
+            }
+        });
+        format!("http://{address}/image.png")
+    }
+
+    #[test]
+    fn test_domain_allowlist() {
+        let allowed = vec!["example.com".to_string(), "*.images.test".to_string()];
+        assert!(is_domain_allowed(&allowed, Some("example.com")));
+        assert!(is_domain_allowed(&allowed, Some("EXAMPLE.com")));
+        assert!(!is_domain_allowed(&allowed, Some("www.example.com")));
+        assert!(is_domain_allowed(&allowed, Some("cdn.images.test")));
+        assert!(is_domain_allowed(&allowed, Some("a.b.images.test")));
+        assert!(!is_domain_allowed(&allowed, Some("images.test")));
+        assert!(!is_domain_allowed(&allowed, Some("evilimages.test")));
+        assert!(!is_domain_allowed(&allowed, None));
+        assert!(is_domain_allowed(&[], Some("anything.test")));
+    }
+
+    #[cfg(feature = "network")]
+    #[tokio::test]
+    async fn test_download_limits() {
+        let processor = |image_handling: crate::config::ImageHandlingConfig| {
+            ImageProcessor::from_config(&MdpdfConfig {
+                image_handling: Some(image_handling),
+                ..Default::default()
+            })
+        };
+
+        // Hosts that aren't allowed are rejected before making a request
+        let error = processor(crate::config::ImageHandlingConfig {
+            allowed_domains: vec!["*.example.com".to_string()],
+            ..Default::default()
+        })
+        .process_image_url("http://127.0.0.1:9/image.png")
+        .await
+        .unwrap_err();
+        assert_eq!(
+            image_error(&error),
+            Some(&ImageError::DomainNotAllowed {
+                url: "http://127.0.0.1:9/image.png".to_string(),
+                host: "127.0.0.1".to_string()
+            })
+        );
+
+        // Slow servers hit the configured timeout
+        let url = serve_once(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nx".to_vec(),
+            std::time::Duration::from_secs(2),
+        );
+        let error = processor(crate::config::ImageHandlingConfig {
+            download_timeout: 100,
+            ..Default::default()
+        })
+        .process_image_url(&url)
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            image_error(&error),
+            Some(ImageError::Timeout {
+                timeout_ms: 100,
+                ..
+            })
+        ));
+
+        // Oversized bodies are cut off even without a Content-Length header
+        let mut response = b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n".to_vec();
+        response.extend(vec![0; 64 * 1024]);
+        let url = serve_once(response, std::time::Duration::ZERO);
+        let error = processor(crate::config::ImageHandlingConfig {
+            max_image_size: Some(1024),
+            ..Default::default()
+        })
+        .process_image_url(&url)
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            image_error(&error),
+            Some(ImageError::TooLarge { max_size: 1024, .. })
+        ));
+
+        // The size limit applies to data URLs too
+        let error = processor(crate::config::ImageHandlingConfig {
+            max_image_size: Some(2),
+            ..Default::default()
+        })
+        .process_image_url("data:image/png;base64,AAAAAA==")
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            image_error(&error),
+            Some(ImageError::TooLarge { max_size: 2, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_offline() {
+        let dir = test_dir("offline");
+        std::fs::create_dir_all(dir.join("cache/example.com")).unwrap();
+        write_test_png(&dir.join("cache/example.com/cached.png"));
+        let processor = ImageProcessor::from_config(&MdpdfConfig {
+            image_handling: Some(crate::config::ImageHandlingConfig {
+                cache_directory: Some(dir.join("cache").to_string_lossy().to_string()),
+                offline: true,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+
+        assert!(
+            processor
+                .process_image_url("https://example.com/cached.png")
+                .await
+                .is_ok()
+        );
+        let error = processor
+            .process_image_url("https://example.com/missing.png")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            image_error(&error),
+            Some(&ImageError::Offline {
+                url: "https://example.com/missing.png".to_string()
+            })
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_local_images_sandboxed() {
+        let dir = test_dir("sandbox");
+        write_test_png(&dir.join("outside.png"));
+        let processor = processor_for(&dir.join("docs"), false);
+
+        let error = processor
+            .process_image_url("../outside.png")
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("outside of the base directory"));
+        let absolute = dir.join("outside.png");
+        assert!(
+            processor
+                .process_image_url(&absolute.to_string_lossy())
+                .await
+                .is_err()
+        );
+
+        let processor = processor_for(&dir.join("docs"), true);
+        assert!(processor.process_image_url("../outside.png").await.is_ok());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
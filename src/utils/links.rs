@@ -0,0 +1,254 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use url::Url;
+
+use crate::config::LinkStyle;
+use crate::diagnostics::{Diagnostic, DiagnosticCategory};
+
+/// Renders links according to the configured [`LinkStyle`], collecting endnotes as it goes.
+#[derive(Debug, Default)]
+pub struct LinkRenderer {
+    style: LinkStyle,
+    endnotes: Vec<String>,
+}
+
+impl LinkRenderer {
+    pub fn new(style: LinkStyle) -> Self {
+        Self {
+            style,
+            endnotes: Vec::new(),
+        }
+    }
+
+    /// Typst code that opens a link to `url`. The link text and [`Self::close`] follow it.
+    pub fn open(&self, url: &str) -> String {
+        format!("#link(\"{}\")[", escape_url(url))
+    }
+
+    /// Typst code that closes a link to `url`, followed by the URL itself if the style shows it.
+    pub fn close(&mut self, url: &str) -> String {
+        match self.style {
+            LinkStyle::InlineUrl if url.contains('`') => {
+                format!("] (#raw(\"{}\"))", escape_url(url))
+            }
+            LinkStyle::InlineUrl => format!("] (`{url}`)"),
+            LinkStyle::TextOnly => "]".to_string(),
+            LinkStyle::FootnoteUrl => format!("]#footnote(link(\"{}\"))", escape_url(url)),
+            LinkStyle::Endnotes => {
+                let number = match self.endnotes.iter().position(|endnote| endnote == url) {
+                    Some(index) => index + 1,
+                    None => {
+                        self.endnotes.push(url.to_string());
+                        self.endnotes.len()
+                    }
+                };
+                format!("]#super[{number}]")
+            }
+        }
+    }
+
+    pub fn render(&mut self, url: &str, text: &str) -> String {
+        let open = self.open(url);
+        let close = self.close(url);
+        format!("{open}{text}{close}")
+    }
+
+    /// Typst code for the numbered list of endnotes, or nothing if there aren't any.
+    pub fn finish(&mut self) -> String {
+        if self.endnotes.is_empty() {
+            return String::new();
+        }
+        // Not a section of the document, so it's left out of the outline and not numbered
+        let mut result =
+            String::from("\n#heading(level: 2, outlined: false, numbering: none)[Links]\n\n");
+        for url in self.endnotes.drain(..) {
+            result.push_str(&format!("+ #link(\"{}\")\n", escape_url(&url)));
+        }
+        result
+    }
+}
+
+/// Labels and links of a Markdown file that's combined with others into one document.
+///
+/// Each file's labels get a prefix, so that headings with the same name in different files
+/// don't clash, and links to the other files (e.g. `setup.md#install`) point at their labels.
+/// The default is a standalone document, whose labels are unchanged.
+#[derive(Debug, Clone, Default)]
+pub struct FileLinks {
+    /// The file's path, e.g. `guide/setup.md`
+    path: String,
+    /// The label of the start of the file, and the prefix of its other labels
+    label: String,
+    files: Arc<HashMap<String, LinkTarget>>,
+}
+
+/// A file that can be linked to.
+#[derive(Debug, Clone)]
+pub struct LinkTarget {
+    label: String,
+    heading_slugs: HashSet<String>,
+}
+
+impl LinkTarget {
+    /// The `index`th file of the document, with the slugs of its headings.
+    pub fn new(index: usize, heading_slugs: HashSet<String>) -> Self {
+        Self {
+            label: format!("file-{}", index + 1),
+            heading_slugs,
+        }
+    }
+}
+
+impl FileLinks {
+    /// Links for the file at `path` of `files`, which are by normalized path.
+    pub fn new(path: &str, files: Arc<HashMap<String, LinkTarget>>) -> Self {
+        let path = normalize_path(path);
+        let label = files
+            .get(&path)
+            .map(|target| target.label.clone())
+            .unwrap_or_default();
+        Self { path, label, files }
+    }
+
+    /// The label of the start of the file, if it's one of several.
+    pub fn file_label(&self) -> Option<&str> {
+        Some(self.label.as_str()).filter(|label| !label.is_empty())
+    }
+
+    /// The label for `name` (e.g. a heading slug) in this file.
+    pub fn label(&self, name: &str) -> String {
+        match self.file_label() {
+            Some(label) => format!("{label}:{name}"),
+            None => name.to_string(),
+        }
+    }
+
+    /// The label that a link to another file (e.g. `setup.md#install`) points at, or `None` if
+    /// `url` doesn't point at one of the files. If the heading doesn't exist, the link points at
+    /// the start of the file instead, with a warning.
+    pub fn resolve(&self, url: &str) -> Option<(String, Option<Diagnostic>)> {
+        if self.files.is_empty() || Url::parse(url).is_ok() {
+            return None;
+        }
+        let (path, fragment) = url.split_once('#').unwrap_or((url, ""));
+        if path.is_empty() || path.starts_with('/') {
+            return None;
+        }
+        let target = self.files.get(&normalize_path(&self.relative(path)))?;
+        if fragment.is_empty() {
+            return Some((target.label.clone(), None));
+        }
+        let slug = [fragment.to_string(), fragment.to_lowercase()]
+            .into_iter()
+            .find(|slug| target.heading_slugs.contains(slug));
+        Some(match slug {
+            Some(slug) => (format!("{}:{slug}", target.label), None),
+            None => (target.label.clone(), Some(missing_heading(url))),
+        })
+    }
+
+    /// Resolve a relative image path from the file's directory, since images are loaded
+    /// relative to the `base_dir` of the whole document.
+    pub fn image_path(&self, src: &str) -> String {
+        let Some((directory, _)) = self.path.rsplit_once('/') else {
+            return src.to_string();
+        };
+        if self.files.is_empty() || Url::parse(src).is_ok() || src.starts_with('/') {
+            return src.to_string();
+        }
+        // Image paths are resolved like URLs, so the directory has to be one too
+        let directory = directory
+            .replace('%', "%25")
+            .replace(' ', "%20")
+            .replace('#', "%23")
+            .replace('?', "%3F");
+        format!("{directory}/{src}")
+    }
+
+    /// `path` relative to the directory of this file.
+    fn relative(&self, path: &str) -> String {
+        match self.path.rsplit_once('/') {
+            Some((directory, _)) => format!("{directory}/{path}"),
+            None => path.to_string(),
+        }
+    }
+}
+
+/// The warning for a link to a heading that doesn't exist.
+pub fn missing_heading(url: &str) -> Diagnostic {
+    Diagnostic::warning(
+        DiagnosticCategory::Link,
+        format!("no heading found for link to `{url}`"),
+    )
+}
+
+/// Normalize a relative path like `./guide/../setup.md` to `setup.md`.
+pub fn normalize_path(path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split(['/', '\\']) {
+        match part {
+            "" | "." => {}
+            ".." if parts.last().is_some_and(|last| *last != "..") => {
+                parts.pop();
+            }
+            _ => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+/// Escape a URL for use inside a Typst string literal.
+fn escape_url(url: &str) -> String {
+    url.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_link_styles() {
+        let url = "https://example.com";
+
+        let mut links = LinkRenderer::new(LinkStyle::InlineUrl);
+        assert_eq!(
+            links.render(url, "text"),
+            "#link(\"https://example.com\")[text] (`https://example.com`)"
+        );
+
+        let mut links = LinkRenderer::new(LinkStyle::TextOnly);
+        assert_eq!(
+            links.render(url, "text"),
+            "#link(\"https://example.com\")[text]"
+        );
+
+        let mut links = LinkRenderer::new(LinkStyle::FootnoteUrl);
+        assert_eq!(
+            links.render(url, "text"),
+            "#link(\"https://example.com\")[text]#footnote(link(\"https://example.com\"))"
+        );
+        assert_eq!(links.finish(), "");
+    }
+
+    #[test]
+    fn test_endnotes() {
+        let mut links = LinkRenderer::new(LinkStyle::Endnotes);
+        assert_eq!(
+            links.render("https://a.example", "a"),
+            "#link(\"https://a.example\")[a]#super[1]"
+        );
+        assert_eq!(
+            links.render("https://b.example/\"quoted\"", "b"),
+            "#link(\"https://b.example/\\\"quoted\\\"\")[b]#super[2]"
+        );
+        assert_eq!(
+            links.render("https://a.example", "a again"),
+            "#link(\"https://a.example\")[a again]#super[1]"
+        );
+        assert_eq!(
+            links.finish(),
+            "\n#heading(level: 2, outlined: false, numbering: none)[Links]\n\n+ #link(\"https://a.example\")\n+ #link(\"https://b.example/\\\"quoted\\\"\")\n"
+        );
+    }
+}
@@ -1,7 +1,7 @@
 import fs from "fs";
 import path from "path";
 
-import { markdownToPdf, markdownToTypstCode } from "../index.js";
+import { markdownToPdfWithWarnings, markdownToTypstCode } from "../index.js";
 
 const numFuzzRegressionTests = 37;
 
@@ -137,18 +137,17 @@ async function main() {
 
       try {
         const startTime = performance.now();
-        const pdfBytes = await markdownToPdf(markdown);
+        const { pdf, warnings } = await markdownToPdfWithWarnings(markdown);
         const endTime = performance.now();
         const pdfTime = endTime - startTime;
         timingData[test].pdf.push(pdfTime);
 
         if (!PROFILE) {
-          console.log(`PDF for ${test}: ${pdfTime.toFixed(2)}ms`);
+          console.log(
+            `PDF for ${test}: ${pdfTime.toFixed(2)}ms (${warnings.length} warnings)`,
+          );
         }
-        fs.writeFileSync(
-          path.join(import.meta.dirname, `${test}.pdf`),
-          pdfBytes,
-        );
+        fs.writeFileSync(path.join(import.meta.dirname, `${test}.pdf`), pdf);
       } catch (error) {
         if (!PROFILE) {
           console.error(`❌ PDF generation failed for ${test}:`, error);